@@ -1,6 +1,28 @@
 fn main() {
     println!("cargo:rustc-link-search=font");
     println!("cargo:rustc-link-search=driver");
+    println!(
+        "cargo:rustc-link-arg=-T{}/kernel.ld",
+        std::env::var("CARGO_MANIFEST_DIR").unwrap()
+    );
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=kernel.ld");
+    // Not the directories themselves, as make writes its objects there.
+    rerun_if_sources_changed(std::path::Path::new("font"));
+    rerun_if_sources_changed(std::path::Path::new("driver"));
+    println!("cargo:rerun-if-env-changed=PONKAN_INITRD");
+
+    // An initrd (cpio "newc" archive) is optional; without one the kernel
+    // starts with no files.
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let initrd_out = std::path::Path::new(&out_dir).join("initrd.cpio");
+    match std::env::var("PONKAN_INITRD") {
+        Ok(initrd) => {
+            println!("cargo:rerun-if-changed={}", initrd);
+            std::fs::copy(initrd, &initrd_out).map(|_| ())
+        },
+        Err(_) => std::fs::write(&initrd_out, b""),
+    }.unwrap();
 
     std::process::Command::new("make")
         .current_dir("font")
@@ -11,3 +33,20 @@ fn main() {
         .output()
         .unwrap();
 }
+
+fn rerun_if_sources_changed(dir: &std::path::Path) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            rerun_if_sources_changed(&path);
+            continue;
+        }
+        let is_source = path.file_name().unwrap() == "Makefile" ||
+            path.extension().is_some_and(|ext| {
+                ["c", "cpp", "hpp", "txt", "py"].iter().any(|e| ext == *e)
+            });
+        if is_source {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+}
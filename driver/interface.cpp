@@ -1,5 +1,6 @@
 #include "usb/xhci/xhci.hpp"
 #include "usb/classdriver/mouse.hpp"
+#include "usb/classdriver/keyboard.hpp"
 
 using namespace usb;
 
@@ -13,6 +14,13 @@ void _XHCI_Mouse_SetDefaultObserver(
     return;
 }
 
+void _XHCI_Keyboard_SetDefaultObserver(
+    std::function<HIDKeyboardDriver::ObserverType> observer
+) {
+    HIDKeyboardDriver::default_observer = observer;
+    return;
+}
+
 extern "C" {
     xhci::Controller *XHCI_Controller_New(uintptr_t mmio_base) {
         return new(controller_buf) xhci::Controller{mmio_base};
@@ -71,9 +79,16 @@ extern "C" {
     }
 
     void XHCI_Mouse_SetDefaultObserver(
-        void (*observer)(int8_t, int8_t)
+        void (*observer)(MouseEvent)
     ) {
         _XHCI_Mouse_SetDefaultObserver(observer);
         return;
     }
+
+    void XHCI_Keyboard_SetDefaultObserver(
+        void (*observer)(KeyEvent)
+    ) {
+        _XHCI_Keyboard_SetDefaultObserver(observer);
+        return;
+    }
 }
@@ -6,9 +6,9 @@
 
 namespace usb {
   HIDBaseDriver::HIDBaseDriver(Device* dev, int interface_index,
-                               int in_packet_size)
+                               int in_packet_size, bool report_protocol)
       : ClassDriver{dev}, interface_index_{interface_index},
-        in_packet_size_{in_packet_size} {
+        in_packet_size_{in_packet_size}, report_protocol_{report_protocol} {
   }
 
   Error HIDBaseDriver::Initialize() {
@@ -18,23 +18,53 @@ namespace usb {
   Error HIDBaseDriver::SetEndpoint(const EndpointConfig& config) {
     if (config.ep_type == EndpointType::kInterrupt && config.ep_id.IsIn()) {
       ep_interrupt_in_ = config.ep_id;
+      if (report_protocol_) {
+        // Shorter reports end the transfer early.
+        in_packet_size_ = std::min<int>(config.max_packet_size, kBufferSize);
+      }
     } else if (config.ep_type == EndpointType::kInterrupt && !config.ep_id.IsIn()) {
       ep_interrupt_out_ = config.ep_id;
     }
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  // Phase 1 reads the report descriptor, phase 2 sets the protocol and
+  // phase 3 receives the reports.
   Error HIDBaseDriver::OnEndpointsConfigured() {
+    if (!report_protocol_) {
+      return SetProtocol(0); // boot protocol
+    }
+
+    SetupData setup_data{};
+    setup_data.request_type.bits.direction = request_type::kIn;
+    setup_data.request_type.bits.type = request_type::kStandard;
+    setup_data.request_type.bits.recipient = request_type::kInterface;
+    setup_data.request = request::kGetDescriptor;
+    setup_data.value = descriptor_type::kReport << 8;
+    setup_data.index = interface_index_;
+    setup_data.length = kBufferSize;
+
+    initialize_phase_ = 1;
+    return ParentDevice()->ControlIn(kDefaultControlPipeID, setup_data,
+                                     buf_.data(), kBufferSize, this);
+  }
+
+  Error HIDBaseDriver::OnReportDescriptorReceived(const uint8_t* desc,
+                                                  int len) {
+    return MAKE_ERROR(Error::kNotImplemented);
+  }
+
+  Error HIDBaseDriver::SetProtocol(int protocol) {
     SetupData setup_data{};
     setup_data.request_type.bits.direction = request_type::kOut;
     setup_data.request_type.bits.type = request_type::kClass;
     setup_data.request_type.bits.recipient = request_type::kInterface;
     setup_data.request = request::kSetProtocol;
-    setup_data.value = 0; // boot protocol
+    setup_data.value = protocol;
     setup_data.index = interface_index_;
     setup_data.length = 0;
 
-    initialize_phase_ = 1;
+    initialize_phase_ = 2;
     return ParentDevice()->ControlOut(kDefaultControlPipeID, setup_data, nullptr, 0, this);
   }
 
@@ -43,7 +73,14 @@ namespace usb {
     Log(kDebug, "HIDBaseDriver::OnControlCompleted: dev %08x, phase = %d, len = %d\n",
         this, initialize_phase_, len);
     if (initialize_phase_ == 1) {
-      initialize_phase_ = 2;
+      const auto desc = reinterpret_cast<const uint8_t*>(buf);
+      if (auto err = OnReportDescriptorReceived(desc, len)) {
+        initialize_phase_ = 0;
+        return err;
+      }
+      return SetProtocol(1); // report protocol
+    } else if (initialize_phase_ == 2) {
+      initialize_phase_ = 3;
       return ParentDevice()->InterruptIn(ep_interrupt_in_, buf_.data(), in_packet_size_);
     }
 
@@ -11,7 +11,10 @@
 namespace usb {
   class HIDBaseDriver : public ClassDriver {
    public:
-    HIDBaseDriver(Device* dev, int interface_index, int in_packet_size);
+    // In the report protocol the report descriptor is read first and
+    // `in_packet_size` is replaced by the max packet size of the endpoint.
+    HIDBaseDriver(Device* dev, int interface_index, int in_packet_size,
+                  bool report_protocol = false);
     Error Initialize() override;
     Error SetEndpoint(const EndpointConfig& config) override;
     Error OnEndpointsConfigured() override;
@@ -20,6 +23,9 @@ namespace usb {
     Error OnInterruptCompleted(EndpointID ep_id, const void* buf, int len) override;
 
     virtual Error OnDataReceived() = 0;
+    // Only called in the report protocol. An error leaves the interface
+    // unused.
+    virtual Error OnReportDescriptorReceived(const uint8_t* desc, int len);
     const static size_t kBufferSize = 1024;
     const std::array<uint8_t, kBufferSize>& Buffer() const { return buf_; }
     const std::array<uint8_t, kBufferSize>& PreviousBuffer() const { return previous_buf_; }
@@ -29,8 +35,11 @@ namespace usb {
     EndpointID ep_interrupt_out_;
     const int interface_index_;
     int in_packet_size_;
+    const bool report_protocol_;
     int initialize_phase_{0};
 
+    Error SetProtocol(int protocol);
+
     std::array<uint8_t, kBufferSize> buf_{}, previous_buf_{};
   };
 }
@@ -10,17 +10,43 @@ namespace usb {
   }
 
   Error HIDKeyboardDriver::OnDataReceived() {
-    for (int i = 2; i < 8; ++i) {
-      const uint8_t key = Buffer()[i];
-      if (key == 0) {
-        continue;
+    const auto& buf = Buffer();
+    const uint8_t modifier = buf[0];
+    // Usage IDs below 4 are errors, like too many keys held.
+    auto is_held = [](const uint8_t* keys, uint8_t key) {
+      return key >= 4 &&
+        std::find(keys, keys + kNumKeys, key) != keys + kNumKeys;
+    };
+
+    for (int i = 0; i < 8; ++i) {
+      const uint8_t bit = 1u << i;
+      if ((modifier ^ modifier_) & bit) {
+        NotifyKeyEvent({modifier, static_cast<uint8_t>(0xe0 + i),
+                        (modifier & bit) != 0});
       }
-      const auto& prev_buf = PreviousBuffer();
-      if (std::find(prev_buf.begin(), prev_buf.end(), key) != prev_buf.end()) {
-        continue;
+    }
+    modifier_ = modifier;
+
+    // With too many keys held (ErrorRollOver), the report tells only the
+    // modifiers, so the keys are taken as still held. The previous buffer of
+    // the base class can't be used here, as it holds the rollover report.
+    const uint8_t* keys = &buf[2];
+    if (keys[0] == 0x01) {
+      return MAKE_ERROR(Error::kSuccess);
+    }
+
+    for (uint8_t key : keys_) {
+      if (is_held(keys_.data(), key) && !is_held(keys, key)) {
+        NotifyKeyEvent({modifier, key, false});
+      }
+    }
+    for (int i = 0; i < kNumKeys; ++i) {
+      const uint8_t key = keys[i];
+      if (is_held(keys, key) && !is_held(keys_.data(), key)) {
+        NotifyKeyEvent({modifier, key, true});
       }
-      NotifyKeyPush(key);
     }
+    std::copy(keys, keys + kNumKeys, keys_.begin());
     return MAKE_ERROR(Error::kSuccess);
   }
 
@@ -32,16 +58,16 @@ namespace usb {
     FreeMem(ptr);
   }
 
-  void HIDKeyboardDriver::SubscribeKeyPush(
-      std::function<void (uint8_t keycode)> observer) {
+  void HIDKeyboardDriver::SubscribeKeyEvent(
+      std::function<ObserverType> observer) {
     observers_[num_observers_++] = observer;
   }
 
   std::function<HIDKeyboardDriver::ObserverType> HIDKeyboardDriver::default_observer;
 
-  void HIDKeyboardDriver::NotifyKeyPush(uint8_t keycode) {
+  void HIDKeyboardDriver::NotifyKeyEvent(const KeyEvent& event) {
     for (int i = 0; i < num_observers_; ++i) {
-      observers_[i](keycode);
+      observers_[i](event);
     }
   }
 }
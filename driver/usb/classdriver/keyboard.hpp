@@ -10,6 +10,14 @@
 #include "usb/classdriver/hid.hpp"
 
 namespace usb {
+  // Must match KeyEvent in src/usb.rs.
+  struct KeyEvent {
+    uint8_t modifier;
+    // HID usage ID. Modifier keys are 0xe0 to 0xe7.
+    uint8_t keycode;
+    bool press;
+  };
+
   class HIDKeyboardDriver : public HIDBaseDriver {
    public:
     HIDKeyboardDriver(Device* dev, int interface_index);
@@ -19,14 +27,19 @@ namespace usb {
 
     Error OnDataReceived() override;
 
-    using ObserverType = void (uint8_t keycode);
-    void SubscribeKeyPush(std::function<ObserverType> observer);
+    using ObserverType = void (KeyEvent event);
+    void SubscribeKeyEvent(std::function<ObserverType> observer);
     static std::function<ObserverType> default_observer;
 
    private:
+    static const int kNumKeys = 6;
+    // Last known state, kept across ErrorRollOver reports.
+    uint8_t modifier_ = 0;
+    std::array<uint8_t, kNumKeys> keys_{};
+
     std::array<std::function<ObserverType>, 4> observers_;
     int num_observers_ = 0;
 
-    void NotifyKeyPush(uint8_t keycode);
+    void NotifyKeyEvent(const KeyEvent& event);
   };
 }
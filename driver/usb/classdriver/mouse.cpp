@@ -5,16 +5,270 @@
 #include "usb/device.hpp"
 #include "logger.hpp"
 
+namespace {
+  // Usages are the usage page in the upper 16 bits and the usage ID.
+  const uint32_t kUsagePointer = 0x00010001;
+  const uint32_t kUsageMouse = 0x00010002;
+  const uint32_t kUsageX = 0x00010030;
+  const uint32_t kUsageY = 0x00010031;
+  const uint32_t kUsageWheel = 0x00010038;
+  const uint32_t kUsagePageButton = 0x0009;
+
+  // Report bits past this do not fit in the buffer of the driver.
+  const int kMaxReportBits = 8 * (usb::HIDBaseDriver::kBufferSize - 1);
+
+  int32_t ItemData(const uint8_t* data, int size, bool is_signed) {
+    uint32_t value = 0;
+    for (int i = 0; i < size; ++i) {
+      value |= static_cast<uint32_t>(data[i]) << (8 * i);
+    }
+    if (is_signed && size > 0 && size < 4 &&
+        (value & (1u << (8 * size - 1)))) {
+      value |= ~0u << (8 * size);
+    }
+    return static_cast<int32_t>(value);
+  }
+
+  int32_t ReadField(const uint8_t* report,
+                    const usb::HIDMouseDriver::ReportField& field) {
+    uint32_t value = 0;
+    for (int i = 0; i < field.size; ++i) {
+      const int bit = field.offset + i;
+      value |= static_cast<uint32_t>((report[bit / 8] >> (bit % 8)) & 1u) << i;
+    }
+    if (field.logical_min < 0 && field.size < 32 &&
+        (value & (1u << (field.size - 1)))) {
+      value |= ~0u << field.size;
+    }
+    return static_cast<int32_t>(value);
+  }
+
+  int8_t ClampRelative(int32_t value) {
+    return std::clamp<int32_t>(value, -127, 127);
+  }
+
+  // Parses the short items of a HID report descriptor (HID 1.11, 6.2.2) and
+  // records the input fields inside the first Mouse or Pointer application
+  // collection. Fails if it has no X and Y.
+  Error ParseReportDescriptor(const uint8_t* desc, int len,
+                              usb::HIDMouseDriver::ReportLayout& layout) {
+    struct Globals {
+      uint32_t usage_page = 0;
+      int32_t logical_min = 0, logical_max = 0;
+      int report_size = 0, report_count = 0;
+      uint8_t report_id = 0;
+    };
+    Globals globals{};
+    std::array<Globals, 4> global_stack;
+    int global_stack_depth = 0;
+
+    std::array<uint32_t, 16> usages;
+    int num_usages = 0;
+    uint32_t usage_min = 0, usage_max = 0;
+    bool has_usage_range = false;
+
+    // Next bit of the input report of each ID.
+    std::array<int, 256> report_bits{};
+    int collection_depth = 0;
+    uint32_t application = 0;
+    bool has_fields = false;
+
+    layout = {};
+    layout.buttons.fill(-1);
+
+    auto add_field = [&](uint32_t usage, int offset, bool relative) {
+      if (globals.report_size == 0 || globals.report_size > 32 ||
+          offset + globals.report_size > kMaxReportBits) {
+        return;
+      }
+      if (has_fields && globals.report_id != layout.report_id) {
+        return;
+      }
+      usb::HIDMouseDriver::ReportField field{
+        offset, globals.report_size, globals.logical_min, globals.logical_max
+      };
+      usb::HIDMouseDriver::ReportField* target = nullptr;
+      if (usage >> 16 == kUsagePageButton) {
+        const uint32_t button = usage & 0xffffu;
+        if (button >= 1 && button <= layout.buttons.size() &&
+            layout.buttons[button - 1] < 0) {
+          layout.buttons[button - 1] = offset;
+        } else {
+          return;
+        }
+      } else if (usage == kUsageX) {
+        target = &layout.x;
+        layout.absolute = !relative;
+      } else if (usage == kUsageY) {
+        target = &layout.y;
+      } else if (usage == kUsageWheel) {
+        target = &layout.wheel;
+      } else {
+        return;
+      }
+      if (target) {
+        if (target->offset >= 0) {
+          return;
+        }
+        *target = field;
+      }
+      layout.report_id = globals.report_id;
+      has_fields = true;
+    };
+
+    int i = 0;
+    while (i < len) {
+      const uint8_t prefix = desc[i];
+      if (prefix == 0xfe) { // long item, none are defined
+        if (i + 1 >= len) {
+          break;
+        }
+        i += 3 + desc[i + 1];
+        continue;
+      }
+
+      const int size = (prefix & 3u) == 3 ? 4 : (prefix & 3u);
+      if (i + 1 + size > len) {
+        return MAKE_ERROR(Error::kInvalidDescriptor);
+      }
+      const uint8_t* data = &desc[i + 1];
+      const uint32_t value = ItemData(data, size, false);
+      const int type = (prefix >> 2) & 3u;
+      const int tag = prefix >> 4;
+      i += 1 + size;
+
+      if (type == 0) { // main
+        if (tag == 0xa) { // Collection
+          if (collection_depth == 0 && value == 1 /* Application */) {
+            application = num_usages > 0 ? usages[0] : 0;
+          }
+          ++collection_depth;
+        } else if (tag == 0xc) { // End Collection
+          collection_depth = std::max(collection_depth - 1, 0);
+        } else if (tag == 0x8) { // Input
+          int& bits = report_bits[globals.report_id];
+          const bool is_constant = value & 1u;
+          const bool is_relative = value & 4u;
+          if (!is_constant &&
+              (application == kUsagePointer || application == kUsageMouse)) {
+            for (int n = 0; n < globals.report_count; ++n) {
+              uint32_t usage = 0;
+              if (has_usage_range) {
+                usage = std::min(usage_min + n, usage_max);
+              } else if (num_usages > 0) {
+                usage = usages[std::min(n, num_usages - 1)];
+              }
+              add_field(usage, bits + n * globals.report_size, is_relative);
+            }
+          }
+          bits = std::min(bits + globals.report_size * globals.report_count,
+                          kMaxReportBits + 1);
+        }
+        num_usages = 0;
+        has_usage_range = false;
+      } else if (type == 1) { // global
+        switch (tag) {
+        case 0x0: globals.usage_page = value; break;
+        case 0x1: globals.logical_min = ItemData(data, size, true); break;
+        case 0x2: globals.logical_max = ItemData(data, size, true); break;
+        case 0x7: globals.report_size = value; break;
+        case 0x8: globals.report_id = value; break;
+        case 0x9: globals.report_count = std::min<uint32_t>(value, 256); break;
+        case 0xa: // Push
+          if (global_stack_depth < static_cast<int>(global_stack.size())) {
+            global_stack[global_stack_depth++] = globals;
+          }
+          break;
+        case 0xb: // Pop
+          if (global_stack_depth > 0) {
+            globals = global_stack[--global_stack_depth];
+          }
+          break;
+        }
+      } else if (type == 2) { // local
+        const uint32_t usage =
+          size == 4 ? value : (globals.usage_page << 16) | value;
+        if (tag == 0x0 && num_usages < static_cast<int>(usages.size())) {
+          usages[num_usages++] = usage;
+        } else if (tag == 0x1) {
+          usage_min = usage;
+          has_usage_range = true;
+        } else if (tag == 0x2) {
+          usage_max = usage;
+        }
+      }
+    }
+
+    if (layout.x.offset < 0 || layout.y.offset < 0) {
+      return MAKE_ERROR(Error::kInvalidDescriptor);
+    }
+    return MAKE_ERROR(Error::kSuccess);
+  }
+}
+
 namespace usb {
+  // Report protocol, so that the wheel and absolute pointers like QEMU's
+  // usb-tablet are read as their report descriptor says.
   HIDMouseDriver::HIDMouseDriver(Device* dev, int interface_index)
-      : HIDBaseDriver{dev, interface_index, 3} {
+      : HIDBaseDriver{dev, interface_index, 0, true} {
+  }
+
+  Error HIDMouseDriver::OnReportDescriptorReceived(const uint8_t* desc,
+                                                   int len) {
+    if (auto err = ParseReportDescriptor(desc, len, layout_)) {
+      Log(kWarn, "HID interface without a pointer, ignored\n");
+      return err;
+    }
+    Log(kDebug, "Mouse report: id %d, x %d:%d, y %d:%d, wheel %d:%d, abs %d\n",
+        layout_.report_id, layout_.x.offset, layout_.x.size,
+        layout_.y.offset, layout_.y.size, layout_.wheel.offset,
+        layout_.wheel.size, layout_.absolute);
+    return MAKE_ERROR(Error::kSuccess);
   }
 
   Error HIDMouseDriver::OnDataReceived() {
-    int8_t displacement_x = Buffer()[1];
-    int8_t displacement_y = Buffer()[2];
-    NotifyMouseMove(displacement_x, displacement_y);
-    Log(kDebug, "%02x,(%3d,%3d)\n", Buffer()[0], displacement_x, displacement_y);
+    const auto& buf = Buffer();
+    const uint8_t* report = buf.data();
+    if (layout_.report_id != 0) {
+      // Reports of the other collections.
+      if (buf[0] != layout_.report_id) {
+        return MAKE_ERROR(Error::kSuccess);
+      }
+      ++report;
+    }
+
+    MouseEvent event{};
+    for (size_t i = 0; i < layout_.buttons.size(); ++i) {
+      const int bit = layout_.buttons[i];
+      if (bit >= 0 && ((report[bit / 8] >> (bit % 8)) & 1u)) {
+        event.buttons |= 1u << i;
+      }
+    }
+    event.absolute = layout_.absolute;
+    if (layout_.absolute) {
+      auto scale = [](const ReportField& field, int32_t value) -> uint16_t {
+        const int64_t range =
+          static_cast<int64_t>(field.logical_max) - field.logical_min;
+        if (range <= 0) {
+          return 0;
+        }
+        const int64_t scaled =
+          (static_cast<int64_t>(value) - field.logical_min) * kAbsoluteMax / range;
+        return std::clamp<int64_t>(scaled, 0, kAbsoluteMax);
+      };
+      event.x = scale(layout_.x, ReadField(report, layout_.x));
+      event.y = scale(layout_.y, ReadField(report, layout_.y));
+    } else {
+      event.displacement_x = ClampRelative(ReadField(report, layout_.x));
+      event.displacement_y = ClampRelative(ReadField(report, layout_.y));
+    }
+    if (layout_.wheel.offset >= 0) {
+      event.wheel = ClampRelative(ReadField(report, layout_.wheel));
+    }
+    NotifyMouseMove(event);
+    Log(kDebug, "%02x,(%3d,%3d),(%5d,%5d),%3d\n", event.buttons,
+        event.displacement_x, event.displacement_y, event.x, event.y,
+        event.wheel);
     return MAKE_ERROR(Error::kSuccess);
   }
 
@@ -27,16 +281,15 @@ namespace usb {
   }
 
   void HIDMouseDriver::SubscribeMouseMove(
-      std::function<void (int8_t displacement_x, int8_t displacement_y)> observer) {
+      std::function<ObserverType> observer) {
     observers_[num_observers_++] = observer;
   }
 
   std::function<HIDMouseDriver::ObserverType> HIDMouseDriver::default_observer;
 
-  void HIDMouseDriver::NotifyMouseMove(int8_t displacement_x, int8_t displacement_y) {
+  void HIDMouseDriver::NotifyMouseMove(const MouseEvent& event) {
     for (int i = 0; i < num_observers_; ++i) {
-      observers_[i](displacement_x, displacement_y);
+      observers_[i](event);
     }
   }
 }
-
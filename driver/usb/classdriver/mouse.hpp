@@ -6,27 +6,59 @@
 
 #pragma once
 
+#include <array>
 #include <functional>
 #include "usb/classdriver/hid.hpp"
 
 namespace usb {
+  // Must match MouseEvent in src/usb.rs.
+  struct MouseEvent {
+    uint8_t buttons;
+    int8_t displacement_x, displacement_y, wheel;
+    // Absolute pointers report x and y from 0 to kAbsoluteMax instead of
+    // the displacement.
+    bool absolute;
+    uint16_t x, y;
+  };
+
   class HIDMouseDriver : public HIDBaseDriver {
    public:
+    static const uint16_t kAbsoluteMax = 0x7fff;
+
     HIDMouseDriver(Device* dev, int interface_index);
 
     void* operator new(size_t size);
     void operator delete(void* ptr) noexcept;
 
     Error OnDataReceived() override;
+    Error OnReportDescriptorReceived(const uint8_t* desc, int len) override;
 
-    using ObserverType = void (int8_t displacement_x, int8_t displacement_y);
+    using ObserverType = void (MouseEvent event);
     void SubscribeMouseMove(std::function<ObserverType> observer);
     static std::function<ObserverType> default_observer;
 
+    // A field of the input report, in bits after the report ID.
+    struct ReportField {
+      int offset = -1; // -1 if the report has no such field
+      int size = 0;
+      int32_t logical_min = 0, logical_max = 0;
+    };
+
+    // Where the first mouse or pointer of the report descriptor puts its
+    // values.
+    struct ReportLayout {
+      // 0 if the device does not use report IDs.
+      uint8_t report_id = 0;
+      bool absolute = false;
+      ReportField x, y, wheel;
+      std::array<int, 8> buttons; // bit offsets, -1 if absent
+    };
+
    private:
+    ReportLayout layout_{};
     std::array<std::function<ObserverType>, 4> observers_;
     int num_observers_ = 0;
 
-    void NotifyMouseMove(int8_t displacement_x, int8_t displacement_y);
+    void NotifyMouseMove(const MouseEvent& event);
   };
 }
@@ -53,22 +53,42 @@ namespace {
     return conf;
   }
 
-  usb::ClassDriver* NewClassDriver(usb::Device* dev, const usb::InterfaceDescriptor& if_desc) {
+  usb::ClassDriver* NewMouseDriver(usb::Device* dev, int interface_index) {
+    auto mouse_driver = new usb::HIDMouseDriver{dev, interface_index};
+    if (usb::HIDMouseDriver::default_observer) {
+      mouse_driver->SubscribeMouseMove(usb::HIDMouseDriver::default_observer);
+    }
+    return mouse_driver;
+  }
+
+  bool IsBootInterface(const usb::InterfaceDescriptor& if_desc) {
+    return if_desc.interface_class == 3 &&
+      if_desc.interface_sub_class == 1 &&
+      (if_desc.interface_protocol == 1 || if_desc.interface_protocol == 2);
+  }
+
+  // A device with a boot interface, like a keyboard with extra keys on a
+  // second interface, is driven through it. Otherwise an interface without
+  // the boot protocol is tried as a pointer.
+  usb::ClassDriver* NewClassDriver(usb::Device* dev, const usb::InterfaceDescriptor& if_desc,
+                                   bool has_boot_interface) {
     if (if_desc.interface_class == 3 &&
         if_desc.interface_sub_class == 1) {  // HID boot interface
       if (if_desc.interface_protocol == 1) {  // keyboard
         auto keyboard_driver = new usb::HIDKeyboardDriver{dev, if_desc.interface_number};
         if (usb::HIDKeyboardDriver::default_observer) {
-          keyboard_driver->SubscribeKeyPush(usb::HIDKeyboardDriver::default_observer);
+          keyboard_driver->SubscribeKeyEvent(usb::HIDKeyboardDriver::default_observer);
         }
         return keyboard_driver;
       } else if (if_desc.interface_protocol == 2) {  // mouse
-        auto mouse_driver = new usb::HIDMouseDriver{dev, if_desc.interface_number};
-        if (usb::HIDMouseDriver::default_observer) {
-          mouse_driver->SubscribeMouseMove(usb::HIDMouseDriver::default_observer);
-        }
-        return mouse_driver;
+        return NewMouseDriver(dev, if_desc.interface_number);
       }
+    } else if (if_desc.interface_class == 3 &&
+               if_desc.interface_sub_class == 0 &&
+               !has_boot_interface) {  // HID, not boot
+      // Such as a tablet. The report descriptor tells whether it is a
+      // pointer; other interfaces are left unused.
+      return NewMouseDriver(dev, if_desc.interface_number);
     }
     return nullptr;
   }
@@ -204,13 +224,19 @@ namespace usb {
     if (conf_desc == nullptr) {
       return MAKE_ERROR(Error::kInvalidDescriptor);
     }
+    bool has_boot_interface = false;
+    ConfigurationDescriptorReader boot_reader{buf, len};
+    while (auto if_desc = boot_reader.Next<InterfaceDescriptor>()) {
+      has_boot_interface |= IsBootInterface(*if_desc);
+    }
+
     ConfigurationDescriptorReader config_reader{buf, len};
 
     ClassDriver* class_driver = nullptr;
     while (auto if_desc = config_reader.Next<InterfaceDescriptor>()) {
       Log(kDebug, *if_desc);
 
-      class_driver = NewClassDriver(this, *if_desc);
+      class_driver = NewClassDriver(this, *if_desc, has_boot_interface);
       if (class_driver == nullptr) {
         // 非対応デバイス．次の interface を調べる．
         continue;
@@ -7,6 +7,7 @@
 #pragma once
 
 #include <cstddef>
+#include <cstdint>
 
 namespace usb {
   /** @brief 動的メモリ確保のためのメモリプールの最大容量（バイト） */
@@ -34,6 +35,29 @@ namespace usb {
   /** @brief 指定されたメモリ領域を解放する．本当に解放することは保証されない． */
   void FreeMem(void* p);
 
+  /** @brief カーネルイメージの仮想アドレスと物理アドレスの差．
+   *
+   * memory_pool はカーネルイメージ内に置かれる．kernel.ld と一致させること．
+   */
+  static const uintptr_t kKernelVirtualBase = 0xffffffff80000000;
+
+  /** @brief xHC に渡すため，メモリプール内のポインタを物理アドレスに変換する */
+  inline uint64_t ToPhysAddr(const void* p) {
+    if (p == nullptr) {
+      return 0;
+    }
+    return reinterpret_cast<uintptr_t>(p) - kKernelVirtualBase;
+  }
+
+  /** @brief xHC から受け取った物理アドレスをポインタに変換する */
+  template <class T = void>
+  T* ToVirtAddr(uint64_t addr) {
+    if (addr == 0) {
+      return nullptr;
+    }
+    return reinterpret_cast<T*>(addr + kKernelVirtualBase);
+  }
+
   /** @brief 標準コンテナ用のメモリアロケータ */
   template <class T, unsigned int Alignment = 64, unsigned int Boundary = 4096>
   class Allocator {
@@ -1,6 +1,7 @@
 #pragma once
 
 #include "usb/endpoint.hpp"
+#include "usb/memory.hpp"
 
 namespace usb::xhci {
   class Ring;
@@ -61,11 +62,11 @@ namespace usb::xhci {
     } __attribute__((packed)) bits;
 
     TRB* TransferRingBuffer() const {
-      return reinterpret_cast<TRB*>(bits.tr_dequeue_pointer << 4);
+      return ToVirtAddr<TRB>(bits.tr_dequeue_pointer << 4);
     }
 
     void SetTransferRingBuffer(TRB* buffer) {
-      bits.tr_dequeue_pointer = reinterpret_cast<uint64_t>(buffer) >> 4;
+      bits.tr_dequeue_pointer = ToPhysAddr(buffer) >> 4;
     }
   } __attribute__((packed));
 
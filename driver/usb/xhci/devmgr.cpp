@@ -87,7 +87,9 @@ namespace usb::xhci {
     }
 
     auto dev = devices_[slot_id];
-    device_context_pointers_[slot_id] = dev->DeviceContext();
+    // The array is read by the xHC, so it holds physical addresses.
+    device_context_pointers_[slot_id] = reinterpret_cast<DeviceContext*>(
+        ToPhysAddr(dev->DeviceContext()));
     return MAKE_ERROR(Error::kSuccess);
   }
 
@@ -76,7 +76,7 @@ namespace usb::xhci {
     }
     memset(erst_, 0, 1 * sizeof(EventRingSegmentTableEntry));
 
-    erst_[0].bits.ring_segment_base_address = reinterpret_cast<uint64_t>(buf_);
+    erst_[0].bits.ring_segment_base_address = ToPhysAddr(buf_);
     erst_[0].bits.ring_segment_size = buf_size_;
 
     ERSTSZ_Bitmap erstsz = interrupter_->ERSTSZ.Read();
@@ -86,7 +86,7 @@ namespace usb::xhci {
     WriteDequeuePointer(&buf_[0]);
 
     ERSTBA_Bitmap erstba = interrupter_->ERSTBA.Read();
-    erstba.SetPointer(reinterpret_cast<uint64_t>(erst_));
+    erstba.SetPointer(ToPhysAddr(erst_));
     interrupter_->ERSTBA.Write(erstba);
 
     return MAKE_ERROR(Error::kSuccess);
@@ -94,7 +94,7 @@ namespace usb::xhci {
 
   void EventRing::WriteDequeuePointer(TRB* p) {
     auto erdp = interrupter_->ERDP.Read();
-    erdp.SetPointer(reinterpret_cast<uint64_t>(p));
+    erdp.SetPointer(ToPhysAddr(p));
     interrupter_->ERDP.Write(erdp);
   }
 
@@ -102,7 +102,7 @@ namespace usb::xhci {
     auto p = ReadDequeuePointer() + 1;
 
     TRB* segment_begin
-      = reinterpret_cast<TRB*>(erst_[0].bits.ring_segment_base_address);
+      = ToVirtAddr<TRB>(erst_[0].bits.ring_segment_base_address);
     TRB* segment_end = segment_begin + erst_[0].bits.ring_segment_size;
 
     if (p == segment_end) {
@@ -80,7 +80,7 @@ namespace usb::xhci {
     Error Initialize(size_t buf_size, InterrupterRegisterSet* interrupter);
 
     TRB* ReadDequeuePointer() const {
-      return reinterpret_cast<TRB*>(interrupter_->ERDP.Read().Pointer());
+      return ToVirtAddr<TRB>(interrupter_->ERDP.Read().Pointer());
     }
 
     void WriteDequeuePointer(TRB* p);
@@ -8,6 +8,7 @@
 
 #include <cstdint>
 #include <array>
+#include "usb/memory.hpp"
 #include "usb/xhci/context.hpp"
 
 namespace usb::xhci {
@@ -55,11 +56,11 @@ namespace usb::xhci {
     }
 
     void* Pointer() const {
-      return reinterpret_cast<TRB*>(bits.data_buffer_pointer);
+      return ToVirtAddr<TRB>(bits.data_buffer_pointer);
     }
 
     void SetPointer(const void* p) {
-      bits.data_buffer_pointer = reinterpret_cast<uint64_t>(p);
+      bits.data_buffer_pointer = ToPhysAddr(p);
     }
   };
 
@@ -127,11 +128,11 @@ namespace usb::xhci {
     }
 
     void* Pointer() const {
-      return reinterpret_cast<void*>(bits.data_buffer_pointer);
+      return ToVirtAddr(bits.data_buffer_pointer);
     }
 
     void SetPointer(const void* p) {
-      bits.data_buffer_pointer = reinterpret_cast<uint64_t>(p);
+      bits.data_buffer_pointer = ToPhysAddr(p);
     }
   };
 
@@ -186,11 +187,11 @@ namespace usb::xhci {
     }
 
     TRB* Pointer() const {
-      return reinterpret_cast<TRB*>(bits.ring_segment_pointer << 4);
+      return ToVirtAddr<TRB>(bits.ring_segment_pointer << 4);
     }
 
     void SetPointer(const TRB* p) {
-      bits.ring_segment_pointer = reinterpret_cast<uint64_t>(p) >> 4;
+      bits.ring_segment_pointer = ToPhysAddr(p) >> 4;
     }
   };
 
@@ -264,11 +265,11 @@ namespace usb::xhci {
     }
 
     InputContext* Pointer() const {
-      return reinterpret_cast<InputContext*>(bits.input_context_pointer << 4);
+      return ToVirtAddr<InputContext>(bits.input_context_pointer << 4);
     }
 
     void SetPointer(const InputContext* p) {
-      bits.input_context_pointer = reinterpret_cast<uint64_t>(p) >> 4;
+      bits.input_context_pointer = ToPhysAddr(p) >> 4;
     }
   };
 
@@ -296,11 +297,11 @@ namespace usb::xhci {
     }
 
     InputContext* Pointer() const {
-      return reinterpret_cast<InputContext*>(bits.input_context_pointer << 4);
+      return ToVirtAddr<InputContext>(bits.input_context_pointer << 4);
     }
 
     void SetPointer(const InputContext* p) {
-      bits.input_context_pointer = reinterpret_cast<uint64_t>(p) >> 4;
+      bits.input_context_pointer = ToPhysAddr(p) >> 4;
     }
   };
 
@@ -379,11 +380,11 @@ namespace usb::xhci {
     }
 
     TRB* Pointer() const {
-      return reinterpret_cast<TRB*>(bits.trb_pointer);
+      return ToVirtAddr<TRB>(bits.trb_pointer);
     }
 
     void SetPointer(const TRB* p) {
-      bits.trb_pointer = reinterpret_cast<uint64_t>(p);
+      bits.trb_pointer = ToPhysAddr(p);
     }
 
     EndpointID EndpointID() const {
@@ -413,11 +414,11 @@ namespace usb::xhci {
     }
 
     TRB* Pointer() const {
-      return reinterpret_cast<TRB*>(bits.command_trb_pointer << 4);
+      return ToVirtAddr<TRB>(bits.command_trb_pointer << 4);
     }
 
     void SetPointer(TRB* p) {
-      bits.command_trb_pointer = reinterpret_cast<uint64_t>(p) >> 4;
+      bits.command_trb_pointer = ToPhysAddr(p) >> 4;
     }
   };
 
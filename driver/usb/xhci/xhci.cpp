@@ -14,7 +14,7 @@ namespace {
     value.bits.ring_cycle_state = true;
     value.bits.command_stop = false;
     value.bits.command_abort = false;
-    value.SetPointer(reinterpret_cast<uint64_t>(ring->Buffer()));
+    value.SetPointer(usb::ToPhysAddr(ring->Buffer()));
     crcr->Write(value);
     return MAKE_ERROR(Error::kSuccess);
   }
@@ -360,17 +360,19 @@ namespace usb::xhci {
     if (max_scratchpad_buffers > 0) {
       auto scratchpad_buf_arr = AllocArray<void*>(max_scratchpad_buffers, 64, 4096);
       for (int i = 0; i < max_scratchpad_buffers; ++i) {
-        scratchpad_buf_arr[i] = AllocMem(4096, 4096, 4096);
+        scratchpad_buf_arr[i] = reinterpret_cast<void*>(
+            ToPhysAddr(AllocMem(4096, 4096, 4096)));
         Log(kDebug, "scratchpad buffer array %d = %p\n",
             i, scratchpad_buf_arr[i]);
       }
-      devmgr_.DeviceContexts()[0] = reinterpret_cast<DeviceContext*>(scratchpad_buf_arr);
+      devmgr_.DeviceContexts()[0] = reinterpret_cast<DeviceContext*>(
+          ToPhysAddr(scratchpad_buf_arr));
       Log(kInfo, "wrote scratchpad buffer array %p to dev ctx array 0\n",
           scratchpad_buf_arr);
     }
 
     DCBAAP_Bitmap dcbaap{};
-    dcbaap.SetPointer(reinterpret_cast<uint64_t>(devmgr_.DeviceContexts()));
+    dcbaap.SetPointer(ToPhysAddr(devmgr_.DeviceContexts()));
     op_->DCBAAP.Write(dcbaap);
 
     auto primary_interrupter = &InterrupterRegisterSets()[0];
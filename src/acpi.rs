@@ -0,0 +1,298 @@
+use crate::error::*;
+use crate::paging::phys_to_virt;
+use crate::sync::OnceCell;
+
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr::read_unaligned, slice};
+
+#[repr(C, packed)]
+pub struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+pub struct DescriptionHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+// Interrupt controller structure types.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+// MPS INTI flags of interrupt source overrides. 0 is the bus default, which
+// for ISA is active high and edge triggered.
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MODE_MASK: u16 = 0b11 << 2;
+const TRIGGER_MODE_LEVEL: u16 = 0b11 << 2;
+
+// IA-PC boot architecture flags of the FADT, since ACPI 2.0.
+const FADT_IAPC_BOOT_ARCH: usize = 109;
+const IAPC_BOOT_ARCH_8042: u16 = 1 << 1;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// What the kernel uses from the tables, copied out so that the memory they
+// are in can be reclaimed.
+struct AcpiInfo {
+    // None without a MADT.
+    madt: Option<MadtInfo>,
+    has_8042: bool,
+}
+
+struct MadtInfo {
+    local_apic_ids: Vec<u32>,
+    io_apics: Vec<IoApic>,
+    // Interrupt source overrides of ISA IRQs, as the IRQ and where it goes.
+    isa_overrides: Vec<(u8, IsaInterrupt)>,
+}
+
+static INFO: OnceCell<AcpiInfo> = OnceCell::new();
+
+fn sum_bytes(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+impl Rsdp {
+    fn is_valid(&self) -> bool {
+        if &self.signature != b"RSD PTR " {
+            return false;
+        }
+        // The XSDT only exists since ACPI 2.0.
+        if self.revision < 2 {
+            return false;
+        }
+        let bytes = unsafe {
+            slice::from_raw_parts(self as *const _ as *const u8, 20)
+        };
+        if sum_bytes(bytes) != 0 {
+            return false;
+        }
+        let bytes = unsafe {
+            slice::from_raw_parts(self as *const _ as *const u8, 36)
+        };
+        sum_bytes(bytes) == 0
+    }
+}
+
+impl DescriptionHeader {
+    fn is_valid(&self, signature: &[u8; 4]) -> bool {
+        &self.signature == signature && sum_bytes(self.bytes()) == 0
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                self as *const _ as *const u8, self.length as usize)
+        }
+    }
+
+    // The part of the table after the header.
+    fn body(&self) -> &[u8] {
+        &self.bytes()[size_of::<Self>()..]
+    }
+}
+
+fn table_at(phys: u64) -> &'static DescriptionHeader {
+    unsafe {
+        &*(phys_to_virt(phys) as *const DescriptionHeader)
+    }
+}
+
+// `rsdp` is the physical address passed by the loader. The tables are not
+// read after this returns.
+pub fn initialize(rsdp: u64) -> Result<(), OsError> {
+    let rsdp = unsafe {
+        &*(phys_to_virt(rsdp) as *const Rsdp)
+    };
+    if !rsdp.is_valid() {
+        return make_error!(OsErrorCode::InvalidAcpiTable);
+    }
+
+    let xsdt = table_at(rsdp.xsdt_address);
+    if !xsdt.is_valid(b"XSDT") {
+        return make_error!(OsErrorCode::InvalidAcpiTable);
+    }
+
+    let madt = match find_table(xsdt, b"APIC") {
+        Some(madt) => Some(parse_madt(madt)?),
+        None => None,
+    };
+    let has_8042 = match find_table(xsdt, b"FACP") {
+        Some(fadt) => fadt_has_8042(fadt),
+        None => false,
+    };
+    INFO.set(AcpiInfo { madt, has_8042 })?;
+    Ok(())
+}
+
+fn find_table(
+    xsdt: &'static DescriptionHeader,
+    signature: &[u8; 4],
+) -> Option<&'static DescriptionHeader> {
+    xsdt.body()
+        .chunks_exact(size_of::<u64>())
+        .map(|entry| unsafe { read_unaligned(entry.as_ptr() as *const u64) })
+        .map(table_at)
+        .find(|table| table.is_valid(signature))
+}
+
+// Interrupt controller structures of the MADT, as the type and the whole
+// structure.
+struct MadtEntries {
+    entries: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = (u8, &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entries.len() < 2 {
+            return None;
+        }
+        let length = self.entries[1] as usize;
+        if length < 2 || length > self.entries.len() {
+            self.entries = &[];
+            return None;
+        }
+        let entry = &self.entries[..length];
+        self.entries = &self.entries[length..];
+        Some((entry[0], entry))
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn parse_madt(madt: &'static DescriptionHeader) -> Result<MadtInfo, OsError> {
+    // The local APIC address and flags come before the entries.
+    let entries = match madt.body().get(8..) {
+        Some(entries) => MadtEntries { entries },
+        None => return make_error!(OsErrorCode::InvalidAcpiTable),
+    };
+
+    let mut info = MadtInfo {
+        local_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        isa_overrides: Vec::new(),
+    };
+    for (entry_type, entry) in entries {
+        let length = entry.len();
+        let (apic_id, flags) = match entry_type {
+            MADT_LOCAL_APIC if length >= 8 => {
+                (entry[3] as u32, read_u32(&entry[4..]))
+            },
+            MADT_LOCAL_X2APIC if length >= 16 => {
+                (read_u32(&entry[4..]), read_u32(&entry[8..]))
+            },
+            MADT_IO_APIC if length >= 12 => {
+                info.io_apics.push(IoApic {
+                    address: read_u32(&entry[4..]) as u64,
+                    gsi_base: read_u32(&entry[8..]),
+                });
+                continue;
+            },
+            MADT_INTERRUPT_SOURCE_OVERRIDE if length >= 10 && entry[2] == 0 => {
+                let flags = read_u16(&entry[8..]);
+                info.isa_overrides.push((entry[3], IsaInterrupt {
+                    gsi: read_u32(&entry[4..]),
+                    active_low: flags & POLARITY_MASK == POLARITY_ACTIVE_LOW,
+                    level_triggered:
+                        flags & TRIGGER_MODE_MASK == TRIGGER_MODE_LEVEL,
+                }));
+                continue;
+            },
+            _ => continue,
+        };
+        if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+            info.local_apic_ids.push(apic_id);
+        }
+    }
+    Ok(info)
+}
+
+fn madt_info() -> Result<&'static MadtInfo, OsError> {
+    match INFO.get().and_then(|info| info.madt.as_ref()) {
+        Some(madt) => Ok(madt),
+        None => make_error!(OsErrorCode::AcpiTableNotFound),
+    }
+}
+
+// Local APIC IDs of the usable processors, from the MADT.
+pub fn local_apic_ids() -> Result<impl Iterator<Item = u32>, OsError> {
+    Ok(madt_info()?.local_apic_ids.iter().copied())
+}
+
+#[derive(Clone, Copy)]
+pub struct IoApic {
+    pub address: u64,
+    // The first global system interrupt of its inputs.
+    pub gsi_base: u32,
+}
+
+pub fn io_apics() -> Result<impl Iterator<Item = IoApic>, OsError> {
+    Ok(madt_info()?.io_apics.iter().copied())
+}
+
+#[derive(Clone, Copy)]
+pub struct IsaInterrupt {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// Where an ISA IRQ is connected, which is the same number unless the MADT
+// overrides it.
+pub fn isa_interrupt(irq: u8) -> IsaInterrupt {
+    let overridden = madt_info().ok().and_then(|madt| {
+        madt.isa_overrides
+            .iter()
+            .find(|(source, _)| *source == irq)
+            .map(|&(_, interrupt)| interrupt)
+    });
+    overridden.unwrap_or(IsaInterrupt {
+        gsi: irq as u32,
+        active_low: false,
+        level_triggered: false,
+    })
+}
+
+fn fadt_has_8042(fadt: &DescriptionHeader) -> bool {
+    let offset = FADT_IAPC_BOOT_ARCH;
+    match fadt.bytes().get(offset..(offset + 2)) {
+        Some(flags) if fadt.revision >= 2 => {
+            read_u16(flags) & IAPC_BOOT_ARCH_8042 != 0
+        },
+        // Tables older than the flag come from PCs which all had one.
+        _ => true,
+    }
+}
+
+// Whether there is an i8042 keyboard controller, from the FADT.
+pub fn has_8042() -> bool {
+    INFO.get().is_some_and(|info| info.has_8042)
+}
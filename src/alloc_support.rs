@@ -1,5 +1,5 @@
-use crate::BUF_MEMMNG;
-use crate::memory_manager::{FrameId, BYTE_PER_FRAME};
+use crate::memory_manager::{memory_manager, FrameId, BYTE_PER_FRAME};
+use crate::paging::virt_to_phys;
 
 use core::{alloc::GlobalAlloc, alloc::Layout, ptr::null_mut};
 
@@ -7,19 +7,18 @@ struct MemoryAllocator;
 
 unsafe impl GlobalAlloc for MemoryAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let memory_manager = BUF_MEMMNG.assume_init_mut();
         let num_frames = (layout.size() + BYTE_PER_FRAME - 1) / BYTE_PER_FRAME;
-        match memory_manager.allocate(num_frames) {
-            Ok(frame) => (frame.id() * BYTE_PER_FRAME) as *mut u8,
+        match memory_manager().allocate(num_frames) {
+            Ok(mut frame) => frame.frame(),
             Err(_) => null_mut(),
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let memory_manager = BUF_MEMMNG.assume_init_mut();
         let num_frames = (layout.size() + BYTE_PER_FRAME - 1) / BYTE_PER_FRAME;
-        let start_frame = FrameId::new(ptr as usize / BYTE_PER_FRAME);
-        memory_manager.free(start_frame, num_frames).unwrap();
+        let start_frame =
+            FrameId::new(virt_to_phys(ptr as u64) as usize / BYTE_PER_FRAME);
+        memory_manager().free(start_frame, num_frames).unwrap();
     }
 }
 
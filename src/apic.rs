@@ -0,0 +1,164 @@
+use crate::acpi::{io_apics, isa_interrupt};
+use crate::error::*;
+use crate::paging::{map_mmio, phys_to_virt};
+use crate::sync::{disable_interrupts, restore_interrupts, IrqLock};
+
+use core::ptr::{read_volatile, write_volatile};
+
+// Local APIC registers. Every CPU sees its own local APIC at this address.
+pub const LAPIC_ID: u64 = 0xfee00020;
+pub const LAPIC_LOGICAL_DESTINATION: u64 = 0xfee000d0;
+pub const LAPIC_DESTINATION_FORMAT: u64 = 0xfee000e0;
+pub const LAPIC_SPURIOUS_VECTOR: u64 = 0xfee000f0;
+pub const LAPIC_ICR_LOW: u64 = 0xfee00300;
+pub const LAPIC_ICR_HIGH: u64 = 0xfee00310;
+pub const LAPIC_LVT_TIMER: u64 = 0xfee00320;
+pub const LAPIC_INITIAL_COUNT: u64 = 0xfee00380;
+pub const LAPIC_CURRENT_COUNT: u64 = 0xfee00390;
+pub const LAPIC_DIVIDE_CONFIG: u64 = 0xfee003e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const DESTINATION_FORMAT_FLAT: u32 = 0xffff_ffff;
+
+// In the flat model each of up to 8 CPUs gets one bit of the logical ID.
+pub const MAX_LOGICAL_APICS: usize = 8;
+
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// I/O APIC registers are read and written through a select register and a
+// window. Redirection table entries are two registers each.
+const IOAPIC_REGISTER_SELECT: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_MMIO_SIZE: u64 = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+// Keeps the select register from changing between the two accesses.
+static IOAPIC_LOCK: IrqLock<()> = IrqLock::new(());
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum IpiDestination {
+    Apic(u32),
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+pub unsafe fn lapic_read(reg: u64) -> u32 {
+    read_volatile(phys_to_virt(reg) as *const u32)
+}
+
+pub unsafe fn lapic_write(reg: u64, value: u32) {
+    write_volatile(phys_to_virt(reg) as *mut u32, value);
+}
+
+pub fn lapic_id() -> u32 {
+    unsafe {
+        lapic_read(LAPIC_ID) >> 24
+    }
+}
+
+// Spurious interrupts use `vector`, which needs an IDT entry but no EOI.
+pub fn enable_lapic(vector: u8) {
+    unsafe {
+        let value = lapic_read(LAPIC_SPURIOUS_VECTOR) & !0xff;
+        lapic_write(
+            LAPIC_SPURIOUS_VECTOR,
+            value | SPURIOUS_APIC_ENABLE | vector as u32,
+        );
+    }
+}
+
+pub fn set_logical_id(index: usize) {
+    if index >= MAX_LOGICAL_APICS {
+        return;
+    }
+    unsafe {
+        lapic_write(LAPIC_DESTINATION_FORMAT, DESTINATION_FORMAT_FLAT);
+        lapic_write(LAPIC_LOGICAL_DESTINATION, 1 << (24 + index));
+    }
+}
+
+// An interrupt in between could send another IPI with a different
+// destination, so the two writes are done with interrupts disabled.
+fn write_icr(apic_id: u32, command: u32) {
+    let interrupts = disable_interrupts();
+    unsafe {
+        lapic_write(LAPIC_ICR_HIGH, apic_id << 24);
+        lapic_write(LAPIC_ICR_LOW, command);
+        while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+    restore_interrupts(interrupts);
+}
+
+pub fn send_ipi(destination: IpiDestination, vector: u8) {
+    let (apic_id, shorthand) = match destination {
+        IpiDestination::Apic(apic_id) => (apic_id, 0),
+        IpiDestination::AllIncludingSelf => (0, ICR_ALL_INCLUDING_SELF),
+        IpiDestination::AllExcludingSelf => (0, ICR_ALL_EXCLUDING_SELF),
+    };
+    write_icr(apic_id, shorthand | ICR_LEVEL_ASSERT | vector as u32);
+}
+
+pub fn send_init(apic_id: u32) {
+    write_icr(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+// The processor starts in real mode at `start_page` * 4096.
+pub fn send_startup(apic_id: u32, start_page: u8) {
+    write_icr(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | start_page as u32);
+}
+
+unsafe fn ioapic_read(base: u64, reg: u32) -> u32 {
+    write_volatile((base + IOAPIC_REGISTER_SELECT) as *mut u32, reg);
+    read_volatile((base + IOAPIC_WINDOW) as *const u32)
+}
+
+unsafe fn ioapic_write(base: u64, reg: u32, value: u32) {
+    write_volatile((base + IOAPIC_REGISTER_SELECT) as *mut u32, reg);
+    write_volatile((base + IOAPIC_WINDOW) as *mut u32, value);
+}
+
+// Delivers the ISA interrupt `irq` to the local APIC `apic_id` as `vector`,
+// through the I/O APIC which has its global system interrupt.
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u32) -> Result<(), OsError> {
+    let interrupt = isa_interrupt(irq);
+    for io_apic in io_apics()? {
+        let base = map_mmio(io_apic.address, IOAPIC_MMIO_SIZE)?;
+        let _lock = IOAPIC_LOCK.lock();
+        let inputs = unsafe {
+            ((ioapic_read(base, IOAPIC_VERSION) >> 16) & 0xff) + 1
+        };
+        if interrupt.gsi < io_apic.gsi_base
+            || interrupt.gsi >= io_apic.gsi_base + inputs
+        {
+            continue;
+        }
+
+        let mut entry = vector as u32;
+        if interrupt.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if interrupt.level_triggered {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        let reg = IOAPIC_REDIRECTION_TABLE
+            + 2 * (interrupt.gsi - io_apic.gsi_base);
+        unsafe {
+            ioapic_write(base, reg + 1, apic_id << 24);
+            ioapic_write(base, reg, entry);
+        }
+        return Ok(());
+    }
+    make_error!(OsErrorCode::IoApicNotFound)
+}
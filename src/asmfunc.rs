@@ -17,6 +17,23 @@ global_asm!(
     "    ret",
 );
 
+global_asm!(
+    ".global io_out8",
+    "io_out8:",
+    "    mov dx, di",
+    "    mov al, sil",
+    "    out dx, al",
+    "    ret",
+);
+
+global_asm!(
+    ".global io_in8",
+    "io_in8:",
+    "    mov dx, di",
+    "    in al, dx",
+    "    ret",
+);
+
 global_asm!(
     ".global get_cs",
     "get_cs:",
@@ -53,6 +70,13 @@ global_asm!(
     "    ret",
 );
 
+global_asm!(
+    ".global load_tr",
+    "load_tr:",
+    "    ltr di",
+    "    ret",
+);
+
 global_asm!(
     ".global set_ds_all",
     "set_ds_all:",
@@ -79,6 +103,151 @@ global_asm!(
     "    ret",
 );
 
+global_asm!(
+    ".global jump_to_user_mode",
+    // (rip: rdi, rsp: rsi, cs: dx, ss: cx, kernel_rsp: r8)
+    "jump_to_user_mode:",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    pushfq",
+    "    mov [r8], rsp",
+    "    push rcx",   // SS
+    "    push rsi",   // RSP
+    "    push 0x202", // RFLAGS (IF)
+    "    push rdx",   // CS
+    "    push rdi",   // RIP
+    // Nothing may run with the user's GS base in the kernel.
+    "    cli",
+    "    swapgs",
+    "    xor eax, eax",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    xor ebx, ebx",
+    "    xor ecx, ecx",
+    "    xor edx, edx",
+    "    xor esi, esi",
+    "    xor edi, edi",
+    "    xor ebp, ebp",
+    "    xor r8d, r8d",
+    "    xor r9d, r9d",
+    "    xor r10d, r10d",
+    "    xor r11d, r11d",
+    "    xor r12d, r12d",
+    "    xor r13d, r13d",
+    "    xor r14d, r14d",
+    "    xor r15d, r15d",
+    "    iretq",
+);
+
+global_asm!(
+    ".global return_from_user_mode",
+    "return_from_user_mode:", // (kernel_rsp: rdi, code: esi)
+    "    mov rsp, rdi",
+    "    mov eax, esi",
+    "    popfq",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+);
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // Interrupts are masked until sysretq.
+    "    swapgs",
+    "    mov gs:[16], rsp", // Cpu::syscall_user_rsp
+    "    mov rsp, gs:[8]",  // Cpu::syscall_stack
+    "    push qword ptr gs:[16]",
+    "    push rcx", // user RIP
+    "    push r11", // user RFLAGS
+    "    push rbp",
+    "    mov rbp, rsp",
+    "    push r9",
+    "    push r8",
+    "    push r10",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    mov rdi, rax",
+    "    mov rsi, rsp",
+    "    call handle_syscall",
+    "    pop rdi",
+    "    pop rsi",
+    "    add rsp, 8", // rdx holds the error
+    "    pop r10",
+    "    pop r8",
+    "    pop r9",
+    "    pop rbp",
+    "    pop r11",
+    "    pop rcx",
+    "    pop rsp",
+    "    swapgs",
+    "    sysretq",
+);
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:", // (next_rsp: rdi, current_rsp: *mut u64 rsi)
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    pushfq",
+    "    sub rsp, 512",
+    "    fxsave64 [rsp]",
+    "    mov [rsi], rsp",
+    "    mov rsp, rdi",
+    "    fxrstor64 [rsp]",
+    "    add rsp, 512",
+    "    popfq",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+);
+
+// A new task starts here when switch_context returns for the first time.
+global_asm!(
+    ".global task_trampoline",
+    "task_trampoline:",
+    "    call task_start",
+    "    ud2",
+);
+
+global_asm!(
+    ".global read_msr",
+    "read_msr:",
+    "    mov ecx, edi",
+    "    rdmsr",
+    "    shl rdx, 32",
+    "    or rax, rdx",
+    "    ret",
+);
+
+global_asm!(
+    ".global write_msr",
+    "write_msr:",
+    "    mov ecx, edi",
+    "    mov eax, esi",
+    "    mov rdx, rsi",
+    "    shr rdx, 32",
+    "    wrmsr",
+    "    ret",
+);
+
 global_asm!(
     ".global set_cr3",
     "set_cr3:",
@@ -87,11 +256,156 @@ global_asm!(
 );
 
 global_asm!(
+    ".global get_cr0",
+    "get_cr0:",
+    "    mov rax, cr0",
+    "    ret",
+);
+
+global_asm!(
+    ".global set_cr0",
+    "set_cr0:",
+    "    mov cr0, rdi",
+    "    ret",
+);
+
+global_asm!(
+    ".global get_cr2",
+    "get_cr2:",
+    "    mov rax, cr2",
+    "    ret",
+);
+
+global_asm!(
+    ".global get_cr3",
+    "get_cr3:",
+    "    mov rax, cr3",
+    "    ret",
+);
+
+global_asm!(
+    ".global invlpg",
+    "invlpg:",
+    "    invlpg [rdi]",
+    "    ret",
+);
+
+// The loader jumps here with the identity mapping built by UEFI, so this
+// part is linked at its physical address. It builds the boot page table
+// (identity, direct map at 0xffff800000000000 and the first 1 GiB at
+// 0xffffffff80000000 for the kernel image) and continues in the higher half.
+// rdi, rsi and rdx hold the loader's arguments and are left untouched.
+global_asm!(
+    ".pushsection .boot, \"ax\"",
     ".global kernel_main",
     "kernel_main:",
-    "    mov rsp, OFFSET KERNEL_MAIN_STACK + 1024 * 1024",
+    "    movabs r8, 0xffffffff80000000",
+    "    movabs r9, OFFSET PML4_TABLE",
+    "    sub r9, r8",
+    "    movabs r10, OFFSET PDP_TABLE",
+    "    sub r10, r8",
+    "    movabs r11, OFFSET KERNEL_PDP_TABLE",
+    "    sub r11, r8",
+    "    movabs rbx, OFFSET PAGE_DIRECTORY",
+    "    sub rbx, r8",
+    "    movabs rax, OFFSET KERNEL_PAGE_DIRECTORY",
+    "    sub rax, r8",
+    "    mov r8, rax",
+    "    lea rax, [r10 + 0x003]",
+    "    mov [r9], rax",
+    "    mov [r9 + 256 * 8], rax",
+    "    lea rax, [r11 + 0x003]",
+    "    mov [r9 + 511 * 8], rax",
+    "    lea rax, [r8 + 0x003]",
+    "    mov [r11 + 510 * 8], rax",
+    "    xor ecx, ecx",
+    ".Lboot_pdp_loop:",
+    "    mov rax, rcx",
+    "    shl rax, 12",
+    "    lea rax, [rbx + rax + 0x003]",
+    "    mov [r10 + rcx * 8], rax",
+    "    inc rcx",
+    "    cmp rcx, 64",
+    "    jb .Lboot_pdp_loop",
+    "    xor ecx, ecx",
+    ".Lboot_pd_loop:",
+    "    mov rax, rcx",
+    "    shl rax, 21",
+    "    or rax, 0x083",
+    "    mov [rbx + rcx * 8], rax",
+    "    cmp rcx, 512",
+    "    jae .Lboot_pd_next",
+    "    mov [r8 + rcx * 8], rax",
+    ".Lboot_pd_next:",
+    "    inc rcx",
+    "    cmp rcx, 64 * 512",
+    "    jb .Lboot_pd_loop",
+    "    mov cr3, r9",
+    "    movabs rax, OFFSET kernel_main_higher_half",
+    "    jmp rax",
+    ".popsection",
+);
+
+// Arguments from the loader are physical addresses. Pass the boot structures
+// through the direct map; the RSDP address is passed as is.
+global_asm!(
+    "kernel_main_higher_half:",
+    "    mov rsp, OFFSET KERNEL_MAIN_STACK + 4096 + 1024 * 1024",
+    "    movabs rax, 0xffff800000000000",
+    "    add rdi, rax",
+    "    add rsi, rax",
     "    call kernel_main_new_stack",
     ".fin:",
     "    hlt",
     "    jmp .fin",
 );
+
+// Application processors start here in real mode, at the page the trampoline
+// is copied to (below 1 MiB, identity mapped). The parameters are filled in by
+// `smp.rs`; keep the offsets in sync with TrampolineParams.
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_gdt",
+    ".global ap_trampoline_params",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    lgdt [AP_TRAMPOLINE_PARAMS]",
+    "    mov eax, [AP_TRAMPOLINE_PARAMS + 20]", // cr4
+    "    mov cr4, eax",
+    "    mov eax, [AP_TRAMPOLINE_PARAMS + 16]", // cr3
+    "    mov cr3, eax",
+    "    mov ecx, 0xc0000080", // EFER.LME and EFER.NXE
+    "    rdmsr",
+    "    or eax, 0x900",
+    "    wrmsr",
+    "    mov eax, [AP_TRAMPOLINE_PARAMS + 12]", // cr0, enables paging
+    "    mov cr0, eax",
+    "    jmp fword ptr [AP_TRAMPOLINE_PARAMS + 6]",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    xor eax, eax",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov rsp, [rip + ap_trampoline_params + 24]",
+    "    mov rdi, [rip + ap_trampoline_params + 32]",
+    "    mov rsi, [rip + ap_trampoline_params + 40]",
+    "    call [rip + ap_trampoline_params + 48]",
+    "    ud2",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00af9a000000ffff", // 64-bit code
+    "    .quad 0x00cf92000000ffff", // data
+    "ap_trampoline_params:",
+    "    .skip 56",
+    "ap_trampoline_end:",
+    ".set AP_TRAMPOLINE_PARAMS, ap_trampoline_params - ap_trampoline_start",
+    ".popsection",
+);
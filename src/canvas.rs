@@ -0,0 +1,504 @@
+use crate::graphics::{PixelColor, PixelWriter, Rectangle, Vector2D, alpha_blend};
+
+use alloc::vec::Vec;
+use core::mem::swap;
+
+// Shapes may extend past the canvas, so points are signed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Point {
+    pub x: isize,
+    pub y: isize,
+}
+
+// Draws shapes on a pixel writer, clipped to the clip rectangle, which is
+// always inside the writer.
+pub struct Canvas<'a> {
+    writer: &'a mut dyn PixelWriter,
+    clip: Rectangle,
+}
+
+impl<'a> Canvas<'a> {
+    pub fn new(writer: &'a mut dyn PixelWriter) -> Self {
+        let clip =
+            Rectangle { pos: Vector2D { x: 0, y: 0 }, size: writer.size() };
+        Self { writer, clip }
+    }
+
+    pub fn set_clip(&mut self, clip: &Rectangle) {
+        let bounds = Rectangle {
+            pos: Vector2D { x: 0, y: 0 },
+            size: self.writer.size(),
+        };
+        self.clip = clip.intersection(&bounds).unwrap_or(Rectangle {
+            pos: Vector2D { x: 0, y: 0 },
+            size: Vector2D { x: 0, y: 0 },
+        });
+    }
+
+    #[allow(dead_code)]
+    pub fn clip(&self) -> Rectangle {
+        self.clip
+    }
+
+    // Whether the box with the edges given, all included, overlaps the clip
+    // rectangle.
+    #[allow(dead_code)]
+    fn overlaps_clip(
+        &self,
+        left: isize,
+        top: isize,
+        right: isize,
+        bottom: isize,
+    ) -> bool {
+        let clip = &self.clip;
+        !clip.is_empty()
+            && right >= clip.pos.x as isize
+            && bottom >= clip.pos.y as isize
+            && left < (clip.pos.x + clip.size.x) as isize
+            && top < (clip.pos.y + clip.size.y) as isize
+    }
+
+    fn contains(&self, x: isize, y: isize) -> bool {
+        let clip = &self.clip;
+        x >= clip.pos.x as isize
+            && y >= clip.pos.y as isize
+            && x < (clip.pos.x + clip.size.x) as isize
+            && y < (clip.pos.y + clip.size.y) as isize
+    }
+
+    pub fn put_pixel(&mut self, x: isize, y: isize, color: &PixelColor) {
+        if self.contains(x, y) {
+            self.writer.write(x as usize, y as usize, color);
+        }
+    }
+
+    // `alpha` of 255 is opaque.
+    #[allow(dead_code)]
+    fn blend_pixel(
+        &mut self,
+        x: isize,
+        y: isize,
+        color: &PixelColor,
+        alpha: u8,
+    ) {
+        if self.contains(x, y) {
+            let below = self.writer.read(x as usize, y as usize);
+            let color = alpha_blend(color, &below, alpha);
+            self.writer.write(x as usize, y as usize, &color);
+        }
+    }
+
+    // From x0 to x1, both included.
+    fn span(&mut self, x0: isize, x1: isize, y: isize, color: &PixelColor) {
+        let clip = &self.clip;
+        if y < clip.pos.y as isize || y >= (clip.pos.y + clip.size.y) as isize {
+            return;
+        }
+        let x0 = x0.max(clip.pos.x as isize);
+        let x1 = x1.min((clip.pos.x + clip.size.x) as isize - 1);
+        if x0 <= x1 {
+            self.writer.fill_span(
+                x0 as usize,
+                y as usize,
+                (x1 - x0 + 1) as usize,
+                color,
+            );
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: &Rectangle, color: &PixelColor) {
+        if rect.is_empty() {
+            return;
+        }
+        let x0 = rect.pos.x as isize;
+        let x1 = x0 + rect.size.x as isize - 1;
+        for y in rect.pos.y..(rect.pos.y + rect.size.y) {
+            self.span(x0, x1, y as isize, color);
+        }
+    }
+
+    // Cohen-Sutherland algorithm. The part of the line from p0 to p1 inside
+    // the clip rectangle, if any. Intersections are rounded toward p0.
+    fn clip_line(
+        &self,
+        mut p0: Point,
+        mut p1: Point,
+    ) -> Option<(Point, Point)> {
+        const LEFT: u8 = 1;
+        const RIGHT: u8 = 2;
+        const TOP: u8 = 4;
+        const BOTTOM: u8 = 8;
+
+        if self.clip.is_empty() {
+            return None;
+        }
+        let left = self.clip.pos.x as isize;
+        let top = self.clip.pos.y as isize;
+        let right = left + self.clip.size.x as isize - 1;
+        let bottom = top + self.clip.size.y as isize - 1;
+        let outcode = |p: Point| {
+            let mut code = 0;
+            if p.x < left {
+                code |= LEFT;
+            } else if p.x > right {
+                code |= RIGHT;
+            }
+            if p.y < top {
+                code |= TOP;
+            } else if p.y > bottom {
+                code |= BOTTOM;
+            }
+            code
+        };
+        loop {
+            let (code0, code1) = (outcode(p0), outcode(p1));
+            if code0 | code1 == 0 {
+                return Some((p0, p1));
+            }
+            if code0 & code1 != 0 {
+                return None;
+            }
+            // The edge crossed lies between the endpoints, so the divisor
+            // is not zero and the new point stays on the segment.
+            let code = if code0 != 0 { code0 } else { code1 };
+            let (dx, dy) = (p1.x - p0.x, p1.y - p0.y);
+            let p = if code & TOP != 0 {
+                Point { x: p0.x + dx * (top - p0.y) / dy, y: top }
+            } else if code & BOTTOM != 0 {
+                Point { x: p0.x + dx * (bottom - p0.y) / dy, y: bottom }
+            } else if code & LEFT != 0 {
+                Point { x: left, y: p0.y + dy * (left - p0.x) / dx }
+            } else {
+                Point { x: right, y: p0.y + dy * (right - p0.x) / dx }
+            };
+            if code == code0 {
+                p0 = p;
+            } else {
+                p1 = p;
+            }
+        }
+    }
+
+    // Bresenham's algorithm, from the clipped endpoints.
+    pub fn draw_line(&mut self, p0: Point, p1: Point, color: &PixelColor) {
+        let (p0, p1) = match self.clip_line(p0, p1) {
+            Some(clipped) => clipped,
+            None => return,
+        };
+        let dx = (p1.x - p0.x).abs();
+        let dy = -(p1.y - p0.y).abs();
+        let step_x = if p0.x < p1.x { 1 } else { -1 };
+        let step_y = if p0.y < p1.y { 1 } else { -1 };
+        let (mut x, mut y) = (p0.x, p0.y);
+        let mut err = dx + dy;
+        loop {
+            self.put_pixel(x, y, color);
+            if x == p1.x && y == p1.y {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += step_x;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    // Xiaolin Wu's algorithm, in 16.16 fixed point. Blends with what is
+    // below, so the writer is read as well.
+    #[allow(dead_code)]
+    pub fn draw_line_antialiased(
+        &mut self,
+        p0: Point,
+        p1: Point,
+        color: &PixelColor,
+    ) {
+        let (mut x0, mut y0, mut x1, mut y1) = (p0.x, p0.y, p1.x, p1.y);
+        let steep = (y1 - y0).abs() > (x1 - x0).abs();
+        if steep {
+            swap(&mut x0, &mut y0);
+            swap(&mut x1, &mut y1);
+        }
+        if x0 > x1 {
+            swap(&mut x0, &mut x1);
+            swap(&mut y0, &mut y1);
+        }
+        let dx = x1 - x0;
+        if dx == 0 {
+            self.put_pixel(p0.x, p0.y, color);
+            return;
+        }
+        // Only the columns (rows if steep) inside the clip rectangle.
+        let (clip_start, clip_len) = if steep {
+            (self.clip.pos.y, self.clip.size.y)
+        } else {
+            (self.clip.pos.x, self.clip.size.x)
+        };
+        let start = x0.max(clip_start as isize);
+        let end = x1.min((clip_start + clip_len) as isize - 1);
+        let gradient = ((y1 - y0) << 16) / dx;
+        let mut y = (y0 << 16) + gradient * (start - x0);
+        for x in start..=end {
+            let fraction = y & 0xffff;
+            let upper = ((255 * (0x10000 - fraction)) >> 16) as u8;
+            let lower = ((255 * fraction) >> 16) as u8;
+            if steep {
+                self.blend_pixel(y >> 16, x, color, upper);
+                self.blend_pixel((y >> 16) + 1, x, color, lower);
+            } else {
+                self.blend_pixel(x, y >> 16, color, upper);
+                self.blend_pixel(x, (y >> 16) + 1, color, lower);
+            }
+            y += gradient;
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn draw_ellipse(
+        &mut self,
+        center: Point,
+        rx: isize,
+        ry: isize,
+        color: &PixelColor,
+    ) {
+        if !self.overlaps_clip(
+            center.x - rx,
+            center.y - ry,
+            center.x + rx,
+            center.y + ry,
+        ) {
+            return;
+        }
+        for_each_ellipse_point(rx, ry, |x, y| {
+            self.put_pixel(center.x + x, center.y + y, color);
+            self.put_pixel(center.x - x, center.y + y, color);
+            self.put_pixel(center.x + x, center.y - y, color);
+            self.put_pixel(center.x - x, center.y - y, color);
+        });
+    }
+
+    #[allow(dead_code)]
+    pub fn fill_ellipse(
+        &mut self,
+        center: Point,
+        rx: isize,
+        ry: isize,
+        color: &PixelColor,
+    ) {
+        if !self.overlaps_clip(
+            center.x - rx,
+            center.y - ry,
+            center.x + rx,
+            center.y + ry,
+        ) {
+            return;
+        }
+        for_each_ellipse_point(rx, ry, |x, y| {
+            self.span(center.x - x, center.x + x, center.y + y, color);
+            self.span(center.x - x, center.x + x, center.y - y, color);
+        });
+    }
+
+    #[allow(dead_code)]
+    pub fn draw_circle(
+        &mut self,
+        center: Point,
+        radius: isize,
+        color: &PixelColor,
+    ) {
+        self.draw_ellipse(center, radius, radius, color);
+    }
+
+    #[allow(dead_code)]
+    pub fn fill_circle(
+        &mut self,
+        center: Point,
+        radius: isize,
+        color: &PixelColor,
+    ) {
+        self.fill_ellipse(center, radius, radius, color);
+    }
+
+    #[allow(dead_code)]
+    pub fn draw_polygon(&mut self, points: &[Point], color: &PixelColor) {
+        for (i, &p0) in points.iter().enumerate() {
+            let p1 = points[(i + 1) % points.len()];
+            self.draw_line(p0, p1, color);
+        }
+    }
+
+    // Even-odd rule: a scan line is filled between pairs of crossed edges.
+    #[allow(dead_code)]
+    pub fn fill_polygon(&mut self, points: &[Point], color: &PixelColor) {
+        if points.len() < 3 {
+            return;
+        }
+        let min_y = points
+            .iter()
+            .map(|p| p.y)
+            .min()
+            .unwrap()
+            .max(self.clip.pos.y as isize);
+        let max_y = points
+            .iter()
+            .map(|p| p.y)
+            .max()
+            .unwrap()
+            .min((self.clip.pos.y + self.clip.size.y) as isize - 1);
+        let mut crossings = Vec::new();
+        for y in min_y..=max_y {
+            crossings.clear();
+            for (i, &a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                if (a.y <= y && b.y > y) || (b.y <= y && a.y > y) {
+                    crossings.push(a.x + (y - a.y) * (b.x - a.x) / (b.y - a.y));
+                }
+            }
+            crossings.sort_unstable();
+            for pair in crossings.chunks_exact(2) {
+                self.span(pair[0], pair[1], y, color);
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn draw_rounded_rect(
+        &mut self,
+        rect: &Rectangle,
+        radius: usize,
+        color: &PixelColor,
+    ) {
+        let (left, top, right, bottom, radius) =
+            match rounded_rect_bounds(rect, radius) {
+                Some(bounds) => bounds,
+                None => return,
+            };
+        self.span(left + radius, right - radius, top, color);
+        self.span(left + radius, right - radius, bottom, color);
+        for y in (top + radius)..=(bottom - radius) {
+            self.put_pixel(left, y, color);
+            self.put_pixel(right, y, color);
+        }
+        for_each_ellipse_point(radius, radius, |x, y| {
+            self.put_pixel(left + radius - x, top + radius - y, color);
+            self.put_pixel(right - radius + x, top + radius - y, color);
+            self.put_pixel(left + radius - x, bottom - radius + y, color);
+            self.put_pixel(right - radius + x, bottom - radius + y, color);
+        });
+    }
+
+    pub fn fill_rounded_rect(
+        &mut self,
+        rect: &Rectangle,
+        radius: usize,
+        color: &PixelColor,
+    ) {
+        let (left, top, right, bottom, radius) =
+            match rounded_rect_bounds(rect, radius) {
+                Some(bounds) => bounds,
+                None => return,
+            };
+        for y in (top + radius)..=(bottom - radius) {
+            self.span(left, right, y, color);
+        }
+        for_each_ellipse_point(radius, radius, |x, y| {
+            self.span(
+                left + radius - x,
+                right - radius + x,
+                top + radius - y,
+                color,
+            );
+            self.span(
+                left + radius - x,
+                right - radius + x,
+                bottom - radius + y,
+                color,
+            );
+        });
+    }
+}
+
+// Edges of `rect`, all included, and the radius limited to fit.
+fn rounded_rect_bounds(
+    rect: &Rectangle,
+    radius: usize,
+) -> Option<(isize, isize, isize, isize, isize)> {
+    if rect.is_empty() {
+        return None;
+    }
+    let radius = radius
+        .min((rect.size.x - 1) / 2)
+        .min((rect.size.y - 1) / 2)
+        .min(MAX_RADIUS as usize);
+    Some((
+        rect.pos.x as isize,
+        rect.pos.y as isize,
+        (rect.pos.x + rect.size.x - 1) as isize,
+        (rect.pos.y + rect.size.y - 1) as isize,
+        radius as isize,
+    ))
+}
+
+// Largest radius for_each_ellipse_point takes. The terms of the midpoint
+// algorithm grow as the fourth power of the radius and must fit in i64.
+const MAX_RADIUS: isize = 1 << 14;
+
+// Calls `f` with the points of the quarter of the ellipse where x, y >= 0,
+// by the midpoint algorithm. Values are doubled or quadrupled to stay in
+// integers. Nothing is drawn for a radius above MAX_RADIUS.
+fn for_each_ellipse_point<F: FnMut(isize, isize)>(
+    rx: isize,
+    ry: isize,
+    mut f: F,
+) {
+    if rx < 0 || ry < 0 || rx > MAX_RADIUS || ry > MAX_RADIUS {
+        return;
+    }
+    if rx == 0 || ry == 0 {
+        for x in 0..=rx {
+            for y in 0..=ry {
+                f(x, y);
+            }
+        }
+        return;
+    }
+    let (rx2, ry2) = ((rx * rx) as i64, (ry * ry) as i64);
+    let (mut x, mut y) = (0i64, ry as i64);
+    let mut px = 0;
+    let mut py = 2 * rx2 * y;
+
+    // Where the slope is above -1, x advances every step.
+    let mut p = 4 * ry2 - 4 * rx2 * ry as i64 + rx2;
+    while px < py {
+        f(x as isize, y as isize);
+        x += 1;
+        px += 2 * ry2;
+        if p < 0 {
+            p += 4 * (ry2 + px);
+        } else {
+            y -= 1;
+            py -= 2 * rx2;
+            p += 4 * (ry2 + px - py);
+        }
+    }
+
+    // Then y does.
+    let mut p = ry2 * (2 * x + 1) * (2 * x + 1) + 4 * rx2 * (y - 1) * (y - 1)
+        - 4 * rx2 * ry2;
+    while y >= 0 {
+        f(x as isize, y as isize);
+        y -= 1;
+        py -= 2 * rx2;
+        if p > 0 {
+            p += 4 * (rx2 - py);
+        } else {
+            x += 1;
+            px += 2 * ry2;
+            p += 4 * (rx2 - py + px);
+        }
+    }
+}
@@ -1,85 +1,231 @@
-use crate::font::{
-    FONT_WIDTH, FONT_HEIGHT,
-    write_ascii, write_string
-};
-use crate::graphics::{PixelColor, PixelWriter};
+use crate::font::{FONT_WIDTH, FONT_HEIGHT, write_ascii};
+use crate::graphics::{PixelColor, PixelWriter, Rectangle, Vector2D};
+use crate::layer::{Layer, LayerId, LayerManager, SharedLayerManager};
 
-use core::cell::RefCell;
+use alloc::collections::VecDeque;
 
 const ROWS: usize = 25;
 const COLUMNS: usize = 80;
+const TAB_WIDTH: usize = 8;
+const CURSOR_HEIGHT: usize = 2;
 
+pub const CURSOR_BLINK_INTERVAL: u64 = 500;
+
+type Line = [u8; COLUMNS];
+
+// The last ROWS lines are on the screen and the ones before are the
+// scrollback, which can be viewed a page at a time. Output always shows the
+// screen again.
 pub struct Console<'a> {
-    buffer: [[u8; COLUMNS]; ROWS],
+    lines: VecDeque<Line>,
+    // Lines kept above the screen at most.
+    scrollback: usize,
+    // Lines the view is scrolled back by.
+    view_offset: usize,
     cursor_row: usize,
+    // COLUMNS after the last column, until the next character wraps.
     cursor_column: usize,
+    // Turned on and off by blink_cursor.
+    cursor_on: bool,
     fg_color: PixelColor,
     bg_color: PixelColor,
-    writer: &'a RefCell<&'a mut dyn PixelWriter>,
+    layers: &'a SharedLayerManager,
+    layer: LayerId,
 }
 
 impl<'a> Console<'a> {
     pub fn new(
         fg_color: PixelColor,
         bg_color: PixelColor,
-        writer: &'a RefCell<&'a mut dyn PixelWriter>,
+        layers: &'a SharedLayerManager,
+        scrollback: usize,
     ) -> Self {
+        let mut manager = layers.lock();
+        let layer = manager.new_layer(FONT_WIDTH * COLUMNS, FONT_HEIGHT * ROWS);
+        manager.layer_mut(layer).unwrap().fill(&bg_color);
+        manager.show(layer);
+        manager.compose();
+        drop(manager);
+
         Console {
-            buffer: [[0; COLUMNS]; ROWS],
+            lines: vec![[0; COLUMNS]; ROWS].into(),
+            scrollback,
+            view_offset: 0,
             cursor_row: 0,
             cursor_column: 0,
+            cursor_on: true,
             fg_color,
             bg_color,
-            writer,
+            layers,
+            layer,
         }
     }
 }
 
 impl<'a> Console<'a> {
     pub fn put_string<A: AsRef<str>>(&mut self, s: A) {
+        let mut layers = self.layers.lock();
+        self.put_string_to(&mut layers, s);
+    }
+
+    // Gives up instead of waiting for the layers, and returns whether the
+    // string was written.
+    pub fn try_put_string<A: AsRef<str>>(&mut self, s: A) -> bool {
+        match self.layers.try_lock() {
+            Some(mut layers) => {
+                self.put_string_to(&mut layers, s);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn put_string_to<A: AsRef<str>>(
+        &mut self,
+        layers: &mut LayerManager,
+        s: A,
+    ) {
+        let layer = layers.layer_mut(self.layer).unwrap();
+        if self.view_offset > 0 {
+            self.view_offset = 0;
+            self.redraw(layer);
+        }
+        self.draw_cursor(layer, false);
         for c in s.as_ref().chars() {
-            if c == '\n' {
-                self.new_line();
-            } else if self.cursor_column < COLUMNS {
-                write_ascii(
-                    self.writer,
-                    FONT_WIDTH * self.cursor_column,
-                    FONT_HEIGHT * self.cursor_row,
-                    c,
-                    &self.fg_color,
-                );
-                self.buffer[self.cursor_row][self.cursor_column] = c as u8;
-                self.cursor_column += 1;
+            match c {
+                '\n' => self.new_line(layer),
+                '\r' => self.cursor_column = 0,
+                '\x08' => {
+                    self.cursor_column = self.cursor_column.saturating_sub(1);
+                },
+                '\t' => {
+                    let next = (self.cursor_column / TAB_WIDTH + 1) * TAB_WIDTH;
+                    self.cursor_column = next.min(COLUMNS);
+                },
+                _ => {
+                    if self.cursor_column == COLUMNS {
+                        self.new_line(layer);
+                    }
+                    *self.screen_cell(self.cursor_row, self.cursor_column) =
+                        c as u8;
+                    self.draw_cell(layer, self.cursor_row, self.cursor_column);
+                    self.cursor_column += 1;
+                },
             }
         }
+        self.draw_cursor(layer, self.cursor_on);
+        layers.compose();
     }
 
-    fn new_line(&mut self) {
+    // Empties the screen and moves the cursor to the top left. The
+    // scrollback is kept.
+    #[allow(dead_code)]
+    pub fn clear(&mut self) {
+        let first = self.lines.len() - ROWS;
+        for line in self.lines.range_mut(first..) {
+            line.fill(0);
+        }
+        self.cursor_row = 0;
+        self.cursor_column = 0;
+        self.view_offset = 0;
+        self.redraw_and_compose();
+    }
+
+    pub fn page_up(&mut self) {
+        let limit = self.lines.len() - ROWS;
+        self.view_offset = (self.view_offset + ROWS).min(limit);
+        self.redraw_and_compose();
+    }
+
+    pub fn page_down(&mut self) {
+        self.view_offset = self.view_offset.saturating_sub(ROWS);
+        self.redraw_and_compose();
+    }
+
+    pub fn blink_cursor(&mut self) {
+        self.cursor_on = !self.cursor_on;
+        let mut layers = self.layers.lock();
+        let layer = layers.layer_mut(self.layer).unwrap();
+        self.draw_cursor(layer, self.cursor_on);
+        layers.compose();
+    }
+
+    fn screen_cell(&mut self, row: usize, column: usize) -> &mut u8 {
+        let index = self.lines.len() - ROWS + row;
+        &mut self.lines[index][column]
+    }
+
+    fn new_line(&mut self, layer: &mut Layer) {
         self.cursor_column = 0;
         if self.cursor_row < ROWS - 1 {
             self.cursor_row += 1;
-        } else {
-            for y in 0..(FONT_HEIGHT * ROWS) {
-                for x in 0..(FONT_WIDTH * COLUMNS) {
-                    self.writer.borrow_mut().write(x, y, &self.bg_color);
-                }
-            }
-            for row in 0..(ROWS - 1) {
-                self.buffer.copy_within((row + 1)..(row + 2), row);
-                let s = unsafe {
-                    // Initial values at the back of buffer are also
-                    // included in string.
-                    core::str::from_utf8_unchecked(&self.buffer[row])
-                };
-                write_string(
-                    self.writer,
-                    0,
-                    FONT_HEIGHT * row,
-                    s,
-                    &self.fg_color
-                );
+            return;
+        }
+
+        self.lines.push_back([0; COLUMNS]);
+        if self.lines.len() > ROWS + self.scrollback {
+            self.lines.pop_front();
+        }
+        // Move the pixels up instead of drawing the text again.
+        layer.copy_rect(
+            &Rectangle {
+                pos: Vector2D { x: 0, y: FONT_HEIGHT },
+                size: Vector2D {
+                    x: FONT_WIDTH * COLUMNS,
+                    y: FONT_HEIGHT * (ROWS - 1),
+                },
+            },
+            &Vector2D { x: 0, y: 0 },
+        );
+        for y in (FONT_HEIGHT * (ROWS - 1))..(FONT_HEIGHT * ROWS) {
+            layer.fill_span(0, y, FONT_WIDTH * COLUMNS, &self.bg_color);
+        }
+    }
+
+    fn draw_cell(&self, layer: &mut Layer, row: usize, column: usize) {
+        let first = self.lines.len() - ROWS - self.view_offset;
+        let line = &self.lines[first + row];
+        let x = FONT_WIDTH * column;
+        let y = FONT_HEIGHT * row;
+        for dy in 0..FONT_HEIGHT {
+            layer.fill_span(x, y + dy, FONT_WIDTH, &self.bg_color);
+        }
+        if line[column] != 0 {
+            write_ascii(layer, x, y, line[column] as char, &self.fg_color);
+        }
+    }
+
+    // Draws an underline at the cursor, or the cell without it. The cursor
+    // is off the screen while the view is scrolled back.
+    fn draw_cursor(&self, layer: &mut Layer, on: bool) {
+        if self.view_offset > 0 {
+            return;
+        }
+        let row = self.cursor_row;
+        let column = self.cursor_column.min(COLUMNS - 1);
+        if !on {
+            self.draw_cell(layer, row, column);
+            return;
+        }
+        let x = FONT_WIDTH * column;
+        let y = FONT_HEIGHT * (row + 1) - CURSOR_HEIGHT;
+        for dy in 0..CURSOR_HEIGHT {
+            layer.fill_span(x, y + dy, FONT_WIDTH, &self.fg_color);
+        }
+    }
+
+    fn redraw(&self, layer: &mut Layer) {
+        for row in 0..ROWS {
+            for column in 0..COLUMNS {
+                self.draw_cell(layer, row, column);
             }
-            self.buffer[ROWS - 1].fill(0);
         }
+        self.draw_cursor(layer, self.cursor_on);
+    }
+
+    fn redraw_and_compose(&self) {
+        let mut layers = self.layers.lock();
+        self.redraw(layers.layer_mut(self.layer).unwrap());
+        layers.compose();
     }
 }
@@ -0,0 +1,383 @@
+use crate::error::*;
+use crate::file::find_file;
+use crate::memory_manager::{memory_manager, BYTE_PER_FRAME};
+use crate::paging::{
+    PageTableManager, PageFlags, phys_to_virt, PAGE_SIZE_4K,
+    USER_HEAP_BASE, USER_SPACE_END, USER_STACK_TOP,
+};
+use crate::segment::call_user_mode;
+use crate::syscall::reset_process_state;
+use crate::task::activate_address_space;
+
+use core::{
+    mem::size_of, ptr::copy_nonoverlapping, ptr::read_unaligned,
+    ptr::write_bytes,
+};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+// Auxiliary vector types.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+const USER_STACK_PAGES: u64 = 16;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+#[allow(dead_code)]
+struct Elf64Header {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+#[allow(dead_code)]
+struct Elf64ProgramHeader {
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+pub struct UserProgram {
+    pub page_table: PageTableManager,
+    pub entry: u64,
+    pub stack_pointer: u64,
+}
+
+fn read_struct<T: Copy>(data: &[u8], offset: u64) -> Result<T, OsError> {
+    match offset.checked_add(size_of::<T>() as u64) {
+        Some(end) if end <= data.len() as u64 => unsafe {
+            Ok(read_unaligned(data.as_ptr().add(offset as usize) as *const T))
+        },
+        _ => make_error!(OsErrorCode::ElfTruncated),
+    }
+}
+
+fn read_header(data: &[u8]) -> Result<Elf64Header, OsError> {
+    let header: Elf64Header = read_struct(data, 0)?;
+    if header.ident[..4] != ELF_MAGIC {
+        return make_error!(OsErrorCode::InvalidElfMagic);
+    }
+    if header.ident[4] != ELF_CLASS_64 ||
+        header.ident[5] != ELF_DATA_LSB ||
+        header.ident[6] != ELF_VERSION_CURRENT ||
+        header.elf_type != ELF_TYPE_EXEC ||
+        header.machine != ELF_MACHINE_X86_64 ||
+        header.phentsize as usize != size_of::<Elf64ProgramHeader>() {
+        return make_error!(OsErrorCode::UnsupportedElfFormat);
+    }
+    Ok(header)
+}
+
+fn program_header(
+    data: &[u8],
+    header: &Elf64Header,
+    index: u16,
+) -> Result<Elf64ProgramHeader, OsError> {
+    let offset = header.phoff
+        .checked_add(index as u64 * header.phentsize as u64);
+    match offset {
+        Some(offset) => read_struct(data, offset),
+        None => make_error!(OsErrorCode::ElfTruncated),
+    }
+}
+
+fn check_segment(data: &[u8], phdr: &Elf64ProgramHeader) -> Result<(), OsError> {
+    let file_end = phdr.offset.checked_add(phdr.filesz);
+    let mem_end = phdr.vaddr.checked_add(phdr.memsz);
+    match (file_end, mem_end) {
+        (Some(file_end), Some(mem_end))
+            if file_end <= data.len() as u64 &&
+               phdr.filesz <= phdr.memsz &&
+               phdr.vaddr >= PAGE_SIZE_4K &&
+               mem_end <= USER_HEAP_BASE => Ok(()),
+        (None, _) => make_error!(OsErrorCode::ElfTruncated),
+        (Some(file_end), _) if file_end > data.len() as u64 => {
+            make_error!(OsErrorCode::ElfTruncated)
+        },
+        _ => make_error!(OsErrorCode::InvalidElfSegment),
+    }
+}
+
+fn segment_flags(flags: u32) -> PageFlags {
+    let mut page_flags = PageFlags::USER;
+    if flags & PF_W != 0 {
+        page_flags |= PageFlags::WRITABLE;
+    }
+    if flags & PF_X == 0 {
+        page_flags |= PageFlags::NO_EXECUTE;
+    }
+    page_flags
+}
+
+// Flags of a page shared by two segments.
+fn merge_flags(a: PageFlags, b: PageFlags) -> PageFlags {
+    let mut flags = PageFlags::USER;
+    if a.contains(PageFlags::WRITABLE) || b.contains(PageFlags::WRITABLE) {
+        flags |= PageFlags::WRITABLE;
+    }
+    if a.contains(PageFlags::NO_EXECUTE) && b.contains(PageFlags::NO_EXECUTE) {
+        flags |= PageFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn allocate_zeroed_frames(num_pages: u64) -> Result<u64, OsError> {
+    let mut frame = memory_manager().allocate(num_pages as usize)?;
+    unsafe {
+        write_bytes(frame.frame(), 0, (num_pages * PAGE_SIZE_4K) as usize);
+    }
+    Ok((frame.id() * BYTE_PER_FRAME) as u64)
+}
+
+// The address space is not active, so the frames are written through the
+// direct map. Pages are zeroed on allocation, which also clears .bss.
+fn load_segment(
+    page_table: &mut PageTableManager,
+    data: &[u8],
+    phdr: &Elf64ProgramHeader,
+) -> Result<(), OsError> {
+    let flags = segment_flags(phdr.flags);
+    let start = phdr.vaddr & !(PAGE_SIZE_4K - 1);
+    let end = phdr.vaddr + phdr.memsz;
+    let file_end = phdr.vaddr + phdr.filesz;
+
+    let mut page = start;
+    while page < end {
+        let phys = match page_table.translate(page) {
+            Some(translation) => {
+                page_table.protect(
+                    page, PAGE_SIZE_4K, merge_flags(translation.flags, flags))?;
+                translation.phys
+            },
+            None => {
+                let phys = allocate_zeroed_frames(1)?;
+                page_table.map(page, phys, PAGE_SIZE_4K, flags)?;
+                phys
+            },
+        };
+
+        let copy_start = page.max(phdr.vaddr);
+        let copy_end = (page + PAGE_SIZE_4K).min(file_end);
+        if copy_start < copy_end {
+            let src = (phdr.offset + (copy_start - phdr.vaddr)) as usize;
+            unsafe {
+                copy_nonoverlapping(
+                    data[src..].as_ptr(),
+                    phys_to_virt(phys + (copy_start - page)) as *mut u8,
+                    (copy_end - copy_start) as usize,
+                );
+            }
+        }
+        page += PAGE_SIZE_4K;
+    }
+    Ok(())
+}
+
+// Address of the program headers if they are part of a loaded segment.
+fn program_header_address(
+    data: &[u8],
+    header: &Elf64Header,
+) -> Result<Option<u64>, OsError> {
+    for i in 0..header.phnum {
+        let phdr = program_header(data, header, i)?;
+        if phdr.p_type == PT_LOAD && phdr.offset <= header.phoff &&
+            header.phoff < phdr.offset + phdr.filesz {
+            return Ok(Some(phdr.vaddr + (header.phoff - phdr.offset)));
+        }
+    }
+    Ok(None)
+}
+
+// Lays out argc, argv, envp and auxv as the System V ABI expects at the
+// entry point, with the strings above them. Returns the stack pointer.
+fn setup_stack(
+    page_table: &mut PageTableManager,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<u64, OsError> {
+    let size = USER_STACK_PAGES * PAGE_SIZE_4K;
+    let bottom = USER_STACK_TOP - size;
+
+    let strings_len: u64 = argv.iter().chain(envp.iter())
+        .map(|s| s.len() as u64 + 1)
+        .sum();
+    let num_words = 1 + (argv.len() + 1 + envp.len() + 1) as u64
+        + 2 * auxv.len() as u64;
+    let words_len = num_words * size_of::<u64>() as u64;
+    // Keep at least one page for the program itself.
+    if strings_len + words_len + 16 > size - PAGE_SIZE_4K {
+        return make_error!(OsErrorCode::ArgumentListTooLong);
+    }
+
+    let phys = allocate_zeroed_frames(USER_STACK_PAGES)?;
+    page_table.map(
+        bottom,
+        phys,
+        size,
+        PageFlags::USER | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+    )?;
+    let to_kernel = |addr: u64| phys_to_virt(phys + (addr - bottom));
+
+    let strings_start = USER_STACK_TOP - strings_len;
+    let stack_pointer = (strings_start - words_len) & !0xf;
+
+    let mut word_addr = stack_pointer;
+    let mut push_word = |value: u64| {
+        unsafe {
+            *(to_kernel(word_addr) as *mut u64) = value;
+        }
+        word_addr += size_of::<u64>() as u64;
+    };
+    let mut string_addr = strings_start;
+    let mut push_string = |s: &str| {
+        unsafe {
+            copy_nonoverlapping(
+                s.as_ptr(), to_kernel(string_addr) as *mut u8, s.len());
+        }
+        let addr = string_addr;
+        // The terminator is already there since the frames are zeroed.
+        string_addr += s.len() as u64 + 1;
+        addr
+    };
+
+    push_word(argv.len() as u64);
+    for arg in argv {
+        push_word(push_string(arg));
+    }
+    push_word(0);
+    for env in envp {
+        push_word(push_string(env));
+    }
+    push_word(0);
+    for &(key, value) in auxv {
+        push_word(key);
+        push_word(value);
+    }
+    Ok(stack_pointer)
+}
+
+fn load_into(
+    page_table: &mut PageTableManager,
+    data: &[u8],
+    header: &Elf64Header,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<u64, OsError> {
+    let mut loaded = false;
+    // The entry point must be in the user half and executable, or iretq
+    // would fault instead.
+    let mut entry_executable = false;
+    for i in 0..header.phnum {
+        let phdr = program_header(data, header, i)?;
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+        check_segment(data, &phdr)?;
+        load_segment(page_table, data, &phdr)?;
+        loaded = true;
+        if phdr.flags & PF_X != 0 && phdr.vaddr <= header.entry &&
+            header.entry - phdr.vaddr < phdr.memsz {
+            entry_executable = true;
+        }
+    }
+    if !loaded {
+        return make_error!(OsErrorCode::NoLoadableSegment);
+    }
+    if !entry_executable || header.entry >= USER_SPACE_END {
+        return make_error!(OsErrorCode::InvalidElfEntry);
+    }
+
+    let mut auxv = [(AT_NULL, 0); 6];
+    let mut num_auxv = 0;
+    if let Some(phdr_addr) = program_header_address(data, header)? {
+        auxv[num_auxv] = (AT_PHDR, phdr_addr);
+        num_auxv += 1;
+    }
+    for &entry in &[
+        (AT_PHENT, header.phentsize as u64),
+        (AT_PHNUM, header.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE_4K),
+        (AT_ENTRY, header.entry),
+        (AT_NULL, 0),
+    ] {
+        auxv[num_auxv] = entry;
+        num_auxv += 1;
+    }
+    setup_stack(page_table, argv, envp, &auxv[..num_auxv])
+}
+
+// Loads an executable into a new user address space.
+pub fn load_elf(
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<UserProgram, OsError> {
+    let header = read_header(data)?;
+    let mut page_table = PageTableManager::new_address_space()?;
+    match load_into(&mut page_table, data, &header, argv, envp) {
+        Ok(stack_pointer) => Ok(UserProgram {
+            page_table,
+            entry: header.entry,
+            stack_pointer,
+        }),
+        Err(err) => {
+            page_table.release()?;
+            Err(err)
+        },
+    }
+}
+
+// Runs a program until it exits and returns its exit code.
+pub fn run_program(
+    name: &str,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<i32, OsError> {
+    let file = match find_file(name) {
+        Some(file) => file,
+        None => return make_error!(OsErrorCode::FileNotFound),
+    };
+    let program = load_elf(file.data(), argv, envp)?;
+
+    let kernel_page_table = PageTableManager::current();
+    activate_address_space(&program.page_table);
+    reset_process_state();
+    let code = call_user_mode(program.entry, program.stack_pointer);
+    activate_address_space(&kernel_page_table);
+
+    program.page_table.release()?;
+    Ok(code)
+}
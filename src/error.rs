@@ -21,6 +21,31 @@ pub enum OsErrorCode {
     UnknownXhciSpeedId,
     NoWaiter,
     NoPciMsi,
+    InvalidAlignment,
+    PageNotMapped,
+    FileNotFound,
+    InvalidFileDescriptor,
+    InvalidSyscall,
+    InvalidUserAddress,
+    ElfTruncated,
+    InvalidElfMagic,
+    UnsupportedElfFormat,
+    InvalidElfSegment,
+    NoLoadableSegment,
+    InvalidElfEntry,
+    ArgumentListTooLong,
+    InvalidArchive,
+    AlreadyInitialized,
+    InvalidAcpiTable,
+    AcpiTableNotFound,
+    CpuStartupTimeout,
+    UnsupportedPixelFormat,
+    NoFrameBuffer,
+    NoSerialPort,
+    InvalidWindowId,
+    IoApicNotFound,
+    NoPs2Controller,
+    Ps2Timeout,
 }
 
 #[derive(Debug)]
@@ -0,0 +1,102 @@
+use crate::error::*;
+use crate::sync::IrqLock;
+
+const MAX_FILES: usize = 32;
+const MAX_OPEN_FILES: usize = 16;
+
+// 0, 1 and 2 are the console.
+const FIRST_FILE_DESCRIPTOR: usize = 3;
+
+#[derive(Clone, Copy)]
+pub struct File {
+    name: &'static str,
+    data: &'static [u8],
+}
+
+impl File {
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+static FILES: IrqLock<[Option<File>; MAX_FILES]> =
+    IrqLock::new([None; MAX_FILES]);
+
+pub fn register_file(
+    name: &'static str,
+    data: &'static [u8],
+) -> Result<(), OsError> {
+    let mut files = FILES.lock();
+    match files.iter_mut().find(|file| file.is_none()) {
+        Some(entry) => {
+            *entry = Some(File { name, data });
+            Ok(())
+        },
+        None => make_error!(OsErrorCode::Full),
+    }
+}
+
+pub fn find_file(name: &str) -> Option<File> {
+    let files = FILES.lock();
+    files.iter().flatten().find(|file| file.name == name).copied()
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    file: File,
+    offset: usize,
+}
+
+pub struct FileDescriptorTable {
+    entries: [Option<OpenFile>; MAX_OPEN_FILES],
+}
+
+impl FileDescriptorTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_OPEN_FILES],
+        }
+    }
+
+    pub fn open(&mut self, name: &str) -> Result<usize, OsError> {
+        let file = match find_file(name) {
+            Some(file) => file,
+            None => return make_error!(OsErrorCode::FileNotFound),
+        };
+        match self.entries.iter().position(|entry| entry.is_none()) {
+            Some(index) => {
+                self.entries[index] = Some(OpenFile { file, offset: 0 });
+                Ok(index + FIRST_FILE_DESCRIPTOR)
+            },
+            None => make_error!(OsErrorCode::Full),
+        }
+    }
+
+    pub fn read(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        let open_file = self.entry_mut(fd)?;
+        let data = &open_file.file.data()[open_file.offset..];
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        open_file.offset += len;
+        Ok(len)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), OsError> {
+        self.entry_mut(fd)?;
+        self.entries[fd - FIRST_FILE_DESCRIPTOR] = None;
+        Ok(())
+    }
+
+    fn entry_mut(&mut self, fd: usize) -> Result<&mut OpenFile, OsError> {
+        let index = fd.wrapping_sub(FIRST_FILE_DESCRIPTOR);
+        match self.entries.get_mut(index) {
+            Some(Some(open_file)) => Ok(open_file),
+            _ => make_error!(OsErrorCode::InvalidFileDescriptor),
+        }
+    }
+}
@@ -1,7 +1,5 @@
 use crate::graphics::{PixelWriter, PixelColor};
 
-use core::cell::RefCell;
-
 #[link(name="hankaku")]
 extern "C" {
     static _binary_hankaku_bin_start: u8;
@@ -35,7 +33,7 @@ pub fn get_font(c: char) -> Option<&'static [u8]> {
 }
 
 pub fn write_ascii(
-    writer: &RefCell<&mut dyn PixelWriter>,
+    writer: &mut dyn PixelWriter,
     x: usize,
     y: usize,
     c: char,
@@ -45,7 +43,7 @@ pub fn write_ascii(
         for (dy, line) in font.iter().enumerate() {
             for dx in 0..8 {
                 if (line << dx & 0x80u8) == 0x80u8 {
-                    writer.borrow_mut().write(x + dx, y + dy, color);
+                    writer.write(x + dx, y + dy, color);
                 }
             }
         }
@@ -53,7 +51,7 @@ pub fn write_ascii(
 }
 
 pub fn write_string<A: AsRef<str>>(
-    writer: &RefCell<&mut dyn PixelWriter>,
+    writer: &mut dyn PixelWriter,
     x: usize,
     y: usize,
     s: A,
@@ -0,0 +1,133 @@
+use crate::error::*;
+use crate::frame_buffer_config::{FrameBufferConfig, PixelFormat};
+use crate::graphics::{
+    BlitMode, Image, PixelColor, PixelWriter, Rectangle, Vector2D,
+    RGBResv8BitPerColorPixelWriter, BGRResv8BitPerColorPixelWriter,
+    BitMaskPixelWriter,
+};
+use crate::sync::IrqLock;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{convert::TryInto, slice};
+
+const BYTES_PER_PIXEL: usize = 4;
+
+// Drawing goes to a shadow buffer in RAM in the pixel format of the screen,
+// and blit copies finished areas to the frame buffer a scan line at a time.
+// Writing to VRAM pixel by pixel is slow and reading it back is worse.
+pub struct FrameBuffer {
+    config: FrameBufferConfig,
+    shadow: Vec<u8>,
+    writer: Box<dyn PixelWriter + Send>,
+}
+
+// The screen is drawn to from interrupt handlers as well.
+pub type SharedFrameBuffer = IrqLock<FrameBuffer>;
+
+impl FrameBuffer {
+    pub fn new(config: FrameBufferConfig) -> Result<Self, OsError> {
+        let pixel_format = match config.pixel_format.try_into() {
+            Ok(PixelFormat::kPixelBltOnly) => {
+                return make_error!(OsErrorCode::NoFrameBuffer);
+            },
+            Ok(pixel_format) => pixel_format,
+            Err(_) => return make_error!(OsErrorCode::UnsupportedPixelFormat),
+        };
+
+        let width = config.horisontal_resolution as usize;
+        let height = config.vertical_resolution as usize;
+        let mut shadow = vec![0; BYTES_PER_PIXEL * width * height];
+        let shadow_config = FrameBufferConfig {
+            frame_buffer: shadow.as_mut_ptr(),
+            pixels_per_scan_line: width as u32,
+            ..config
+        };
+        let writer: Box<dyn PixelWriter + Send> = match pixel_format {
+            PixelFormat::kPixelRGBResv8BitPerColor => {
+                Box::new(RGBResv8BitPerColorPixelWriter(shadow_config))
+            },
+            PixelFormat::kPixelBGRResv8BitPerColor => {
+                Box::new(BGRResv8BitPerColorPixelWriter(shadow_config))
+            },
+            PixelFormat::kPixelBitMask => {
+                match BitMaskPixelWriter::new(
+                    shadow_config,
+                    &config.pixel_bit_mask,
+                ) {
+                    Some(writer) => Box::new(writer),
+                    None => {
+                        return make_error!(
+                            OsErrorCode::UnsupportedPixelFormat
+                        );
+                    },
+                }
+            },
+            PixelFormat::kPixelBltOnly => unreachable!(),
+        };
+        Ok(Self { config, shadow, writer })
+    }
+
+    // Copies `rect` of the shadow buffer to the screen.
+    pub fn blit(&mut self, rect: &Rectangle) {
+        let screen =
+            Rectangle { pos: Vector2D { x: 0, y: 0 }, size: self.size() };
+        let rect = match rect.intersection(&screen) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let width = screen.size.x;
+        let stride = self.config.pixels_per_scan_line as usize;
+        let row_bytes = BYTES_PER_PIXEL * rect.size.x;
+        for y in rect.pos.y..(rect.pos.y + rect.size.y) {
+            let offset = BYTES_PER_PIXEL * (width * y + rect.pos.x);
+            let src = &self.shadow[offset..(offset + row_bytes)];
+            let dst = unsafe {
+                slice::from_raw_parts_mut(
+                    self.config
+                        .frame_buffer
+                        .add(BYTES_PER_PIXEL * (stride * y + rect.pos.x)),
+                    row_bytes,
+                )
+            };
+            dst.copy_from_slice(src);
+        }
+    }
+}
+
+impl PixelWriter for FrameBuffer {
+    fn write(&mut self, x: usize, y: usize, color: &PixelColor) {
+        self.writer.write(x, y, color);
+    }
+
+    fn read(&self, x: usize, y: usize) -> PixelColor {
+        self.writer.read(x, y)
+    }
+
+    fn size(&self) -> Vector2D {
+        self.writer.size()
+    }
+
+    fn fill_span(
+        &mut self,
+        x: usize,
+        y: usize,
+        len: usize,
+        color: &PixelColor,
+    ) {
+        self.writer.fill_span(x, y, len, color);
+    }
+
+    fn copy_rect(&mut self, src: &Rectangle, dst: &Vector2D) {
+        self.writer.copy_rect(src, dst);
+    }
+
+    fn blit_image(
+        &mut self,
+        x: usize,
+        y: usize,
+        image: &Image,
+        mode: BlitMode,
+    ) {
+        self.writer.blit_image(x, y, image, mode);
+    }
+}
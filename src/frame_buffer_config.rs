@@ -1,9 +1,36 @@
-#[derive(Clone, Copy)]
+use core::convert::TryFrom;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, dead_code)]
 pub enum PixelFormat {
     kPixelRGBResv8BitPerColor,
     kPixelBGRResv8BitPerColor,
+    kPixelBitMask,
+    // No frame buffer; the screen can only be drawn by UEFI's Blt().
+    kPixelBltOnly,
+}
+
+impl TryFrom<u8> for PixelFormat {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value <= PixelFormat::kPixelBltOnly as u8 {
+            unsafe { Ok(core::mem::transmute(value)) }
+        } else {
+            Err(())
+        }
+    }
+}
+
+// As EFI_PIXEL_BITMASK.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct PixelBitMask {
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
 }
 
 #[derive(Clone, Copy)]
@@ -13,5 +40,12 @@ pub struct FrameBufferConfig {
     pub pixels_per_scan_line: u32,
     pub horisontal_resolution: u32,
     pub vertical_resolution: u32,
-    pub pixel_format: PixelFormat,
+    // A PixelFormat. Kept as the raw value, since it comes from the loader.
+    pub pixel_format: u8,
+    // Only for kPixelBitMask.
+    pub pixel_bit_mask: PixelBitMask,
 }
+
+// The frame buffer is only written through a pixel writer, which is shared
+// behind a lock.
+unsafe impl Send for FrameBufferConfig {}
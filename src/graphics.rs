@@ -1,19 +1,27 @@
-use crate::frame_buffer_config::FrameBufferConfig;
+use crate::frame_buffer_config::{FrameBufferConfig, PixelBitMask};
 
-use core::cell::RefCell;
-
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct PixelColor {
     pub r: u8,
     pub g: u8,
     pub b: u8,
 }
 
+pub const BLACK: PixelColor = PixelColor { r: 0, g: 0, b: 0 };
+
+#[derive(Clone, Copy)]
 pub struct Vector2D {
     pub x: usize,
     pub y: usize,
 }
 
+#[derive(Clone, Copy)]
+pub struct Rectangle {
+    pub pos: Vector2D,
+    pub size: Vector2D,
+}
+
+#[derive(Clone, Copy)]
 pub struct Displacement {
     pub x: isize,
     pub y: isize,
@@ -34,78 +42,510 @@ impl Vector2D {
     }
 }
 
+impl Rectangle {
+    pub fn is_empty(&self) -> bool {
+        self.size.x == 0 || self.size.y == 0
+    }
+
+    pub fn intersection(&self, other: &Rectangle) -> Option<Rectangle> {
+        let x = self.pos.x.max(other.pos.x);
+        let y = self.pos.y.max(other.pos.y);
+        let end_x = (self.pos.x + self.size.x).min(other.pos.x + other.size.x);
+        let end_y = (self.pos.y + self.size.y).min(other.pos.y + other.size.y);
+        if x >= end_x || y >= end_y {
+            return None;
+        }
+        Some(Rectangle {
+            pos: Vector2D { x, y },
+            size: Vector2D { x: end_x - x, y: end_y - y },
+        })
+    }
+
+    // The smallest rectangle containing both.
+    pub fn union(&self, other: &Rectangle) -> Rectangle {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.pos.x.min(other.pos.x);
+        let y = self.pos.y.min(other.pos.y);
+        let end_x = (self.pos.x + self.size.x).max(other.pos.x + other.size.x);
+        let end_y = (self.pos.y + self.size.y).max(other.pos.y + other.size.y);
+        Rectangle {
+            pos: Vector2D { x, y },
+            size: Vector2D { x: end_x - x, y: end_y - y },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum BlitMode {
+    Opaque,
+    // Pixels of this color are not drawn.
+    ColorKey(PixelColor),
+    // Blended with what is below; 255 is opaque.
+    Alpha(u8),
+}
+
+// Pixels row by row, without padding.
+pub struct Image<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: &'a [PixelColor],
+}
+
 pub trait PixelWriter {
+    // Pixels outside of `size` are ignored, and read as black.
     fn write(&mut self, x: usize, y: usize, color: &PixelColor);
-    fn pixel_at(&mut self, x: usize, y: usize) -> &mut [u8];
+    fn read(&self, x: usize, y: usize) -> PixelColor;
+    fn size(&self) -> Vector2D;
+
+    // Fills `len` pixels to the right of (x, y).
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, color: &PixelColor) {
+        for i in 0..len {
+            self.write(x + i, y, color);
+        }
+    }
+
+    // `src` and the destination may overlap.
+    fn copy_rect(&mut self, src: &Rectangle, dst: &Vector2D) {
+        let forward = dst.y < src.pos.y || (dst.y == src.pos.y && dst.x <= src.pos.x);
+        for i in 0..src.size.y {
+            let dy = if forward { i } else { src.size.y - 1 - i };
+            for j in 0..src.size.x {
+                let dx = if forward { j } else { src.size.x - 1 - j };
+                let color = self.read(src.pos.x + dx, src.pos.y + dy);
+                self.write(dst.x + dx, dst.y + dy, &color);
+            }
+        }
+    }
+
+    fn blit_image(&mut self, x: usize, y: usize, image: &Image, mode: BlitMode) {
+        for dy in 0..image.height {
+            for dx in 0..image.width {
+                let color = &image.pixels[image.width * dy + dx];
+                if let Some(color) = blend(self, x + dx, y + dy, color, mode) {
+                    self.write(x + dx, y + dy, &color);
+                }
+            }
+        }
+    }
 }
 
-pub struct RGBResv8BitPerColorPixelWriter<'a>(pub &'a mut FrameBufferConfig);
+// The color to write at (x, y) for `color` drawn with `mode`, if any.
+fn blend<W: PixelWriter + ?Sized>(
+    writer: &W,
+    x: usize,
+    y: usize,
+    color: &PixelColor,
+    mode: BlitMode,
+) -> Option<PixelColor> {
+    match mode {
+        BlitMode::Opaque => Some(*color),
+        BlitMode::ColorKey(key) if *color == key => None,
+        BlitMode::ColorKey(_) => Some(*color),
+        BlitMode::Alpha(0) => None,
+        BlitMode::Alpha(255) => Some(*color),
+        BlitMode::Alpha(alpha) => {
+            Some(alpha_blend(color, &writer.read(x, y), alpha))
+        },
+    }
+}
 
-impl<'a> PixelWriter for RGBResv8BitPerColorPixelWriter<'a> {
-    fn write(&mut self, x: usize, y: usize, color: &PixelColor) {
-        let pixel = self.pixel_at(x, y);
-        pixel[0] = color.r;
-        pixel[1] = color.b;
-        pixel[2] = color.g;
+pub fn alpha_blend(color: &PixelColor, below: &PixelColor, alpha: u8) -> PixelColor {
+    let mix = |src: u8, dst: u8| {
+        ((src as u32 * alpha as u32 + dst as u32 * (255 - alpha as u32)) / 255)
+            as u8
+    };
+    PixelColor {
+        r: mix(color.r, below.r),
+        g: mix(color.g, below.g),
+        b: mix(color.b, below.b),
+    }
+}
+
+// Clips a copy within an area of `size` to what is inside on both sides.
+pub fn clip_copy(
+    size: &Vector2D,
+    src: &Rectangle,
+    dst: &Vector2D,
+) -> Option<(Rectangle, Vector2D)> {
+    let bounds = Rectangle { pos: Vector2D { x: 0, y: 0 }, size: *size };
+    let clipped_src = src.intersection(&bounds)?;
+    let dst_rect = Rectangle {
+        pos: Vector2D {
+            x: dst.x + (clipped_src.pos.x - src.pos.x),
+            y: dst.y + (clipped_src.pos.y - src.pos.y),
+        },
+        size: clipped_src.size,
+    };
+    let clipped_dst = dst_rect.intersection(&bounds)?;
+    let src = Rectangle {
+        pos: Vector2D {
+            x: clipped_src.pos.x + (clipped_dst.pos.x - dst_rect.pos.x),
+            y: clipped_src.pos.y + (clipped_dst.pos.y - dst_rect.pos.y),
+        },
+        size: clipped_dst.size,
+    };
+    Some((src, clipped_dst.pos))
+}
+
+// Helpers for frame buffers of 32 bits per pixel. The writers only differ in
+// how a color is encoded.
+const BYTES_PER_PIXEL: usize = 4;
+
+fn frame_size(config: &FrameBufferConfig) -> Vector2D {
+    Vector2D {
+        x: config.horisontal_resolution as usize,
+        y: config.vertical_resolution as usize,
+    }
+}
+
+// (x, y) must be inside the frame buffer.
+fn pixel_ptr(config: &FrameBufferConfig, x: usize, y: usize) -> *mut u8 {
+    unsafe {
+        config.frame_buffer.add(
+            BYTES_PER_PIXEL * ((config.pixels_per_scan_line as usize) * y + x),
+        )
+    }
+}
+
+// `len` pixels from (x, y), which must be inside the frame buffer.
+fn scan_line(config: &mut FrameBufferConfig, x: usize, y: usize, len: usize) -> &mut [u8] {
+    unsafe {
+        core::slice::from_raw_parts_mut(pixel_ptr(config, x, y), BYTES_PER_PIXEL * len)
+    }
+}
+
+fn pixel_at(
+    config: &mut FrameBufferConfig,
+    x: usize,
+    y: usize,
+) -> Option<&mut [u8]> {
+    let size = frame_size(config);
+    if x >= size.x || y >= size.y {
+        return None;
+    }
+    Some(scan_line(config, x, y, 1))
+}
+
+fn read_pixel_32(
+    config: &FrameBufferConfig,
+    x: usize,
+    y: usize,
+) -> Option<[u8; BYTES_PER_PIXEL]> {
+    let size = frame_size(config);
+    if x >= size.x || y >= size.y {
+        return None;
+    }
+    let mut pixel = [0; BYTES_PER_PIXEL];
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            pixel_ptr(config, x, y),
+            pixel.as_mut_ptr(),
+            BYTES_PER_PIXEL,
+        );
     }
+    Some(pixel)
+}
+
+fn fill_span_32(
+    config: &mut FrameBufferConfig,
+    x: usize,
+    y: usize,
+    len: usize,
+    pixel: [u8; BYTES_PER_PIXEL],
+) {
+    let size = frame_size(config);
+    if x >= size.x || y >= size.y {
+        return;
+    }
+    let len = len.min(size.x - x);
+    for chunk in scan_line(config, x, y, len).chunks_exact_mut(BYTES_PER_PIXEL) {
+        chunk.copy_from_slice(&pixel);
+    }
+}
 
-    fn pixel_at(&mut self, x: usize, y: usize) -> &mut [u8] {
+fn copy_rect_32(config: &mut FrameBufferConfig, src: &Rectangle, dst: &Vector2D) {
+    let (src, dst) = match clip_copy(&frame_size(config), src, dst) {
+        Some(clipped) => clipped,
+        None => return,
+    };
+    let row_bytes = BYTES_PER_PIXEL * src.size.x;
+    for i in 0..src.size.y {
+        let dy = if dst.y <= src.pos.y { i } else { src.size.y - 1 - i };
+        let from = pixel_ptr(config, src.pos.x, src.pos.y + dy);
+        let to = pixel_ptr(config, dst.x, dst.y + dy);
         unsafe {
-            const PIXEL_SIZE: usize = 4;
-            let p = self.0.frame_buffer.add(
-                PIXEL_SIZE * ((self.0.pixels_per_scan_line as usize) * y + x)
-            );
-            core::slice::from_raw_parts_mut(p, PIXEL_SIZE)
+            core::ptr::copy(from, to, row_bytes);
         }
     }
 }
 
-pub struct BGRResv8BitPerColorPixelWriter<'a>(pub &'a mut FrameBufferConfig);
+fn blit_image_32<E, D>(
+    config: &mut FrameBufferConfig,
+    x: usize,
+    y: usize,
+    image: &Image,
+    mode: BlitMode,
+    encode: E,
+    decode: D,
+) where
+    E: Fn(&PixelColor) -> [u8; BYTES_PER_PIXEL],
+    D: Fn(&[u8]) -> PixelColor,
+{
+    let size = frame_size(config);
+    if x >= size.x || y >= size.y {
+        return;
+    }
+    let width = image.width.min(size.x - x);
+    let height = image.height.min(size.y - y);
+    for dy in 0..height {
+        let row = &image.pixels[(image.width * dy)..(image.width * dy + width)];
+        let line = scan_line(config, x, y + dy, width);
+        for (color, pixel) in row.iter().zip(line.chunks_exact_mut(BYTES_PER_PIXEL)) {
+            let color = match mode {
+                BlitMode::Opaque => *color,
+                BlitMode::ColorKey(key) if *color == key => continue,
+                BlitMode::ColorKey(_) => *color,
+                BlitMode::Alpha(alpha) => {
+                    alpha_blend(color, &decode(pixel), alpha)
+                },
+            };
+            pixel.copy_from_slice(&encode(&color));
+        }
+    }
+}
 
-impl<'a> PixelWriter for BGRResv8BitPerColorPixelWriter<'a> {
+pub struct RGBResv8BitPerColorPixelWriter(pub FrameBufferConfig);
+
+impl RGBResv8BitPerColorPixelWriter {
+    fn encode(color: &PixelColor) -> [u8; BYTES_PER_PIXEL] {
+        [color.r, color.g, color.b, 0]
+    }
+
+    fn decode(pixel: &[u8]) -> PixelColor {
+        PixelColor { r: pixel[0], g: pixel[1], b: pixel[2] }
+    }
+}
+
+impl PixelWriter for RGBResv8BitPerColorPixelWriter {
     fn write(&mut self, x: usize, y: usize, color: &PixelColor) {
-        let pixel = self.pixel_at(x, y);
-        pixel[0] = color.b;
-        pixel[1] = color.g;
-        pixel[2] = color.r;
+        if let Some(pixel) = pixel_at(&mut self.0, x, y) {
+            pixel.copy_from_slice(&Self::encode(color));
+        }
     }
 
-    fn pixel_at(&mut self, x: usize, y: usize) -> &mut [u8] {
-        unsafe {
-            const PIXEL_SIZE: usize = 4;
-            let p = self.0.frame_buffer.add(
-                PIXEL_SIZE * ((self.0.pixels_per_scan_line as usize) * y + x)
-            );
-            core::slice::from_raw_parts_mut(p, PIXEL_SIZE)
+    fn read(&self, x: usize, y: usize) -> PixelColor {
+        read_pixel_32(&self.0, x, y).map_or(BLACK, |pixel| Self::decode(&pixel))
+    }
+
+    fn size(&self) -> Vector2D {
+        frame_size(&self.0)
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, color: &PixelColor) {
+        fill_span_32(&mut self.0, x, y, len, Self::encode(color));
+    }
+
+    fn copy_rect(&mut self, src: &Rectangle, dst: &Vector2D) {
+        copy_rect_32(&mut self.0, src, dst);
+    }
+
+    fn blit_image(&mut self, x: usize, y: usize, image: &Image, mode: BlitMode) {
+        blit_image_32(&mut self.0, x, y, image, mode, Self::encode, Self::decode);
+    }
+}
+
+pub struct BGRResv8BitPerColorPixelWriter(pub FrameBufferConfig);
+
+impl BGRResv8BitPerColorPixelWriter {
+    fn encode(color: &PixelColor) -> [u8; BYTES_PER_PIXEL] {
+        [color.b, color.g, color.r, 0]
+    }
+
+    fn decode(pixel: &[u8]) -> PixelColor {
+        PixelColor { r: pixel[2], g: pixel[1], b: pixel[0] }
+    }
+}
+
+impl PixelWriter for BGRResv8BitPerColorPixelWriter {
+    fn write(&mut self, x: usize, y: usize, color: &PixelColor) {
+        if let Some(pixel) = pixel_at(&mut self.0, x, y) {
+            pixel.copy_from_slice(&Self::encode(color));
         }
     }
+
+    fn read(&self, x: usize, y: usize) -> PixelColor {
+        read_pixel_32(&self.0, x, y).map_or(BLACK, |pixel| Self::decode(&pixel))
+    }
+
+    fn size(&self) -> Vector2D {
+        frame_size(&self.0)
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, color: &PixelColor) {
+        fill_span_32(&mut self.0, x, y, len, Self::encode(color));
+    }
+
+    fn copy_rect(&mut self, src: &Rectangle, dst: &Vector2D) {
+        copy_rect_32(&mut self.0, src, dst);
+    }
+
+    fn blit_image(&mut self, x: usize, y: usize, image: &Image, mode: BlitMode) {
+        blit_image_32(&mut self.0, x, y, image, mode, Self::encode, Self::decode);
+    }
+}
+
+// A color component of a BitMask pixel.
+#[derive(Clone, Copy)]
+struct Channel {
+    shift: u32,
+    bits: u32,
+}
+
+impl Channel {
+    // The bits must be contiguous.
+    fn new(mask: u32) -> Option<Self> {
+        if mask == 0 {
+            return None;
+        }
+        let shift = mask.trailing_zeros();
+        let value = mask >> shift;
+        if value & value.wrapping_add(1) != 0 {
+            return None;
+        }
+        Some(Self { shift, bits: value.count_ones() })
+    }
+
+    fn encode(&self, value: u8) -> u32 {
+        let value = if self.bits < 8 {
+            value as u32 >> (8 - self.bits)
+        } else {
+            (value as u32) << (self.bits - 8)
+        };
+        value << self.shift
+    }
+
+    fn decode(&self, pixel: u32) -> u8 {
+        let value = (pixel >> self.shift) & ((1u64 << self.bits) - 1) as u32;
+        if self.bits < 8 {
+            // Scale up so that the maximum stays the maximum.
+            (value * 255 / ((1 << self.bits) - 1)) as u8
+        } else {
+            (value >> (self.bits - 8)) as u8
+        }
+    }
+}
+
+// 32 bits per pixel in any layout given by the masks.
+#[derive(Clone, Copy)]
+struct BitMaskLayout {
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl BitMaskLayout {
+    fn encode(&self, color: &PixelColor) -> [u8; BYTES_PER_PIXEL] {
+        let pixel = self.red.encode(color.r)
+            | self.green.encode(color.g)
+            | self.blue.encode(color.b);
+        pixel.to_le_bytes()
+    }
+
+    fn decode(&self, pixel: &[u8]) -> PixelColor {
+        let pixel = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        PixelColor {
+            r: self.red.decode(pixel),
+            g: self.green.decode(pixel),
+            b: self.blue.decode(pixel),
+        }
+    }
+}
+
+pub struct BitMaskPixelWriter {
+    config: FrameBufferConfig,
+    layout: BitMaskLayout,
+}
+
+impl BitMaskPixelWriter {
+    pub fn new(config: FrameBufferConfig, masks: &PixelBitMask) -> Option<Self> {
+        let (red, green, blue) = (masks.red_mask, masks.green_mask, masks.blue_mask);
+        if red & green != 0 || green & blue != 0 || blue & red != 0 {
+            return None;
+        }
+        let layout = BitMaskLayout {
+            red: Channel::new(red)?,
+            green: Channel::new(green)?,
+            blue: Channel::new(blue)?,
+        };
+        Some(Self { config, layout })
+    }
+}
+
+impl PixelWriter for BitMaskPixelWriter {
+    fn write(&mut self, x: usize, y: usize, color: &PixelColor) {
+        let encoded = self.layout.encode(color);
+        if let Some(pixel) = pixel_at(&mut self.config, x, y) {
+            pixel.copy_from_slice(&encoded);
+        }
+    }
+
+    fn read(&self, x: usize, y: usize) -> PixelColor {
+        read_pixel_32(&self.config, x, y)
+            .map_or(BLACK, |pixel| self.layout.decode(&pixel))
+    }
+
+    fn size(&self) -> Vector2D {
+        frame_size(&self.config)
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, color: &PixelColor) {
+        let encoded = self.layout.encode(color);
+        fill_span_32(&mut self.config, x, y, len, encoded);
+    }
+
+    fn copy_rect(&mut self, src: &Rectangle, dst: &Vector2D) {
+        copy_rect_32(&mut self.config, src, dst);
+    }
+
+    fn blit_image(&mut self, x: usize, y: usize, image: &Image, mode: BlitMode) {
+        let layout = self.layout;
+        blit_image_32(
+            &mut self.config, x, y, image, mode,
+            |color| layout.encode(color),
+            |pixel| layout.decode(pixel),
+        );
+    }
 }
 
 pub fn fill_rectangle(
-    writer: &RefCell<&mut dyn PixelWriter>,
+    writer: &mut dyn PixelWriter,
     pos: &Vector2D,
     size: &Vector2D,
     color: &PixelColor,
 ) {
     for y in 0..size.y {
-        for x in 0..size.x {
-            writer.borrow_mut().write(pos.x + x, pos.y + y, color);
-        }
+        writer.fill_span(pos.x, pos.y + y, size.x, color);
     }
 }
 
 pub fn draw_rectangle(
-    writer: &RefCell<&mut dyn PixelWriter>,
+    writer: &mut dyn PixelWriter,
     pos: &Vector2D,
     size: &Vector2D,
     color: &PixelColor,
 ) {
     for x in 0..size.x {
-        writer.borrow_mut().write(pos.x + x, pos.y, color);
-        writer.borrow_mut().write(pos.x + x, pos.y + size.y - 1, color);
+        writer.write(pos.x + x, pos.y, color);
+        writer.write(pos.x + x, pos.y + size.y - 1, color);
     }
     for y in 0..size.y {
-        writer.borrow_mut().write(pos.x, pos.y + y, color);
-        writer.borrow_mut().write(pos.x + size.x - 1, pos.y + y, color);
+        writer.write(pos.x, pos.y + y, color);
+        writer.write(pos.x + size.x - 1, pos.y + y, color);
     }
 }
@@ -0,0 +1,74 @@
+use crate::error::*;
+use crate::file::register_file;
+
+use core::str;
+
+// Built by build.rs, empty unless an initrd was given.
+static INITRD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.cpio"));
+
+// cpio "newc" format.
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_REGULAR_FILE: u32 = 0o100000;
+
+fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
+
+// Header fields are 8 hexadecimal digits following the magic.
+fn header_field(header: &[u8], index: usize) -> Result<u32, OsError> {
+    let start = CPIO_MAGIC.len() + index * 8;
+    let digits = str::from_utf8(&header[start..start + 8]);
+    match digits.ok().and_then(|s| u32::from_str_radix(s, 16).ok()) {
+        Some(value) => Ok(value),
+        None => make_error!(OsErrorCode::InvalidArchive),
+    }
+}
+
+fn slice_at(
+    data: &'static [u8],
+    start: usize,
+    len: usize,
+) -> Result<&'static [u8], OsError> {
+    match start.checked_add(len) {
+        Some(end) if end <= data.len() => Ok(&data[start..end]),
+        _ => make_error!(OsErrorCode::InvalidArchive),
+    }
+}
+
+// Registers every regular file of the initrd, without the leading "./".
+pub fn load_initrd() -> Result<usize, OsError> {
+    let mut offset = 0;
+    let mut num_files = 0;
+    while offset < INITRD.len() {
+        let header = slice_at(INITRD, offset, CPIO_HEADER_SIZE)?;
+        if &header[..CPIO_MAGIC.len()] != CPIO_MAGIC {
+            return make_error!(OsErrorCode::InvalidArchive);
+        }
+        let mode = header_field(header, 1)?;
+        let file_size = header_field(header, 6)? as usize;
+        let name_size = header_field(header, 11)? as usize;
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = slice_at(INITRD, name_start, name_size)?;
+        let name = match str::from_utf8(name) {
+            Ok(name) => name.trim_end_matches('\0'),
+            Err(_) => return make_error!(OsErrorCode::InvalidArchive),
+        };
+        if name == CPIO_TRAILER {
+            break;
+        }
+
+        let data_start = align4(name_start + name_size);
+        let data = slice_at(INITRD, data_start, file_size)?;
+        if mode & MODE_TYPE_MASK == MODE_REGULAR_FILE {
+            let name = name.trim_start_matches("./").trim_start_matches('/');
+            register_file(name, data)?;
+            num_files += 1;
+        }
+        offset = align4(data_start + file_size);
+    }
+    Ok(num_files)
+}
@@ -1,10 +1,16 @@
 use crate::x86_descriptor::GateDescriptorType;
+use crate::paging::phys_to_virt;
+use crate::kernel_stack::is_guard_page;
+use crate::segment::{IST_DOUBLE_FAULT, IST_NMI, IST_MACHINE_CHECK};
+use crate::segment::exit_user_mode;
+use crate::logger::*;
 
-use core::ptr::write_volatile;
+use core::{arch::asm, mem::size_of_val, ptr::write_volatile};
 
 extern "C" {
     pub fn get_cs() -> u16;
     pub fn load_idt(limit: u16, offset: u64);
+    fn get_cr2() -> u64;
 }
 
 pub static mut IDT: [InterruptDescriptor; 256] = {
@@ -20,7 +26,31 @@ pub static mut IDT: [InterruptDescriptor; 256] = {
 };
 
 pub enum InterruptVector {
+    DivideError = 0x00,
+    Debug = 0x01,
+    Nmi = 0x02,
+    Breakpoint = 0x03,
+    Overflow = 0x04,
+    BoundRange = 0x05,
+    InvalidOpcode = 0x06,
+    DeviceNotAvailable = 0x07,
+    DoubleFault = 0x08,
+    InvalidTss = 0x0a,
+    SegmentNotPresent = 0x0b,
+    StackSegmentFault = 0x0c,
+    GeneralProtection = 0x0d,
+    PageFault = 0x0e,
+    X87FloatingPoint = 0x10,
+    AlignmentCheck = 0x11,
+    MachineCheck = 0x12,
+    SimdFloatingPoint = 0x13,
     Xhci = 0x40,
+    LapicTimer = 0x41,
+    CallFunction = 0x42,
+    Ps2Keyboard = 0x43,
+    Ps2Mouse = 0x44,
+    Reschedule = 0x45,
+    Spurious = 0xff,
 }
 
 type InterruptDescriptorAttribute = u16;
@@ -70,11 +100,287 @@ pub fn set_idt_entry(
     desc.segment_selector = segment_selector;
 }
 
+// The handler runs on the stack `interrupt_stack_table` (1 to 7) of the TSS.
+pub fn set_idt_entry_with_ist(
+    desc: &mut InterruptDescriptor,
+    attr: InterruptDescriptorAttribute,
+    offset: u64,
+    segment_selector: u16,
+    interrupt_stack_table: u16,
+) {
+    let attr = (attr & !0b0000_0000_0000_0111)
+        | (interrupt_stack_table & 0b0000_0000_0000_0111);
+    set_idt_entry(desc, attr, offset, segment_selector);
+}
+
 pub unsafe fn notify_end_of_interrupt() {
-    let end_of_interrupt = 0xfee000b0 as *mut u32;
+    let end_of_interrupt = phys_to_virt(0xfee000b0) as *mut u32;
     write_volatile(end_of_interrupt, 0);
 }
 
+const PF_PROTECTION_VIOLATION: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_INSTRUCTION_FETCH: u64 = 1 << 4;
+
+extern "x86-interrupt" fn interrupt_handler_page_fault(
+    stack_frame: ExceptionStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGsGuard::new(&stack_frame);
+    let address = unsafe {
+        get_cr2()
+    };
+    let access = if error_code & PF_INSTRUCTION_FETCH != 0 {
+        "execute"
+    } else if error_code & PF_WRITE != 0 {
+        "write"
+    } else {
+        "read"
+    };
+    let cause = if error_code & PF_PROTECTION_VIOLATION != 0 {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let mode = if error_code & PF_USER != 0 { "user" } else { "kernel" };
+    if is_user_mode(&stack_frame) {
+        log!(Error, "#PF: {} ({} {} at {:016x}) RIP {:016x}",
+             cause, mode, access, address, stack_frame.rip);
+        exit_user_mode(-1);
+    }
+    // The kernel may have faulted while holding the console locks.
+    log_unlocked!(Error, "#PF: {} ({} {} at {:016x}) RIP {:016x}",
+                  cause, mode, access, address, stack_frame.rip);
+    halt();
+}
+
+fn is_user_mode(stack_frame: &ExceptionStackFrame) -> bool {
+    stack_frame.cs & 3 == 3
+}
+
+// Interrupts and exceptions from ring 3 come with the user's GS base.
+// Handlers which reach the per-CPU data, including through a task switch,
+// hold one of these for their whole body, so that the kernel's is loaded
+// until they return.
+pub struct KernelGsGuard {
+    swapped: bool,
+}
+
+impl KernelGsGuard {
+    pub fn new(stack_frame: &ExceptionStackFrame) -> Self {
+        let swapped = is_user_mode(stack_frame);
+        if swapped {
+            unsafe {
+                asm!("swapgs", options(nostack, preserves_flags));
+            }
+        }
+        Self { swapped }
+    }
+}
+
+impl Drop for KernelGsGuard {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe {
+                asm!("swapgs", options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+// A fault in a user program ends only that program. In the kernel, it stops
+// the machine.
+fn handle_fault(
+    name: &str,
+    stack_frame: &ExceptionStackFrame,
+    error_code: u64,
+) -> ! {
+    if is_user_mode(stack_frame) {
+        log!(Error, "{}: user program terminated (error {:x}) RIP {:016x}",
+             name, error_code, stack_frame.rip);
+        exit_user_mode(-1);
+    }
+    // The kernel may have faulted while holding the console locks.
+    log_unlocked!(Error, "{}: (error {:x}) RIP {:016x}",
+                  name, error_code, stack_frame.rip);
+    halt();
+}
+
+macro_rules! fault_handler {
+    ($handler:ident, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: ExceptionStackFrame) {
+            let _gs = KernelGsGuard::new(&stack_frame);
+            handle_fault($name, &stack_frame, 0);
+        }
+    };
+    ($handler:ident, $name:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(
+            stack_frame: ExceptionStackFrame,
+            error_code: u64,
+        ) {
+            let _gs = KernelGsGuard::new(&stack_frame);
+            handle_fault($name, &stack_frame, error_code);
+        }
+    };
+}
+
+fault_handler!(interrupt_handler_divide_error, "#DE");
+fault_handler!(interrupt_handler_debug, "#DB");
+fault_handler!(interrupt_handler_breakpoint, "#BP");
+fault_handler!(interrupt_handler_overflow, "#OF");
+fault_handler!(interrupt_handler_bound_range, "#BR");
+fault_handler!(interrupt_handler_invalid_opcode, "#UD");
+fault_handler!(interrupt_handler_device_not_available, "#NM");
+fault_handler!(interrupt_handler_invalid_tss, "#TS", error_code);
+fault_handler!(interrupt_handler_segment_not_present, "#NP", error_code);
+fault_handler!(interrupt_handler_stack_segment_fault, "#SS", error_code);
+fault_handler!(interrupt_handler_general_protection, "#GP", error_code);
+fault_handler!(interrupt_handler_x87_floating_point, "#MF");
+fault_handler!(interrupt_handler_alignment_check, "#AC", error_code);
+fault_handler!(interrupt_handler_simd_floating_point, "#XM");
+
+// Runs on its own stack (IST), so this is reached even if the kernel stack
+// is exhausted. It may also have interrupted a holder of the console locks.
+extern "x86-interrupt" fn interrupt_handler_double_fault(
+    stack_frame: ExceptionStackFrame,
+    _error_code: u64,
+) -> ! {
+    let address = unsafe {
+        get_cr2()
+    };
+    if is_guard_page(address) {
+        log_unlocked!(Error, "#DF: kernel stack overflow ({:016x}) RIP {:016x}",
+             address, stack_frame.rip);
+    } else {
+        log_unlocked!(Error, "#DF: RIP {:016x}", stack_frame.rip);
+    }
+    halt();
+}
+
+extern "x86-interrupt" fn interrupt_handler_nmi(
+    stack_frame: ExceptionStackFrame,
+) {
+    log_unlocked!(Error, "NMI: RIP {:016x}", stack_frame.rip);
+}
+
+// Spurious interrupts must not be acknowledged with EOI.
+extern "x86-interrupt" fn interrupt_handler_spurious(
+    _stack_frame: ExceptionStackFrame,
+) {
+}
+
+extern "x86-interrupt" fn interrupt_handler_machine_check(
+    stack_frame: ExceptionStackFrame,
+) -> ! {
+    log_unlocked!(Error, "#MC: RIP {:016x}", stack_frame.rip);
+    halt();
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt");
+        }
+    }
+}
+
+pub fn setup_exception_handlers() {
+    unsafe {
+        let cs = get_cs();
+        let attr = make_id_attr(GateDescriptorType::InterruptGate, 0);
+        let faults = [
+            (
+                InterruptVector::DivideError,
+                interrupt_handler_divide_error as usize,
+            ),
+            (InterruptVector::Debug, interrupt_handler_debug as usize),
+            (
+                InterruptVector::Breakpoint,
+                interrupt_handler_breakpoint as usize,
+            ),
+            (InterruptVector::Overflow, interrupt_handler_overflow as usize),
+            (
+                InterruptVector::BoundRange,
+                interrupt_handler_bound_range as usize,
+            ),
+            (
+                InterruptVector::InvalidOpcode,
+                interrupt_handler_invalid_opcode as usize,
+            ),
+            (
+                InterruptVector::DeviceNotAvailable,
+                interrupt_handler_device_not_available as usize,
+            ),
+            (
+                InterruptVector::InvalidTss,
+                interrupt_handler_invalid_tss as usize,
+            ),
+            (
+                InterruptVector::SegmentNotPresent,
+                interrupt_handler_segment_not_present as usize,
+            ),
+            (
+                InterruptVector::StackSegmentFault,
+                interrupt_handler_stack_segment_fault as usize,
+            ),
+            (
+                InterruptVector::GeneralProtection,
+                interrupt_handler_general_protection as usize,
+            ),
+            (
+                InterruptVector::X87FloatingPoint,
+                interrupt_handler_x87_floating_point as usize,
+            ),
+            (
+                InterruptVector::AlignmentCheck,
+                interrupt_handler_alignment_check as usize,
+            ),
+            (
+                InterruptVector::SimdFloatingPoint,
+                interrupt_handler_simd_floating_point as usize,
+            ),
+        ];
+        for (vector, handler) in faults {
+            set_idt_entry(&mut IDT[vector as usize], attr, handler as u64, cs);
+        }
+        set_idt_entry(
+            &mut IDT[InterruptVector::PageFault as usize],
+            attr,
+            interrupt_handler_page_fault as usize as u64,
+            cs,
+        );
+        set_idt_entry_with_ist(
+            &mut IDT[InterruptVector::Nmi as usize],
+            attr,
+            interrupt_handler_nmi as usize as u64,
+            cs,
+            IST_NMI,
+        );
+        set_idt_entry_with_ist(
+            &mut IDT[InterruptVector::DoubleFault as usize],
+            attr,
+            interrupt_handler_double_fault as usize as u64,
+            cs,
+            IST_DOUBLE_FAULT,
+        );
+        set_idt_entry_with_ist(
+            &mut IDT[InterruptVector::MachineCheck as usize],
+            attr,
+            interrupt_handler_machine_check as usize as u64,
+            cs,
+            IST_MACHINE_CHECK,
+        );
+        set_idt_entry(
+            &mut IDT[InterruptVector::Spurious as usize],
+            attr,
+            interrupt_handler_spurious as usize as u64,
+            cs,
+        );
+        load_idt((size_of_val(&IDT) - 1) as u16, &IDT as *const _ as u64);
+    }
+}
+
 #[allow(dead_code)]
 #[repr(C)]
 pub struct ExceptionStackFrame {
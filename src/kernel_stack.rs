@@ -0,0 +1,93 @@
+use crate::error::*;
+use crate::memory_manager::{memory_manager, FrameId, BYTE_PER_FRAME};
+use crate::paging::{
+    PageTableManager, PageFlags, PAGE_SIZE_1G, PAGE_SIZE_4K,
+};
+use crate::sync::{IrqLock, OnceCell};
+
+// Kernel stacks are mapped here, each with an unmapped guard page below it.
+const KERNEL_STACK_REGION_BASE: u64 = 0xffff_c000_0000_0000;
+const KERNEL_STACK_REGION_SIZE: u64 = 512 * PAGE_SIZE_1G;
+
+static NEXT_STACK_ADDR: IrqLock<u64> = IrqLock::new(KERNEL_STACK_REGION_BASE);
+static MAIN_STACK_GUARD: OnceCell<u64> = OnceCell::new();
+
+#[derive(Clone, Copy)]
+pub struct KernelStack {
+    bottom: u64,
+    top: u64,
+}
+
+impl KernelStack {
+    #[allow(dead_code)]
+    pub fn bottom(&self) -> u64 {
+        self.bottom
+    }
+
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+}
+
+pub fn allocate_kernel_stack(num_pages: usize) -> Result<KernelStack, OsError> {
+    let size = num_pages as u64 * PAGE_SIZE_4K;
+    // The range is reserved even if mapping it fails below.
+    let (bottom, top) = {
+        let mut next = NEXT_STACK_ADDR.lock();
+        let bottom = *next + PAGE_SIZE_4K;
+        let top = bottom + size;
+        if top > KERNEL_STACK_REGION_BASE + KERNEL_STACK_REGION_SIZE {
+            return make_error!(OsErrorCode::NoEnoughMemory);
+        }
+        *next = top;
+        (bottom, top)
+    };
+
+    let frame = memory_manager().allocate(num_pages)?;
+    PageTableManager::current().map(
+        bottom,
+        (frame.id() * BYTE_PER_FRAME) as u64,
+        size,
+        PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+    )?;
+    Ok(KernelStack { bottom, top })
+}
+
+// The virtual range is not reused, so the guard page stays unmapped.
+pub fn free_kernel_stack(stack: KernelStack) -> Result<(), OsError> {
+    let mut page_table = PageTableManager::current();
+    let phys = match page_table.translate(stack.bottom) {
+        Some(translation) => translation.phys,
+        None => return make_error!(OsErrorCode::PageNotMapped),
+    };
+    let size = stack.top - stack.bottom;
+    page_table.unmap(stack.bottom, size)?;
+
+    memory_manager().free(
+        FrameId::new(phys as usize / BYTE_PER_FRAME),
+        size as usize / BYTE_PER_FRAME,
+    )
+}
+
+// The main stack is a static array, so its lowest page is unmapped instead.
+pub fn set_main_stack_guard(guard_page: u64) -> Result<(), OsError> {
+    PageTableManager::current().unmap(guard_page, PAGE_SIZE_4K)?;
+    MAIN_STACK_GUARD.set(guard_page)?;
+    Ok(())
+}
+
+pub fn is_guard_page(addr: u64) -> bool {
+    let page = addr & !(PAGE_SIZE_4K - 1);
+    if MAIN_STACK_GUARD.get() == Some(&page) {
+        return true;
+    }
+
+    // Everything unmapped in the region below the last stack is a guard.
+    // Called from #DF, which may have interrupted an allocation on this CPU.
+    let next = match NEXT_STACK_ADDR.try_lock() {
+        Some(next) => *next,
+        None => KERNEL_STACK_REGION_BASE + KERNEL_STACK_REGION_SIZE,
+    };
+    (KERNEL_STACK_REGION_BASE..next).contains(&addr) &&
+        PageTableManager::current().translate(addr).is_none()
+}
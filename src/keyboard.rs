@@ -0,0 +1,282 @@
+use crate::logger::*;
+use crate::message::Message;
+use crate::sync::{IrqLock, OnceCell};
+use crate::syscall::{push_event, AppEvent};
+use crate::task::{send_message, TaskId};
+use crate::timer::add_timer;
+use crate::window::WINDOW_MANAGER;
+
+// Bits of the modifier byte of HID keyboards. Usage IDs 0xe0 to 0xe7 are the
+// modifier keys in the same order.
+const MODIFIER_CONTROL: u8 = (1 << 0) | (1 << 4);
+const MODIFIER_SHIFT: u8 = (1 << 1) | (1 << 5);
+const KEYCODE_LEFT_CONTROL: u8 = 0xe0;
+const KEYCODE_RIGHT_GUI: u8 = 0xe7;
+
+pub const KEYCODE_PAGE_UP: u8 = 0x4b;
+pub const KEYCODE_PAGE_DOWN: u8 = 0x4e;
+
+// Set in the ids of the key repeat timers, so that the task can tell them
+// from its other timers.
+pub const KEY_REPEAT_TIMER: u64 = 1 << 63;
+
+const KEY_REPEAT_DELAY: u64 = 500;
+const KEY_REPEAT_INTERVAL: u64 = 40;
+
+const KEYMAP_SIZE: usize = 0x90;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    Us,
+    Jis,
+}
+
+// Characters by HID usage ID, 0 for keys without one.
+struct Keymap {
+    normal: &'static [u8; KEYMAP_SIZE],
+    shifted: &'static [u8; KEYMAP_SIZE],
+}
+
+const US_NORMAL: [u8; KEYMAP_SIZE] = [
+    0,     0,     0,     0,     b'a',  b'b',  b'c',  b'd',   // 0x00
+    b'e',  b'f',  b'g',  b'h',  b'i',  b'j',  b'k',  b'l',   // 0x08
+    b'm',  b'n',  b'o',  b'p',  b'q',  b'r',  b's',  b't',   // 0x10
+    b'u',  b'v',  b'w',  b'x',  b'y',  b'z',  b'1',  b'2',   // 0x18
+    b'3',  b'4',  b'5',  b'6',  b'7',  b'8',  b'9',  b'0',   // 0x20
+    b'\n', 0x1b,  0x08,  b'\t', b' ',  b'-',  b'=',  b'[',   // 0x28
+    b']',  b'\\', b'#',  b';',  b'\'', b'`',  b',',  b'.',   // 0x30
+    b'/',  0,     0,     0,     0,     0,     0,     0,      // 0x38
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x40
+    0,     0,     0,     0,     0x7f,  0,     0,     0,      // 0x48
+    0,     0,     0,     0,     b'/',  b'*',  b'-',  b'+',   // 0x50
+    b'\n', b'1',  b'2',  b'3',  b'4',  b'5',  b'6',  b'7',   // 0x58
+    b'8',  b'9',  b'0',  b'.',  b'\\', 0,     0,     0,      // 0x60
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x68
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x70
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x78
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x80
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x88
+];
+
+const US_SHIFTED: [u8; KEYMAP_SIZE] = [
+    0,     0,     0,     0,     b'A',  b'B',  b'C',  b'D',   // 0x00
+    b'E',  b'F',  b'G',  b'H',  b'I',  b'J',  b'K',  b'L',   // 0x08
+    b'M',  b'N',  b'O',  b'P',  b'Q',  b'R',  b'S',  b'T',   // 0x10
+    b'U',  b'V',  b'W',  b'X',  b'Y',  b'Z',  b'!',  b'@',   // 0x18
+    b'#',  b'$',  b'%',  b'^',  b'&',  b'*',  b'(',  b')',   // 0x20
+    b'\n', 0x1b,  0x08,  b'\t', b' ',  b'_',  b'+',  b'{',   // 0x28
+    b'}',  b'|',  b'~',  b':',  b'"',  b'~',  b'<',  b'>',   // 0x30
+    b'?',  0,     0,     0,     0,     0,     0,     0,      // 0x38
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x40
+    0,     0,     0,     0,     0x7f,  0,     0,     0,      // 0x48
+    0,     0,     0,     0,     b'/',  b'*',  b'-',  b'+',   // 0x50
+    b'\n', b'1',  b'2',  b'3',  b'4',  b'5',  b'6',  b'7',   // 0x58
+    b'8',  b'9',  b'0',  b'.',  b'|',  0,     0,     0,      // 0x60
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x68
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x70
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x78
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x80
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x88
+];
+
+const JIS_NORMAL: [u8; KEYMAP_SIZE] = [
+    0,     0,     0,     0,     b'a',  b'b',  b'c',  b'd',   // 0x00
+    b'e',  b'f',  b'g',  b'h',  b'i',  b'j',  b'k',  b'l',   // 0x08
+    b'm',  b'n',  b'o',  b'p',  b'q',  b'r',  b's',  b't',   // 0x10
+    b'u',  b'v',  b'w',  b'x',  b'y',  b'z',  b'1',  b'2',   // 0x18
+    b'3',  b'4',  b'5',  b'6',  b'7',  b'8',  b'9',  b'0',   // 0x20
+    b'\n', 0x1b,  0x08,  b'\t', b' ',  b'-',  b'^',  b'@',   // 0x28
+    b'[',  b']',  b']',  b';',  b':',  0,     b',',  b'.',   // 0x30
+    b'/',  0,     0,     0,     0,     0,     0,     0,      // 0x38
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x40
+    0,     0,     0,     0,     0x7f,  0,     0,     0,      // 0x48
+    0,     0,     0,     0,     b'/',  b'*',  b'-',  b'+',   // 0x50
+    b'\n', b'1',  b'2',  b'3',  b'4',  b'5',  b'6',  b'7',   // 0x58
+    b'8',  b'9',  b'0',  b'.',  0,     0,     0,     0,      // 0x60
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x68
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x70
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x78
+    0,     0,     0,     0,     0,     0,     0,     b'\\',  // 0x80
+    0,     b'\\', 0,     0,     0,     0,     0,     0,      // 0x88
+];
+
+const JIS_SHIFTED: [u8; KEYMAP_SIZE] = [
+    0,     0,     0,     0,     b'A',  b'B',  b'C',  b'D',   // 0x00
+    b'E',  b'F',  b'G',  b'H',  b'I',  b'J',  b'K',  b'L',   // 0x08
+    b'M',  b'N',  b'O',  b'P',  b'Q',  b'R',  b'S',  b'T',   // 0x10
+    b'U',  b'V',  b'W',  b'X',  b'Y',  b'Z',  b'!',  b'"',   // 0x18
+    b'#',  b'$',  b'%',  b'&',  b'\'', b'(',  b')',  0,      // 0x20
+    b'\n', 0x1b,  0x08,  b'\t', b' ',  b'=',  b'~',  b'`',   // 0x28
+    b'{',  b'}',  b'}',  b'+',  b'*',  0,     b'<',  b'>',   // 0x30
+    b'?',  0,     0,     0,     0,     0,     0,     0,      // 0x38
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x40
+    0,     0,     0,     0,     0x7f,  0,     0,     0,      // 0x48
+    0,     0,     0,     0,     b'/',  b'*',  b'-',  b'+',   // 0x50
+    b'\n', b'1',  b'2',  b'3',  b'4',  b'5',  b'6',  b'7',   // 0x58
+    b'8',  b'9',  b'0',  b'.',  0,     0,     0,     0,      // 0x60
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x68
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x70
+    0,     0,     0,     0,     0,     0,     0,     0,      // 0x78
+    0,     0,     0,     0,     0,     0,     0,     b'_',   // 0x80
+    0,     b'|',  0,     0,     0,     0,     0,     0,      // 0x88
+];
+
+
+static US_KEYMAP: Keymap = Keymap { normal: &US_NORMAL, shifted: &US_SHIFTED };
+static JIS_KEYMAP: Keymap =
+    Keymap { normal: &JIS_NORMAL, shifted: &JIS_SHIFTED };
+
+// Key events come from the drivers as HID usage IDs, are translated with the
+// keymap and go to the task of the active window as Message::KeyPush and
+// Message::KeyRelease, or to `task` without one, and to the application as
+// AppEvents. The last key pressed repeats while held, driven by timers of
+// `task`.
+struct Keyboard {
+    keymap: &'static Keymap,
+    modifier: u8,
+    task: TaskId,
+    repeat: Option<u8>,
+    // Timer id of the current repeat; timers of earlier ones are ignored as
+    // timers cannot be cancelled.
+    repeat_timer: u64,
+}
+
+static KEYBOARD: OnceCell<IrqLock<Keyboard>> = OnceCell::new();
+
+impl Keyboard {
+    fn translate(&self, keycode: u8) -> u8 {
+        let keymap = if self.modifier & MODIFIER_SHIFT != 0 {
+            self.keymap.shifted
+        } else {
+            self.keymap.normal
+        };
+        let ascii = keymap.get(keycode as usize).copied().unwrap_or(0);
+        let control = self.modifier & MODIFIER_CONTROL != 0;
+        if control && ascii.is_ascii_alphabetic() {
+            ascii & 0x1f
+        } else {
+            ascii
+        }
+    }
+
+    fn start_repeat(&mut self, delay: u64) {
+        let id = KEY_REPEAT_TIMER | self.repeat_timer;
+        // Without a free timer the key just does not repeat.
+        if add_timer(delay, id, self.task).is_err() {
+            self.repeat = None;
+        }
+    }
+}
+
+fn is_modifier(keycode: u8) -> bool {
+    (KEYCODE_LEFT_CONTROL..=KEYCODE_RIGHT_GUI).contains(&keycode)
+}
+
+// Key events go to the task and the application in the foreground.
+fn dispatch(task: TaskId, message: Message, event: AppEvent) {
+    let manager = WINDOW_MANAGER.get();
+    let task = manager.and_then(|m| m.lock().active_owner()).unwrap_or(task);
+    // Lost if the task has exited or its queue is full.
+    let _ = send_message(task, message);
+    push_event(event);
+}
+
+fn dispatch_push(task: TaskId, keycode: u8, ascii: u8, modifier: u8) {
+    dispatch(
+        task,
+        Message::KeyPush { keycode, ascii, modifier },
+        AppEvent::KeyPush {
+            keycode: keycode as u32,
+            ascii: ascii as u32,
+            modifier: modifier as u32,
+        },
+    );
+}
+
+fn keymap(layout: KeyboardLayout) -> &'static Keymap {
+    match layout {
+        KeyboardLayout::Us => &US_KEYMAP,
+        KeyboardLayout::Jis => &JIS_KEYMAP,
+    }
+}
+
+// `task` gets the key repeat timers and has to pass Message::TimerTimeout
+// with KEY_REPEAT_TIMER to on_key_repeat_timer. The keymap is chosen by
+// PONKAN_KEYMAP at build time.
+pub fn setup_keyboard(task: TaskId) {
+    let layout = match option_env!("PONKAN_KEYMAP") {
+        None | Some("us") => KeyboardLayout::Us,
+        Some("jis") => KeyboardLayout::Jis,
+        Some(name) => {
+            log!(Warn, "Unknown keymap {}, using us", name);
+            KeyboardLayout::Us
+        },
+    };
+    let keyboard = Keyboard {
+        keymap: keymap(layout),
+        modifier: 0,
+        task,
+        repeat: None,
+        repeat_timer: 0,
+    };
+    KEYBOARD.set(IrqLock::new(keyboard)).unwrap();
+}
+
+#[allow(dead_code)]
+pub fn set_keyboard_layout(layout: KeyboardLayout) {
+    if let Some(keyboard) = KEYBOARD.get() {
+        keyboard.lock().keymap = keymap(layout);
+    }
+}
+
+// Called by the keyboard drivers. `modifier` is the state after the event.
+pub fn on_key_event(keycode: u8, modifier: u8, press: bool) {
+    let mut keyboard = match KEYBOARD.get() {
+        Some(keyboard) => keyboard.lock(),
+        None => return,
+    };
+    keyboard.modifier = modifier;
+    let task = keyboard.task;
+    let ascii = keyboard.translate(keycode);
+    if press && !is_modifier(keycode) {
+        keyboard.repeat = Some(keycode);
+        keyboard.repeat_timer += 1;
+        keyboard.start_repeat(KEY_REPEAT_DELAY);
+    } else if !press && keyboard.repeat == Some(keycode) {
+        keyboard.repeat = None;
+    }
+    drop(keyboard);
+
+    if press {
+        dispatch_push(task, keycode, ascii, modifier);
+    } else {
+        dispatch(
+            task,
+            Message::KeyRelease { keycode, modifier },
+            AppEvent::KeyRelease {
+                keycode: keycode as u32,
+                modifier: modifier as u32,
+            },
+        );
+    }
+}
+
+pub fn on_key_repeat_timer(id: u64) {
+    let mut keyboard = match KEYBOARD.get() {
+        Some(keyboard) => keyboard.lock(),
+        None => return,
+    };
+    let keycode = match keyboard.repeat {
+        Some(keycode) if id == KEY_REPEAT_TIMER | keyboard.repeat_timer => {
+            keycode
+        },
+        _ => return,
+    };
+    keyboard.start_repeat(KEY_REPEAT_INTERVAL);
+    let ascii = keyboard.translate(keycode);
+    let modifier = keyboard.modifier;
+    let task = keyboard.task;
+    drop(keyboard);
+
+    dispatch_push(task, keycode, ascii, modifier);
+}
@@ -0,0 +1,388 @@
+use crate::frame_buffer::SharedFrameBuffer;
+use crate::graphics::{
+    BLACK, BlitMode, Displacement, Image, PixelColor, PixelWriter, Rectangle,
+    Vector2D, alpha_blend, clip_copy,
+};
+use crate::sync::IrqLock;
+
+use alloc::vec::Vec;
+
+pub type LayerId = usize;
+
+pub struct Layer {
+    width: usize,
+    height: usize,
+    buffer: Vec<PixelColor>,
+    position: Vector2D,
+    // Pixels of this color show the layers below.
+    transparent_color: Option<PixelColor>,
+    // Stays above the other layers, like the mouse cursor.
+    topmost: bool,
+    // Area written since the last compose, in layer coordinates.
+    dirty: Option<Rectangle>,
+}
+
+impl Layer {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            buffer: vec![BLACK; width * height],
+            position: Vector2D { x: 0, y: 0 },
+            transparent_color: None,
+            topmost: false,
+            dirty: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn set_transparent_color(&mut self, color: Option<PixelColor>) {
+        self.transparent_color = color;
+        self.mark_all_dirty();
+    }
+
+    pub fn fill(&mut self, color: &PixelColor) {
+        self.buffer.fill(*color);
+        self.mark_all_dirty();
+    }
+
+    fn mark_dirty(&mut self, rect: &Rectangle) {
+        self.dirty = Some(match &self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => *rect,
+        });
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty = Some(self.local_area());
+    }
+
+    fn local_area(&self) -> Rectangle {
+        Rectangle {
+            pos: Vector2D { x: 0, y: 0 },
+            size: Vector2D { x: self.width, y: self.height },
+        }
+    }
+
+    // In screen coordinates.
+    fn area(&self) -> Rectangle {
+        Rectangle {
+            pos: self.position,
+            size: Vector2D { x: self.width, y: self.height },
+        }
+    }
+}
+
+impl PixelWriter for Layer {
+    fn write(&mut self, x: usize, y: usize, color: &PixelColor) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        self.buffer[self.width * y + x] = *color;
+        self.mark_dirty(&Rectangle {
+            pos: Vector2D { x, y },
+            size: Vector2D { x: 1, y: 1 },
+        });
+    }
+
+    fn read(&self, x: usize, y: usize) -> PixelColor {
+        if x >= self.width || y >= self.height {
+            return BLACK;
+        }
+        self.buffer[self.width * y + x]
+    }
+
+    fn size(&self) -> Vector2D {
+        Vector2D { x: self.width, y: self.height }
+    }
+
+    fn fill_span(
+        &mut self,
+        x: usize,
+        y: usize,
+        len: usize,
+        color: &PixelColor,
+    ) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let len = len.min(self.width - x);
+        let start = self.width * y + x;
+        self.buffer[start..(start + len)].fill(*color);
+        self.mark_dirty(&Rectangle {
+            pos: Vector2D { x, y },
+            size: Vector2D { x: len, y: 1 },
+        });
+    }
+
+    fn copy_rect(&mut self, src: &Rectangle, dst: &Vector2D) {
+        let size = Vector2D { x: self.width, y: self.height };
+        let (src, dst) = match clip_copy(&size, src, dst) {
+            Some(clipped) => clipped,
+            None => return,
+        };
+        for i in 0..src.size.y {
+            let dy = if dst.y <= src.pos.y { i } else { src.size.y - 1 - i };
+            let from = self.width * (src.pos.y + dy) + src.pos.x;
+            let to = self.width * (dst.y + dy) + dst.x;
+            self.buffer.copy_within(from..(from + src.size.x), to);
+        }
+        self.mark_dirty(&Rectangle { pos: dst, size: src.size });
+    }
+
+    fn blit_image(
+        &mut self,
+        x: usize,
+        y: usize,
+        image: &Image,
+        mode: BlitMode,
+    ) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let width = image.width.min(self.width - x);
+        let height = image.height.min(self.height - y);
+        for dy in 0..height {
+            let row =
+                &image.pixels[(image.width * dy)..(image.width * dy + width)];
+            let start = self.width * (y + dy) + x;
+            let line = &mut self.buffer[start..(start + width)];
+            match mode {
+                BlitMode::Opaque => line.copy_from_slice(row),
+                BlitMode::ColorKey(key) => {
+                    for (pixel, color) in line.iter_mut().zip(row) {
+                        if *color != key {
+                            *pixel = *color;
+                        }
+                    }
+                },
+                BlitMode::Alpha(alpha) => {
+                    for (pixel, color) in line.iter_mut().zip(row) {
+                        *pixel = alpha_blend(color, pixel, alpha);
+                    }
+                },
+            }
+        }
+        self.mark_dirty(&Rectangle {
+            pos: Vector2D { x, y },
+            size: Vector2D { x: width, y: height },
+        });
+    }
+}
+
+// Layers are drawn to their own buffers and composited onto the screen in
+// z-order by compose, which only redraws the damaged areas.
+pub struct LayerManager {
+    screen: &'static SharedFrameBuffer,
+    screen_size: Vector2D,
+    layers: Vec<Option<Layer>>,
+    // Visible layers, bottom to top.
+    z_order: Vec<LayerId>,
+    // Areas to redraw, in screen coordinates.
+    damage: Vec<Rectangle>,
+}
+
+pub type SharedLayerManager = IrqLock<LayerManager>;
+
+impl LayerManager {
+    pub fn new(screen: &'static SharedFrameBuffer) -> Self {
+        let screen_size = screen.lock().size();
+        Self {
+            screen,
+            screen_size,
+            layers: Vec::new(),
+            z_order: Vec::new(),
+            damage: Vec::new(),
+        }
+    }
+
+    pub fn screen_size(&self) -> Vector2D {
+        self.screen_size
+    }
+
+    // The new layer is hidden until shown.
+    pub fn new_layer(&mut self, width: usize, height: usize) -> LayerId {
+        let layer = Some(Layer::new(width, height));
+        match self.layers.iter().position(|layer| layer.is_none()) {
+            Some(id) => {
+                self.layers[id] = layer;
+                id
+            },
+            None => {
+                self.layers.push(layer);
+                self.layers.len() - 1
+            },
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn remove_layer(&mut self, id: LayerId) {
+        self.hide(id);
+        if let Some(layer) = self.layers.get_mut(id) {
+            *layer = None;
+        }
+    }
+
+    pub fn layer_mut(&mut self, id: LayerId) -> Option<&mut Layer> {
+        self.layers.get_mut(id).and_then(|layer| layer.as_mut())
+    }
+
+    pub fn set_topmost(&mut self, id: LayerId, topmost: bool) {
+        if let Some(layer) = self.layer_mut(id) {
+            layer.topmost = topmost;
+        }
+        if self.is_visible(id) {
+            self.raise(id);
+        }
+    }
+
+    pub fn is_visible(&self, id: LayerId) -> bool {
+        self.z_order.contains(&id)
+    }
+
+    // Puts the layer on top of the others, but below topmost layers unless
+    // it is one of them.
+    pub fn raise(&mut self, id: LayerId) {
+        let topmost = match self.layer_mut(id) {
+            Some(layer) => layer.topmost,
+            None => return,
+        };
+        self.z_order.retain(|&other| other != id);
+        let index = if topmost {
+            self.z_order.len()
+        } else {
+            self.z_order
+                .iter()
+                .position(|&other| self.layers[other].as_ref().unwrap().topmost)
+                .unwrap_or(self.z_order.len())
+        };
+        self.z_order.insert(index, id);
+        self.damage_layer(id);
+    }
+
+    pub fn show(&mut self, id: LayerId) {
+        if !self.is_visible(id) {
+            self.raise(id);
+        }
+    }
+
+    pub fn hide(&mut self, id: LayerId) {
+        if self.is_visible(id) {
+            self.damage_layer(id);
+            self.z_order.retain(|&other| other != id);
+        }
+    }
+
+    pub fn move_to(&mut self, id: LayerId, position: Vector2D) {
+        self.damage_layer(id);
+        if let Some(layer) = self.layer_mut(id) {
+            layer.position = position;
+        }
+        self.damage_layer(id);
+    }
+
+    #[allow(dead_code)]
+    pub fn move_relative(&mut self, id: LayerId, displacement: &Displacement) {
+        if let Some(mut position) = self.position(id) {
+            position.displace(displacement);
+            self.move_to(id, position);
+        }
+    }
+
+    pub fn position(&self, id: LayerId) -> Option<Vector2D> {
+        self.layers.get(id).and_then(|l| l.as_ref()).map(|l| l.position)
+    }
+
+    // The topmost visible layer at `pos` for which `filter` holds.
+    pub fn layer_at<F: Fn(LayerId) -> bool>(
+        &self,
+        pos: &Vector2D,
+        filter: F,
+    ) -> Option<LayerId> {
+        let point = Rectangle { pos: *pos, size: Vector2D { x: 1, y: 1 } };
+        self.z_order.iter().rev().copied().find(|&id| {
+            let layer = self.layers[id].as_ref().unwrap();
+            filter(id) && layer.area().intersection(&point).is_some()
+        })
+    }
+
+    fn damage_layer(&mut self, id: LayerId) {
+        let area = match self.layers.get(id).and_then(|l| l.as_ref()) {
+            Some(layer) if self.z_order.contains(&id) => layer.area(),
+            _ => return,
+        };
+        self.add_damage(area);
+    }
+
+    // Overlapping areas are merged so that no pixel is drawn twice.
+    fn add_damage(&mut self, rect: Rectangle) {
+        let screen =
+            Rectangle { pos: Vector2D { x: 0, y: 0 }, size: self.screen_size };
+        let mut rect = match rect.intersection(&screen) {
+            Some(rect) => rect,
+            None => return,
+        };
+        while let Some(index) = self
+            .damage
+            .iter()
+            .position(|other| other.intersection(&rect).is_some())
+        {
+            rect = rect.union(&self.damage.swap_remove(index));
+        }
+        self.damage.push(rect);
+    }
+
+    // Redraws what changed since the last call.
+    pub fn compose(&mut self) {
+        for i in 0..self.z_order.len() {
+            let layer = self.layers[self.z_order[i]].as_mut().unwrap();
+            if let Some(dirty) = layer.dirty.take() {
+                let rect = Rectangle {
+                    pos: Vector2D {
+                        x: layer.position.x + dirty.pos.x,
+                        y: layer.position.y + dirty.pos.y,
+                    },
+                    size: dirty.size,
+                };
+                self.add_damage(rect);
+            }
+        }
+        // Hidden layers are drawn in full when shown.
+        for layer in self.layers.iter_mut().flatten() {
+            layer.dirty = None;
+        }
+
+        let mut screen = self.screen.lock();
+        for rect in self.damage.drain(..) {
+            for &id in &self.z_order {
+                let layer = self.layers[id].as_ref().unwrap();
+                let area = match layer.area().intersection(&rect) {
+                    Some(area) => area,
+                    None => continue,
+                };
+                let mode = match layer.transparent_color {
+                    Some(color) => BlitMode::ColorKey(color),
+                    None => BlitMode::Opaque,
+                };
+                for y in area.pos.y..(area.pos.y + area.size.y) {
+                    let start = layer.width * (y - layer.position.y)
+                        + (area.pos.x - layer.position.x);
+                    let row = Image {
+                        width: area.size.x,
+                        height: 1,
+                        pixels: &layer.buffer[start..(start + area.size.x)],
+                    };
+                    screen.blit_image(area.pos.x, y, &row, mode);
+                }
+            }
+            screen.blit(&rect);
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::serial::{put_string_unlocked, SERIAL};
+use crate::CONSOLE;
+
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub enum LogLevel {
@@ -21,18 +24,47 @@ macro_rules! log {
         if unsafe { &LOG_LEVEL } >= &($level) {
             let mut buf = WriteBuffer::<1024>::new();
             writeln!(buf, $($arg)*).unwrap();
-            let console = unsafe {
-                BUF_CONSOLE.assume_init_mut()
-            };
-            console.put_string(buf);
+            print(buf);
         }
     });
 }
 
+// For NMI, #DF and #MC, which may interrupt a holder of the console locks.
+#[macro_export]
+macro_rules! log_unlocked {
+    ($level:expr, $($arg:tt)*) => ({
+        if unsafe { &LOG_LEVEL } >= &($level) {
+            let mut buf = WriteBuffer::<1024>::new();
+            writeln!(buf, $($arg)*).unwrap();
+            print_unlocked(buf);
+        }
+    });
+}
+
+// Without a frame buffer, output goes to the serial port.
+pub fn print<A: AsRef<str>>(s: A) {
+    if let Some(console) = CONSOLE.get() {
+        console.lock().put_string(s);
+    } else if let Some(serial) = SERIAL.get() {
+        serial.lock().put_string(s);
+    }
+}
+
+// Never waits for a lock. If the console is busy, the output goes to the
+// serial port instead.
+pub fn print_unlocked<A: AsRef<str>>(s: A) {
+    if let Some(mut console) = CONSOLE.get().and_then(|c| c.try_lock()) {
+        if console.try_put_string(&s) {
+            return;
+        }
+    }
+    put_string_unlocked(s);
+}
+
 pub use LogLevel::*;
 
 pub use crate::log;
-pub use crate::BUF_CONSOLE;
+pub use crate::log_unlocked;
 pub use crate::WriteBuffer;
 
 pub use core::fmt::Write;
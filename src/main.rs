@@ -23,45 +23,95 @@ mod x86_descriptor;
 mod paging;
 mod memory_manager;
 mod alloc_support;
+mod kernel_stack;
+mod timer;
+mod file;
+mod syscall;
+mod elf;
+mod initrd;
+mod task;
+mod message;
+mod sync;
+mod acpi;
+mod apic;
+mod smp;
+mod layer;
+mod frame_buffer;
+mod serial;
+mod canvas;
+mod window;
+mod keyboard;
+mod ps2;
 
 use graphics::{
-    PixelColor, PixelWriter, Vector2D, Displacement,
-    RGBResv8BitPerColorPixelWriter, BGRResv8BitPerColorPixelWriter,
-    fill_rectangle, draw_rectangle,
-};
-use frame_buffer_config::{
-    PixelFormat, FrameBufferConfig
+    PixelColor, Vector2D, Displacement, fill_rectangle, draw_rectangle, BLACK,
 };
+use font::write_string;
+use error::OsError;
+use frame_buffer_config::FrameBufferConfig;
+use frame_buffer::{FrameBuffer, SharedFrameBuffer};
+use serial::setup_serial;
 pub use write_buffer::WriteBuffer;
-use console::Console;
+use console::{Console, CURSOR_BLINK_INTERVAL};
 use pci::{
     BusScanner, Device, MsiDeliveryMode, MsiTriggerMode,
     read_bar, read_class_code, read_vendor_id, read_conf_reg_from_device,
     write_conf_reg_from_device, read_vendor_id_from_device,
-    configure_msi_fixed_destination,
+    configure_msi_logical_destination,
 };
 use logger::*;
 use usb::{
-    XhciController, configure_port, process_event,
-    set_default_mouse_observer,
+    XhciController, KeyEvent, MouseEvent, configure_port, process_event,
+    set_default_keyboard_observer, set_default_mouse_observer,
 };
 use mouse::MouseCursor;
+use layer::{LayerManager, SharedLayerManager};
+use window::{
+    close_window, create_window, draw_window, setup_window_manager, WindowId,
+    WINDOW_MANAGER,
+};
+use keyboard::{
+    on_key_event, on_key_repeat_timer, setup_keyboard,
+    KEYCODE_PAGE_DOWN, KEYCODE_PAGE_UP, KEY_REPEAT_TIMER,
+};
+use ps2::{on_ps2_data, setup_ps2};
 use interrupt::{
-    InterruptVector, ExceptionStackFrame,
+    InterruptVector, ExceptionStackFrame, KernelGsGuard,
     notify_end_of_interrupt, get_cs, load_idt, make_id_attr, set_idt_entry,
-    IDT,
+    setup_exception_handlers, IDT,
+};
+use memory_map::{
+    EfiMemoryType, MemoryMap, is_available, is_reclaimable, is_loader_memory,
+    is_acpi_reclaimable, UEFI_PAGE_SIZE,
 };
-use queue::ArrayQueue;
-use memory_map::{MemoryMap, is_available, UEFI_PAGE_SIZE};
 use x86_descriptor::GateDescriptorType;
-use segment::setup_segments;
-use paging::setup_identity_page_table;
-use memory_manager::{BitmapMemoryManager, FrameId, BYTE_PER_FRAME};
-
-use core::{
-    arch::asm, cell::RefCell, convert::TryInto, fmt::Write, mem::size_of_val,
-    mem::MaybeUninit, ptr::read_volatile
+use segment::{setup_segments, setup_tss, KERNEL_CS, KERNEL_SS};
+use paging::{
+    setup_page_table, setup_memory_protection, setup_kernel_address_space,
+    map_mmio, phys_to_virt, virt_to_phys,
+};
+use memory_manager::{
+    BitmapMemoryManager, FrameId, BYTE_PER_FRAME, MEMORY_MANAGER,
+};
+use kernel_stack::set_main_stack_guard;
+use timer::{add_timer, setup_timer};
+use syscall::{setup_syscall, push_event, AppEvent};
+use elf::run_program;
+use initrd::load_initrd;
+use file::find_file;
+use task::{
+    TaskId, init_task_manager, current_task, preempt_if_needed,
+    send_message, receive_message, take_dropped_messages,
+};
+use message::{Message, WindowEvent};
+use smp::{
+    init_bsp, logical_destination_all, reserve_trampoline,
+    start_application_processors,
 };
+use sync::{IrqLock, OnceCell, SpinLock};
+
+use core::{convert::TryInto, fmt::Write, mem::size_of_val};
+use core::sync::atomic::{AtomicU8, Ordering};
 
 #[allow(unused_imports)]
 #[macro_use]
@@ -70,44 +120,38 @@ extern crate alloc;
 extern "C" {
     fn set_ds_all(value: u16);
     fn set_cs_ss(cs: u16, ss: u16);
-}
 
-#[derive(Clone, Copy, Debug)]
-enum MassageType {
-    InterruptXhci,
+    // Defined in kernel.ld.
+    static __kernel_start: u8;
+    static __kernel_end: u8;
 }
 
-#[derive(Clone, Copy)]
-struct Message {
-    m_type: Option<MassageType>,
-}
+const LOCAL_APIC_BASE: u64 = 0xfee00000;
+const XHC_MMIO_SIZE: u64 = 64 * 1024;
+const CONSOLE_SCROLLBACK: usize = 1000;
+
+// Timers of the main task other than the key repeats.
+const CURSOR_BLINK_TIMER: u64 = 0;
 
-static mut MAIN_QUEUE_DATA: [Message; 32] =[Message { m_type: None }; 32];
-
-static mut BUF_RGB: MaybeUninit<RGBResv8BitPerColorPixelWriter> =
-    MaybeUninit::uninit();
-static mut BUF_BGR: MaybeUninit<BGRResv8BitPerColorPixelWriter> =
-    MaybeUninit::uninit();
-static mut BUF_WRITER: MaybeUninit<RefCell<&mut dyn PixelWriter>> =
-    MaybeUninit::uninit();
-pub static mut BUF_CONSOLE: MaybeUninit<Console> = MaybeUninit::uninit();
-static mut BUF_MOUSE: MaybeUninit<MouseCursor> = MaybeUninit::uninit();
-static mut BUF_XHC: MaybeUninit<XhciController> = MaybeUninit::uninit();
-static mut BUF_QUEUE: MaybeUninit<ArrayQueue<Message, 32>> =
-    MaybeUninit::uninit();
-static mut BUF_FBCONFIG: MaybeUninit<FrameBufferConfig> = MaybeUninit::uninit();
-static mut BUF_MEMMAP: MaybeUninit<MemoryMap> = MaybeUninit::uninit();
-pub static mut BUF_MEMMNG: MaybeUninit<BitmapMemoryManager> =
-    MaybeUninit::uninit();
+// Receives Message::InterruptXhci.
+static XHCI_TASK: OnceCell<TaskId> = OnceCell::new();
+
+static SCREEN: OnceCell<SharedFrameBuffer> = OnceCell::new();
+static LAYERS: OnceCell<SharedLayerManager> = OnceCell::new();
+pub static CONSOLE: OnceCell<IrqLock<Console<'static>>> = OnceCell::new();
+static MOUSE: OnceCell<IrqLock<MouseCursor<'static>>> = OnceCell::new();
+// Buttons held at the last mouse event.
+static MOUSE_BUTTONS: AtomicU8 = AtomicU8::new(0);
+// Only the main task touches the controller.
+static XHC: OnceCell<SpinLock<XhciController>> = OnceCell::new();
+// Only written while copying the memory map at boot.
+static mut MEMMAP_DATA: [u8; 4096 * 4] = [0; 4096 * 4];
 
 macro_rules! _kprint {
     ($w:ident, $($arg:tt)*) => ({
         let mut buf = WriteBuffer::<1024>::new();
         $w!(buf, $($arg)*).unwrap();
-        let console = unsafe {
-            BUF_CONSOLE.assume_init_mut()
-        };
-        console.put_string(buf);
+        print(buf);
     });
 }
 
@@ -122,15 +166,51 @@ macro_rules! kprintln {
     ($($arg:tt)*) => (_kprint!(writeln, $($arg)*));
 }
 
-extern "C" fn mouse_observer(displacement_x: i8, displacement_y: i8) {
-    let displacement = Displacement {
-        x: displacement_x as isize,
-        y: displacement_y as isize,
+extern "C" fn mouse_observer(event: MouseEvent) {
+    let mut displacement = Displacement {
+        x: event.dx as isize,
+        y: event.dy as isize,
     };
-    let mouse_cursor = unsafe {
-        BUF_MOUSE.assume_init_mut()
-    };
-    mouse_cursor.move_relative(displacement);
+    if let Some(mouse_cursor) = MOUSE.get() {
+        let mut mouse_cursor = mouse_cursor.lock();
+        let position = if event.absolute {
+            // Reported to applications as a move from where it was.
+            let previous = mouse_cursor.position();
+            let position = mouse_cursor.move_absolute(event.x, event.y);
+            displacement = Displacement {
+                x: position.x as isize - previous.x as isize,
+                y: position.y as isize - previous.y as isize,
+            };
+            position
+        } else {
+            mouse_cursor.move_relative(displacement)
+        };
+        drop(mouse_cursor);
+        if let Some(window_manager) = WINDOW_MANAGER.get() {
+            window_manager.lock().on_mouse(position, event.buttons);
+        }
+    } else if event.absolute {
+        // No screen to scale the position to.
+        displacement = Displacement { x: 0, y: 0 };
+    }
+
+    if displacement.x != 0 || displacement.y != 0 {
+        push_event(AppEvent::MouseMove {
+            dx: displacement.x as i32,
+            dy: displacement.y as i32,
+        });
+    }
+    if MOUSE_BUTTONS.swap(event.buttons, Ordering::Relaxed) != event.buttons {
+        push_event(AppEvent::MouseButton { buttons: event.buttons as u32 });
+    }
+    if event.wheel != 0 {
+        // HID reports positive for away from the user as well.
+        push_event(AppEvent::MouseWheel { delta: event.wheel as i32 });
+    }
+}
+
+extern "C" fn keyboard_observer(event: KeyEvent) {
+    on_key_event(event.keycode, event.modifier, event.press);
 }
 
 impl BusScanner {
@@ -157,108 +237,150 @@ impl BusScanner {
 }
 
 extern "x86-interrupt" fn interrupt_handler_xhci(
-    _stack_frame: ExceptionStackFrame,
+    stack_frame: ExceptionStackFrame,
 ) {
-    let main_queue = unsafe {
-        BUF_QUEUE.assume_init_mut()
-    };
-    main_queue
-        .push(Message { m_type: Some(MassageType::InterruptXhci) })
-        .unwrap();
+    let _gs = KernelGsGuard::new(&stack_frame);
+    // A burst of interrupts may overflow the queue. Such messages are
+    // counted and the next one drains the event ring anyway.
+    if let Some(&task) = XHCI_TASK.get() {
+        let _ = send_message(task, Message::InterruptXhci);
+    }
     unsafe {
         notify_end_of_interrupt();
     }
+    preempt_if_needed();
 }
 
-#[repr(align(16))]
-pub struct KernelMainStack([u8; 1024 * 1024]);
+// Frees the memory of the types `memory_types` accepts.
+fn reclaim_boot_memory(
+    memory_manager: &mut BitmapMemoryManager,
+    memory_map: &MemoryMap,
+    memory_types: fn(EfiMemoryType) -> bool,
+) {
+    // The kernel image itself is loaded as `LoaderData`.
+    let (kernel_start, kernel_end) = unsafe {
+        (
+            virt_to_phys(&__kernel_start as *const u8 as u64) as usize,
+            virt_to_phys(&__kernel_end as *const u8 as u64) as usize,
+        )
+    };
+    let kernel_start_frame = kernel_start / BYTE_PER_FRAME;
+    let kernel_end_frame = (kernel_end + BYTE_PER_FRAME - 1) / BYTE_PER_FRAME;
 
-#[no_mangle]
-pub static mut KERNEL_MAIN_STACK: KernelMainStack =
-    KernelMainStack([0; 1024 * 1024]);
+    for desc in memory_map.iter() {
+        match desc.memory_type.try_into() {
+            Ok(memory_type) if memory_types(memory_type) => {},
+            _ => continue,
+        }
 
-#[no_mangle]
-pub extern "C" fn kernel_main_new_stack(
-    frame_buffer_config_ref: &'static mut FrameBufferConfig,
-    memory_map_ref: &'static MemoryMap,
-) -> ! {
-    let frame_buffer_config = unsafe {
-        BUF_FBCONFIG.write(*frame_buffer_config_ref)
-    };
-    let memory_map = unsafe {
-        BUF_MEMMAP.write(*memory_map_ref)
-    };
+        let start_frame = desc.physical_start / BYTE_PER_FRAME;
+        let end_frame = start_frame +
+            desc.number_of_pages as usize * UEFI_PAGE_SIZE / BYTE_PER_FRAME;
 
-    let frame_width = frame_buffer_config.horisontal_resolution as usize;
-    let frame_height = frame_buffer_config.vertical_resolution as usize;
+        if start_frame < kernel_start_frame {
+            let end = end_frame.min(kernel_start_frame);
+            memory_manager
+                .free(FrameId::new(start_frame), end - start_frame)
+                .unwrap();
+        }
+        if end_frame > kernel_end_frame {
+            let start = start_frame.max(kernel_end_frame);
+            memory_manager
+                .free(FrameId::new(start), end_frame - start)
+                .unwrap();
+        }
+    }
+}
 
-    let pixel_writer: &mut dyn PixelWriter =
-        match &frame_buffer_config.pixel_format
-    {
-        PixelFormat::kPixelRGBResv8BitPerColor => {
-            unsafe {
-                BUF_RGB.write(RGBResv8BitPerColorPixelWriter(frame_buffer_config))
-            }
-        },
-        PixelFormat::kPixelBGRResv8BitPerColor => {
-            unsafe {
-                BUF_BGR.write(BGRResv8BitPerColorPixelWriter(frame_buffer_config))
-            }
-        },
-    };
-    let pixel_writer = unsafe {
-        BUF_WRITER.write(RefCell::new(pixel_writer))
-    };
+// The guard page is unmapped once paging is set up, so that an overflow
+// faults instead of corrupting the statics next to the stack.
+#[repr(C, align(4096))]
+pub struct KernelMainStack {
+    guard_page: [u8; 4096],
+    stack: [u8; 1024 * 1024],
+}
+
+#[no_mangle]
+pub static mut KERNEL_MAIN_STACK: KernelMainStack = KernelMainStack {
+    guard_page: [0; 4096],
+    stack: [0; 1024 * 1024],
+};
 
-    let desktop_bg_color = PixelColor { r: 45, g: 115, b: 200 };
-    let desktop_fg_color = PixelColor { r: 255, g: 255, b: 255 };
+fn draw_desktop(manager: &mut LayerManager, bg_color: &PixelColor) {
+    let Vector2D { x: frame_width, y: frame_height } = manager.screen_size();
+    let desktop_layer = manager.new_layer(frame_width, frame_height);
+    let desktop = manager.layer_mut(desktop_layer).unwrap();
     fill_rectangle(
-        pixel_writer,
+        desktop,
         &Vector2D { x: 0, y: 0 },
         &Vector2D { x: frame_width, y: frame_height - 40 },
-        &desktop_bg_color,
+        bg_color,
     );
     fill_rectangle(
-        pixel_writer,
+        desktop,
         &Vector2D { x: 0, y: frame_height - 40 },
         &Vector2D { x: frame_width, y: 40 },
         &PixelColor { r: 10, g: 30, b: 50 },
     );
     fill_rectangle(
-        pixel_writer,
+        desktop,
         &Vector2D { x: 0, y: frame_height - 40 },
         &Vector2D { x: frame_width / 4, y: 40 },
         &PixelColor { r: 120, g: 120, b: 120 },
     );
     draw_rectangle(
-        pixel_writer,
+        desktop,
         &Vector2D { x: 10, y: frame_height - 30 },
         &Vector2D { x: 20, y: 20 },
         &PixelColor { r: 50, g: 160, b: 50 },
     );
+    manager.show(desktop_layer);
+    manager.compose();
+}
 
-    unsafe {
-        BUF_CONSOLE.write(
-            Console::new(desktop_fg_color, desktop_bg_color, pixel_writer)
-        );
-    }
-    kprintln!("Welcome to PonkanOS!");
-    set_log_level(Warn);
+// Belongs to the main task.
+fn open_hello_window() -> Result<WindowId, OsError> {
+    let id = create_window("Hello", 176, 40, Vector2D { x: 300, y: 100 })?;
+    draw_window(id, |client| {
+        write_string(client, 8, 12, "Welcome to PonkanOS!", &BLACK);
+    })?;
+    Ok(id)
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main_new_stack(
+    frame_buffer_config_ref: &'static mut FrameBufferConfig,
+    memory_map_ref: &'static MemoryMap,
+    acpi_rsdp: u64,
+) -> ! {
+    // Output goes here until there is a console, if there is a serial port.
+    let _ = setup_serial();
+
+    let mut frame_buffer_config = *frame_buffer_config_ref;
+    let memory_map = unsafe {
+        memory_map_ref.copy_to(&mut MEMMAP_DATA).unwrap()
+    };
+    let frame_buffer_phys = frame_buffer_config.frame_buffer as u64;
+    frame_buffer_config.frame_buffer =
+        phys_to_virt(frame_buffer_phys) as *mut u8;
+
+    let frame_buffer_size = frame_buffer_config.pixels_per_scan_line as u64
+        * frame_buffer_config.vertical_resolution as u64
+        * 4;
 
     setup_segments();
 
     unsafe {
-        let kernel_cs = 1 << 3;
-        let kernel_ss = 2 << 3;
         set_ds_all(0);
-        set_cs_ss(kernel_cs, kernel_ss);
+        set_cs_ss(KERNEL_CS, KERNEL_SS);
     }
 
-    setup_identity_page_table();
+    setup_page_table();
 
-    let memory_manager = unsafe {
-        BUF_MEMMNG.write(BitmapMemoryManager::new())
-    };
+    let mut memory_manager = MEMORY_MANAGER
+        .set(IrqLock::new(BitmapMemoryManager::new()))
+        .unwrap()
+        .lock();
     let mut available_end = 0;
     for desc in memory_map.iter() {
         if available_end < desc.physical_start {
@@ -270,9 +392,13 @@ pub extern "C" fn kernel_main_new_stack(
 
         let physical_end =
             desc.physical_start + desc.number_of_pages as usize * UEFI_PAGE_SIZE;
-        if is_available(desc.memory_type.try_into().unwrap()) {
+        let memory_type = desc.memory_type.try_into().unwrap();
+        if is_available(memory_type) {
             available_end = physical_end;
         } else {
+            if is_reclaimable(memory_type) {
+                available_end = physical_end;
+            }
             memory_manager.mark_allocated(
                 FrameId::new(desc.physical_start / BYTE_PER_FRAME),
                 desc.number_of_pages as usize * UEFI_PAGE_SIZE / BYTE_PER_FRAME,
@@ -285,18 +411,107 @@ pub extern "C" fn kernel_main_new_stack(
         FrameId::new(available_end / BYTE_PER_FRAME),
     );
 
-    let initial_position = Vector2D {
-        x: 300,
-        y: 200,
+    // Nothing refers to the loader's memory any more. The ACPI tables are
+    // reclaimed once they have been parsed.
+    reclaim_boot_memory(&mut memory_manager, &memory_map, is_loader_memory);
+    let trampoline = reserve_trampoline(&mut memory_manager);
+    drop(memory_manager);
+
+    // The shadow buffer and layers need the heap, so nothing is drawn before
+    // this.
+    let layers = match FrameBuffer::new(frame_buffer_config) {
+        Ok(frame_buffer) => {
+            let screen = SCREEN.set(IrqLock::new(frame_buffer)).unwrap();
+            Some(LAYERS.set(IrqLock::new(LayerManager::new(screen))).unwrap())
+        },
+        Err(err) => {
+            log!(Error, "No frame buffer, using the serial port ({:?})", err.code);
+            None
+        },
     };
-    unsafe {
-        BUF_MOUSE.write(
-            MouseCursor::new(pixel_writer, desktop_bg_color, initial_position)
-        );
+    if let Some(layers) = layers {
+        let desktop_bg_color = PixelColor { r: 45, g: 115, b: 200 };
+        let desktop_fg_color = PixelColor { r: 255, g: 255, b: 255 };
+        draw_desktop(&mut layers.lock(), &desktop_bg_color);
+        CONSOLE.set(IrqLock::new(
+            Console::new(
+                desktop_fg_color,
+                desktop_bg_color,
+                layers,
+                CONSOLE_SCROLLBACK,
+            )
+        )).unwrap();
+        setup_window_manager(layers);
+    }
+    kprintln!("Welcome to PonkanOS!");
+    set_log_level(Warn);
+    if let Err(err) = trampoline {
+        log!(Error, "reserve_trampoline: Error ({:?})", err.code);
     }
 
+    // The IST gates need the stacks of the TSS.
+    setup_tss().unwrap();
+    setup_exception_handlers();
+    setup_memory_protection().unwrap();
+    setup_kernel_address_space().unwrap();
     unsafe {
-        BUF_QUEUE.write(ArrayQueue::new(&mut MAIN_QUEUE_DATA));
+        set_main_stack_guard(
+            &KERNEL_MAIN_STACK.guard_page as *const _ as u64).unwrap();
+    }
+    setup_syscall();
+    if layers.is_some() {
+        map_mmio(frame_buffer_phys, frame_buffer_size).unwrap();
+    }
+    map_mmio(LOCAL_APIC_BASE, 4096).unwrap();
+    setup_timer();
+    // Tasks keep the kernel stack for system calls in the per-CPU data.
+    init_bsp().unwrap();
+    // The rest of this function, including the event loop, is the main task.
+    init_task_manager().unwrap();
+
+    match acpi::initialize(acpi_rsdp).and_then(|_| start_application_processors()) {
+        Ok(num_cpus) => log!(Info, "{} CPUs online", num_cpus),
+        Err(err) => log!(Error, "SMP: Error ({:?})", err.code),
+    }
+    // What the kernel needs from the ACPI tables has been copied out.
+    reclaim_boot_memory(
+        &mut MEMORY_MANAGER.get().unwrap().lock(),
+        &memory_map,
+        is_acpi_reclaimable,
+    );
+
+    match load_initrd() {
+        Ok(num_files) => log!(Info, "initrd: {} files", num_files),
+        Err(err) => log!(Error, "initrd: Error ({:?})", err.code),
+    }
+    if find_file("init").is_some() {
+        match run_program("init", &["init"], &[]) {
+            Ok(code) => log!(Info, "init exited with {}", code),
+            Err(err) => log!(Error, "init: Error ({:?})", err.code),
+        }
+    }
+
+    if let Some(layers) = layers {
+        let initial_position = Vector2D {
+            x: 300,
+            y: 200,
+        };
+        MOUSE.set(IrqLock::new(
+            MouseCursor::new(layers, initial_position)
+        )).unwrap();
+    }
+
+    // Keys from any keyboard come to the main task, as do the key repeat
+    // timers.
+    setup_keyboard(current_task());
+    if CONSOLE.get().is_some() {
+        let _ = add_timer(
+            CURSOR_BLINK_INTERVAL, CURSOR_BLINK_TIMER, current_task());
+    }
+    if acpi::has_8042() {
+        if let Err(err) = setup_ps2(current_task(), mouse_observer) {
+            log!(Error, "PS/2: Error ({:?})", err.code);
+        }
     }
 
     let mut scanner = BusScanner::new();
@@ -334,6 +549,8 @@ pub extern "C" fn kernel_main_new_stack(
         log!(Info, "xHC has been found: {}.{}.{}",
             device.bus, device.device, device.function);
 
+        XHCI_TASK.set(current_task()).unwrap();
+
         unsafe {
             let cs = get_cs();
             let attr = make_id_attr(GateDescriptorType::InterruptGate, 0);
@@ -345,13 +562,11 @@ pub extern "C" fn kernel_main_new_stack(
             );
             load_idt((size_of_val(&IDT) - 1) as u16, &IDT as *const _ as u64);
 
-            let bsp_local_apic_id =
-                (read_volatile(0xfee00020 as *const u32) >> 24) & 0x000000ff;
-            configure_msi_fixed_destination(
+            configure_msi_logical_destination(
                 device,
-                bsp_local_apic_id,
+                logical_destination_all(),
                 MsiTriggerMode::Level,
-                MsiDeliveryMode::Fixed,
+                MsiDeliveryMode::LowestPriority,
                 InterruptVector::Xhci as u32,
                 0,
             ).unwrap();
@@ -362,9 +577,10 @@ pub extern "C" fn kernel_main_new_stack(
                 let xhc_mmio_base = bar & !0xf;
                 log!(Debug, "xHC mmio_base = {:08x}", xhc_mmio_base);
 
-                let xhc = unsafe {
-                    BUF_XHC.write(XhciController::new(xhc_mmio_base))
-                };
+                let xhc = XhciController::new(
+                    map_mmio(xhc_mmio_base, XHC_MMIO_SIZE).unwrap());
+                let xhc_lock = XHC.set(SpinLock::new(xhc)).unwrap();
+                let mut xhc = xhc_lock.lock();
 
                 if read_vendor_id_from_device(device) == 0x8086 {
                     scanner.switch_ehci_to_xhci(device);
@@ -377,6 +593,7 @@ pub extern "C" fn kernel_main_new_stack(
                 xhc.run();
 
                 set_default_mouse_observer(mouse_observer);
+                set_default_keyboard_observer(keyboard_observer);
 
                 for i in 0..xhc.max_ports() {
                     let mut port = xhc.port_at(i);
@@ -384,50 +601,12 @@ pub extern "C" fn kernel_main_new_stack(
                          i, port.is_connected());
 
                     if port.is_connected() &&
-                        configure_port(xhc, &mut port) != 0 {
+                        configure_port(&mut xhc, &mut port) != 0 {
                         log!(Error, "Failed to configure port");
                         continue;
                     }
                 }
-
-                let main_queue = unsafe {
-                    BUF_QUEUE.assume_init_mut()
-                };
-                loop {
-                    unsafe {
-                        asm!("cli");
-                    }
-                    if main_queue.count() == 0 {
-                        unsafe {
-                            asm!("sti", "hlt");
-                        }
-                        continue;
-                    }
-
-                    let message = main_queue.pop().unwrap();
-                    unsafe {
-                        asm!("sti");
-                    }
-
-                    #[allow(unreachable_patterns)]
-                    match message.m_type.unwrap() {
-                        MassageType::InterruptXhci => {
-                            let xhc = unsafe {
-                                BUF_XHC.assume_init_mut()
-                            };
-                            while xhc.primary_event_ring().has_front() {
-                                if process_event(xhc) != 0 {
-                                    log!(Error, "Error while process_event");
-                                }
-                            }
-                        },
-                        _ => log!(
-                            Error,
-                            "Unknown message type: {:?}",
-                            message.m_type,
-                        ),
-                    }
-                }
+                drop(xhc);
             },
             Err(err) => {
                 log!(Debug, "read_bar: Error ({:?})", err.code);
@@ -435,9 +614,62 @@ pub extern "C" fn kernel_main_new_stack(
         }
     }
 
+    if layers.is_some() {
+        if let Err(err) = open_hello_window() {
+            log!(Error, "open_hello_window: Error ({:?})", err.code);
+        }
+    }
+
     loop {
-        unsafe {
-            asm!("hlt");
+        match receive_message() {
+            Message::InterruptXhci => {
+                let mut xhc = XHC.get().unwrap().lock();
+                while xhc.primary_event_ring().has_front() {
+                    if process_event(&mut xhc) != 0 {
+                        log!(Error, "Error while process_event");
+                    }
+                }
+            },
+            Message::Ps2Data { data, mouse, tick } => {
+                on_ps2_data(data, mouse, tick);
+            },
+            Message::TimerTimeout { id } if id & KEY_REPEAT_TIMER != 0 => {
+                on_key_repeat_timer(id);
+            },
+            Message::TimerTimeout { id: CURSOR_BLINK_TIMER } => {
+                CONSOLE.get().unwrap().lock().blink_cursor();
+                // Without a free timer the cursor stops blinking.
+                let _ = add_timer(
+                    CURSOR_BLINK_INTERVAL, CURSOR_BLINK_TIMER, current_task());
+            },
+            Message::Window { id, event: WindowEvent::Close } => {
+                if let Err(err) = close_window(id as WindowId) {
+                    log!(Error, "close_window: Error ({:?})", err.code);
+                }
+            },
+            Message::Window { .. } => {},
+            // Keys come here when no window is active.
+            Message::KeyPush { keycode: KEYCODE_PAGE_UP, .. } => {
+                if let Some(console) = CONSOLE.get() {
+                    console.lock().page_up();
+                }
+            },
+            Message::KeyPush { keycode: KEYCODE_PAGE_DOWN, .. } => {
+                if let Some(console) = CONSOLE.get() {
+                    console.lock().page_down();
+                }
+            },
+            Message::KeyPush { .. } | Message::KeyRelease { .. } => {},
+            message => log!(
+                Error,
+                "Unknown message type: {:?}",
+                message,
+            ),
+        }
+
+        let dropped = take_dropped_messages();
+        if dropped > 0 {
+            log!(Warn, "{} messages were dropped", dropped);
         }
     }
 }
@@ -1,4 +1,6 @@
 use crate::error::*;
+use crate::paging::phys_to_virt;
+use crate::sync::{IrqLock, IrqLockGuard, OnceCell};
 
 use core::mem::size_of;
 
@@ -17,9 +19,8 @@ impl FrameId {
         self.id
     }
 
-    #[allow(dead_code)]
     pub fn frame(&mut self) -> *mut u8 {
-        (self.id * BYTE_PER_FRAME) as *mut u8
+        phys_to_virt((self.id * BYTE_PER_FRAME) as u64) as *mut u8
     }
 }
 
@@ -32,6 +33,15 @@ type MapLineType = u32;
 
 const BITS_PER_MAP_LINE: usize = 8 * size_of::<MapLineType>();
 
+pub static MEMORY_MANAGER: OnceCell<IrqLock<BitmapMemoryManager>> =
+    OnceCell::new();
+
+// Callers must not hold the guard while calling into code which allocates
+// frames, such as PageTableManager::map.
+pub fn memory_manager() -> IrqLockGuard<'static, BitmapMemoryManager> {
+    MEMORY_MANAGER.get().unwrap().lock()
+}
+
 pub struct BitmapMemoryManager {
     alloc_map: [MapLineType; FRAME_COUNT / BITS_PER_MAP_LINE],
     range_begin: FrameId,
@@ -1,3 +1,6 @@
+use crate::error::*;
+use crate::paging::phys_to_virt;
+
 use core::convert::TryFrom;
 
 #[derive(Clone, Copy)]
@@ -32,6 +35,27 @@ impl MemoryMap {
             cur: 0,
         }
     }
+
+    // `buffer` points into the memory owned by the bootloader, so the
+    // descriptors have to be moved before that memory is reclaimed.
+    pub fn copy_to(&self, buf: &'static mut [u8]) -> Result<Self, OsError> {
+        let map_size = self.map_size as usize;
+        if buf.len() < map_size {
+            return make_error!(OsErrorCode::BufferTooSmall);
+        }
+
+        unsafe {
+            let src = core::slice::from_raw_parts(
+                phys_to_virt(self.buffer as u64) as *const u8, map_size);
+            buf[..map_size].copy_from_slice(src);
+        }
+
+        Ok(Self {
+            buffer_size: buf.len() as u64,
+            buffer: buf.as_ptr(),
+            ..*self
+        })
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
@@ -89,4 +113,22 @@ pub fn is_available(memory_type: EfiMemoryType) -> bool {
     memory_type == EfiMemoryType::ConventionalMemory
 }
 
+// Usable after the kernel has copied everything it needs out of them.
+#[inline]
+pub fn is_reclaimable(memory_type: EfiMemoryType) -> bool {
+    is_loader_memory(memory_type) || is_acpi_reclaimable(memory_type)
+}
+
+#[inline]
+pub fn is_loader_memory(memory_type: EfiMemoryType) -> bool {
+    memory_type == EfiMemoryType::LoaderCode ||
+    memory_type == EfiMemoryType::LoaderData
+}
+
+// ACPI tables, which are free once the kernel has parsed them.
+#[inline]
+pub fn is_acpi_reclaimable(memory_type: EfiMemoryType) -> bool {
+    memory_type == EfiMemoryType::AcpiReclaimMemory
+}
+
 pub const UEFI_PAGE_SIZE: usize = 4096;
@@ -0,0 +1,23 @@
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum WindowEvent {
+    Activate,
+    Deactivate,
+    Close,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum Message {
+    // Only fills unused slots of a queue.
+    Empty,
+    InterruptXhci,
+    // A byte from the i8042, from the mouse if `mouse`, read at `tick`.
+    Ps2Data { data: u8, mouse: bool, tick: u64 },
+    TimerTimeout { id: u64 },
+    // Also sent while a key repeats.
+    KeyPush { keycode: u8, ascii: u8, modifier: u8 },
+    KeyRelease { keycode: u8, modifier: u8 },
+    MouseMove { dx: i32, dy: i32 },
+    Window { id: u32, event: WindowEvent },
+}
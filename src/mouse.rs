@@ -1,11 +1,13 @@
 use crate::graphics::{
-    PixelColor, PixelWriter, Vector2D, Displacement
+    BlitMode, Image, PixelColor, PixelWriter, Vector2D, Displacement
 };
-
-use core::cell::RefCell;
+use crate::layer::{LayerId, LayerManager, SharedLayerManager};
+use crate::usb::MOUSE_ABSOLUTE_MAX;
 
 const MOUSE_CURSOR_WIDTH: usize = 15;
 const MOUSE_CURSOR_HEIGHT: usize = 24;
+// Not used by the shape, so it shows the layers below.
+const TRANSPARENT_COLOR: PixelColor = PixelColor { r: 0, g: 0, b: 1 };
 const MOUSE_CURSOR_SHAPE: [[u8; MOUSE_CURSOR_WIDTH]; MOUSE_CURSOR_HEIGHT] = [
     *b"@              ",
     *b"@@             ",
@@ -34,72 +36,89 @@ const MOUSE_CURSOR_SHAPE: [[u8; MOUSE_CURSOR_WIDTH]; MOUSE_CURSOR_HEIGHT] = [
 ];
 
 pub struct MouseCursor<'a> {
-    writer: &'a RefCell<&'a mut dyn PixelWriter>,
-    erase_color: PixelColor,
-    position: Vector2D,
+    layers: &'a SharedLayerManager,
+    layer: LayerId,
 }
 
 impl<'a> MouseCursor<'a> {
     pub fn new(
-        writer: &'a RefCell<&'a mut dyn PixelWriter>,
-        erase_color: PixelColor,
+        layers: &'a SharedLayerManager,
         initial_position: Vector2D,
     ) -> Self {
-        let this = Self {
-            writer,
-            erase_color,
-            position: initial_position,
-        };
-        draw_mouse_cursor(this.writer, &this.position);
-        this
+        let mut manager = layers.lock();
+        let layer = manager.new_layer(MOUSE_CURSOR_WIDTH, MOUSE_CURSOR_HEIGHT);
+        let cursor = manager.layer_mut(layer).unwrap();
+        cursor.set_transparent_color(Some(TRANSPARENT_COLOR));
+        draw_mouse_cursor(cursor);
+        manager.set_topmost(layer, true);
+        manager.move_to(layer, initial_position);
+        manager.show(layer);
+        manager.compose();
+        drop(manager);
+
+        Self { layers, layer }
     }
 
-    pub fn move_relative(&mut self, displacement: Displacement) {
-        erase_mouse_cursor(self.writer, &self.erase_color, &self.position);
-        self.position.displace(&displacement);
-        draw_mouse_cursor(self.writer, &self.position);
+    pub fn position(&self) -> Vector2D {
+        self.layers.lock().position(self.layer).unwrap()
     }
-}
 
-fn draw_mouse_cursor(
-    writer: &RefCell<&mut dyn PixelWriter>,
-    position: &Vector2D,
-) {
-    for (y, &row) in MOUSE_CURSOR_SHAPE.iter().enumerate() {
-        for (x, &pixel) in row.iter().enumerate() {
-            if pixel == b'@' {
-                let black = PixelColor { r: 0, g: 0, b: 0 };
-                writer.borrow_mut().write(
-                    position.x + x,
-                    position.y + y,
-                    &black,
-                );
-            } else if pixel == b'.' {
-                let white = PixelColor { r: 255, g: 255, b: 255 };
-                writer.borrow_mut().write(
-                    position.x + x,
-                    position.y + y,
-                    &white,
-                );
-            }
-        }
+    // Returns the new position.
+    pub fn move_relative(&mut self, displacement: Displacement) -> Vector2D {
+        let mut layers = self.layers.lock();
+        let mut position = layers.position(self.layer).unwrap();
+        position.displace(&displacement);
+        self.move_to(&mut layers, position)
+    }
+
+    // For absolute pointers; x and y are scaled from 0..=MOUSE_ABSOLUTE_MAX
+    // to the screen.
+    pub fn move_absolute(&mut self, x: u16, y: u16) -> Vector2D {
+        let mut layers = self.layers.lock();
+        let screen_size = layers.screen_size();
+        let scale = |value: u16, size: usize| {
+            value as usize * size / (MOUSE_ABSOLUTE_MAX as usize + 1)
+        };
+        let position = Vector2D {
+            x: scale(x, screen_size.x),
+            y: scale(y, screen_size.y),
+        };
+        self.move_to(&mut layers, position)
+    }
+
+    // The hot spot at the top left stays on the screen.
+    fn move_to(
+        &self,
+        layers: &mut LayerManager,
+        position: Vector2D,
+    ) -> Vector2D {
+        let screen_size = layers.screen_size();
+        let position = Vector2D {
+            x: position.x.min(screen_size.x.saturating_sub(1)),
+            y: position.y.min(screen_size.y.saturating_sub(1)),
+        };
+        layers.move_to(self.layer, position);
+        layers.compose();
+        position
     }
 }
 
-fn erase_mouse_cursor(
-    writer: &RefCell<&mut dyn PixelWriter>,
-    erase_color: &PixelColor,
-    position: &Vector2D,
-) {
+fn draw_mouse_cursor(writer: &mut dyn PixelWriter) {
+    let mut pixels =
+        [TRANSPARENT_COLOR; MOUSE_CURSOR_WIDTH * MOUSE_CURSOR_HEIGHT];
     for (y, &row) in MOUSE_CURSOR_SHAPE.iter().enumerate() {
         for (x, &pixel) in row.iter().enumerate() {
-            if pixel != b' ' {
-                writer.borrow_mut().write(
-                    position.x + x,
-                    position.y + y,
-                    erase_color,
-                );
-            }
+            pixels[MOUSE_CURSOR_WIDTH * y + x] = match pixel {
+                b'@' => PixelColor { r: 0, g: 0, b: 0 },
+                b'.' => PixelColor { r: 255, g: 255, b: 255 },
+                _ => TRANSPARENT_COLOR,
+            };
         }
     }
+    let image = Image {
+        width: MOUSE_CURSOR_WIDTH,
+        height: MOUSE_CURSOR_HEIGHT,
+        pixels: &pixels,
+    };
+    writer.blit_image(0, 0, &image, BlitMode::Opaque);
 }
@@ -1,35 +1,646 @@
+use crate::error::*;
+use crate::memory_manager::{memory_manager, FrameId, BYTE_PER_FRAME};
+use crate::smp::shootdown_tlb;
+
+use core::{arch::asm, ops::{BitOr, BitOrAssign}};
+
 extern "C" {
     fn set_cr3(value: u64);
+    fn get_cr3() -> u64;
+    fn get_cr0() -> u64;
+    fn set_cr0(value: u64);
+    fn read_msr(msr: u32) -> u64;
+    fn write_msr(msr: u32, value: u64);
+    fn invlpg(addr: u64);
+
+    // Defined in kernel.ld.
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __kernel_end: u8;
 }
 
+const MSR_EFER: u32 = 0xc000_0080;
+const EFER_NXE: u64 = 1 << 11;
+const CR0_WP: u64 = 1 << 16;
+const CR4_PGE: u64 = 1 << 7;
+
+// Larger ranges flush the whole TLB instead of each page.
+const TLB_FLUSH_ALL_PAGES: u64 = 64;
+
 const PAGE_DIRECTORY_COUNT: usize = 64;
 
-const PAGE_SIZE_4K: u64 = 4096;
-const PAGE_SIZE_2M: u64 = 512 * PAGE_SIZE_4K;
-const PAGE_SIZE_1G: u64 = 512 * PAGE_SIZE_2M;
+pub const PAGE_SIZE_4K: u64 = 4096;
+pub const PAGE_SIZE_2M: u64 = 512 * PAGE_SIZE_4K;
+pub const PAGE_SIZE_1G: u64 = 512 * PAGE_SIZE_2M;
+
+// Keep in sync with `kernel.ld` and the boot code in `asmfunc.rs`.
+pub const KERNEL_VIRTUAL_BASE: u64 = 0xffff_ffff_8000_0000;
+pub const PHYS_MAP_OFFSET: u64 = 0xffff_8000_0000_0000;
+pub const PHYS_MAP_SIZE: u64 = PAGE_DIRECTORY_COUNT as u64 * PAGE_SIZE_1G;
+
+// The lower half belongs to user space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+pub const USER_HEAP_BASE: u64 = 0x0000_4000_0000_0000;
+pub const USER_HEAP_END: u64 = 0x0000_6000_0000_0000;
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_f000;
+
+const KERNEL_PML4_INDEX_START: usize = 256;
 
 #[repr(align(4096))]
-struct AlignedTable([u64; 512]);
+pub struct AlignedTable([u64; 512]);
 
 #[repr(align(4096))]
-struct AlignedDirectory([[u64; 512]; PAGE_DIRECTORY_COUNT]);
+#[allow(dead_code)]
+pub struct AlignedDirectory([[u64; 512]; PAGE_DIRECTORY_COUNT]);
 
-static mut PML4_TABLE: AlignedTable = AlignedTable([0; 512]);
-static mut PDP_TABLE: AlignedTable = AlignedTable([0; 512]);
-static mut PAGE_DIRECTORY: AlignedDirectory
+// Filled by the boot code before entering the higher half.
+#[no_mangle]
+pub static mut PML4_TABLE: AlignedTable = AlignedTable([0; 512]);
+#[no_mangle]
+pub static mut PDP_TABLE: AlignedTable = AlignedTable([0; 512]);
+#[no_mangle]
+pub static mut KERNEL_PDP_TABLE: AlignedTable = AlignedTable([0; 512]);
+#[no_mangle]
+pub static mut PAGE_DIRECTORY: AlignedDirectory
     = AlignedDirectory([[0; 512]; PAGE_DIRECTORY_COUNT]);
+#[no_mangle]
+pub static mut KERNEL_PAGE_DIRECTORY: AlignedTable = AlignedTable([0; 512]);
+
+pub fn phys_to_virt(phys: u64) -> u64 {
+    phys + PHYS_MAP_OFFSET
+}
+
+// Only valid for the kernel image and the direct map.
+pub fn virt_to_phys(virt: u64) -> u64 {
+    if virt >= KERNEL_VIRTUAL_BASE {
+        virt - KERNEL_VIRTUAL_BASE
+    } else {
+        virt - PHYS_MAP_OFFSET
+    }
+}
+
+// Invalidates [virt, virt + size) in the TLB of this CPU.
+pub fn flush_tlb(virt: u64, size: u64) {
+    if size / PAGE_SIZE_4K > TLB_FLUSH_ALL_PAGES {
+        flush_tlb_all();
+        return;
+    }
+    // Counted from `virt`, as the range may end at the top of the address
+    // space.
+    let mut offset = 0;
+    while offset < size {
+        unsafe {
+            invlpg(virt + offset);
+        }
+        offset += PAGE_SIZE_4K;
+    }
+}
+
+// Reloading CR3 keeps global pages, so CR4.PGE is toggled instead.
+fn flush_tlb_all() {
+    unsafe {
+        let cr4: u64;
+        asm!("mov {}, cr4", out(reg) cr4);
+        if cr4 & CR4_PGE != 0 {
+            asm!("mov cr4, {}", in(reg) cr4 & !CR4_PGE);
+            asm!("mov cr4, {}", in(reg) cr4);
+        } else {
+            set_cr3(get_cr3());
+        }
+    }
+}
+
+pub fn setup_page_table() {
+    unsafe {
+        // The identity mapping is only needed until the kernel jumps to the
+        // higher half. The lower half is left for user space.
+        PML4_TABLE.0[0] = 0;
+        set_cr3(virt_to_phys(&PML4_TABLE as *const _ as u64));
+    }
+}
+
+// Maps the kernel image according to the permissions of its sections and
+// makes everything else non-executable. Requires the frame allocator since
+// huge pages are split.
+pub fn setup_memory_protection() -> Result<(), OsError> {
+    unsafe {
+        write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_NXE);
+    }
+
+    let symbol = |s: &u8| s as *const u8 as u64;
+    let (text_start, text_end, rodata_start, rodata_end, data_start, end) =
+        unsafe {
+            (
+                symbol(&__text_start), symbol(&__text_end),
+                symbol(&__rodata_start), symbol(&__rodata_end),
+                symbol(&__data_start), symbol(&__kernel_end),
+            )
+        };
+
+    let mut page_table = PageTableManager::current();
+
+    // The boot code maps the whole first 1 GiB here; keep only the image.
+    page_table.unmap(KERNEL_VIRTUAL_BASE, text_start - KERNEL_VIRTUAL_BASE)?;
+    page_table.unmap(end, KERNEL_VIRTUAL_BASE + PAGE_SIZE_1G - end)?;
+
+    let sections = [
+        (text_start, text_end, PageFlags::GLOBAL),
+        (rodata_start, rodata_end, PageFlags::GLOBAL | PageFlags::NO_EXECUTE),
+        (
+            data_start,
+            end,
+            PageFlags::GLOBAL | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        ),
+    ];
+    for &(start, end, flags) in &sections {
+        page_table.protect(start, end - start, flags)?;
+    }
+
+    page_table.protect(
+        PHYS_MAP_OFFSET,
+        PHYS_MAP_SIZE,
+        PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+    )?;
+    // Code and constants must not be modified through the direct map either.
+    page_table.protect(
+        phys_to_virt(virt_to_phys(text_start)),
+        rodata_end - text_start,
+        PageFlags::NO_EXECUTE,
+    )?;
+
+    unsafe {
+        set_cr0(get_cr0() | CR0_WP);
+    }
+    Ok(())
+}
+
+// Every address space shares the kernel half of the PML4, so all of its
+// entries have to point to a table before the first one is created.
+pub fn setup_kernel_address_space() -> Result<(), OsError> {
+    let pml4 = table_at(PageTableManager::current().pml4());
+    for entry in pml4[KERNEL_PML4_INDEX_START..].iter_mut() {
+        if !is_present(*entry) {
+            *entry = allocate_table()? | table_entry_flags(PageFlags::empty());
+        }
+    }
+    Ok(())
+}
+
+// Maps device memory into the direct map as uncached and returns its
+// virtual address.
+pub fn map_mmio(phys: u64, size: u64) -> Result<u64, OsError> {
+    let start = phys & !(PAGE_SIZE_4K - 1);
+    let end = (phys + size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+    PageTableManager::current().map(
+        phys_to_virt(start),
+        start,
+        end - start,
+        PageFlags::WRITABLE
+            | PageFlags::NO_EXECUTE
+            | PageFlags::WRITE_THROUGH
+            | PageFlags::CACHE_DISABLE,
+    )?;
+    Ok(phys_to_virt(phys))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PageFlags(u64);
+
+#[allow(dead_code)]
+impl PageFlags {
+    pub const PRESENT: Self = Self(1 << 0);
+    pub const WRITABLE: Self = Self(1 << 1);
+    pub const USER: Self = Self(1 << 2);
+    pub const WRITE_THROUGH: Self = Self(1 << 3);
+    pub const CACHE_DISABLE: Self = Self(1 << 4);
+    pub const GLOBAL: Self = Self(1 << 8);
+    pub const NO_EXECUTE: Self = Self(1 << 63);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PageFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+const HUGE_PAGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const FLAGS_MASK: u64 = PageFlags::PRESENT.bits()
+    | PageFlags::WRITABLE.bits()
+    | PageFlags::USER.bits()
+    | PageFlags::WRITE_THROUGH.bits()
+    | PageFlags::CACHE_DISABLE.bits()
+    | PageFlags::GLOBAL.bits()
+    | PageFlags::NO_EXECUTE.bits();
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4K => PAGE_SIZE_4K,
+            PageSize::Size2M => PAGE_SIZE_2M,
+            PageSize::Size1G => PAGE_SIZE_1G,
+        }
+    }
+
+    // Level of the table whose entries map pages of this size.
+    // (PML4 = 4, PDP = 3, PD = 2, PT = 1)
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 1,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 3,
+        }
+    }
+
+    const fn from_level(level: usize) -> Self {
+        match level {
+            3 => PageSize::Size1G,
+            2 => PageSize::Size2M,
+            _ => PageSize::Size4K,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub struct Translation {
+    pub phys: u64,
+    pub size: PageSize,
+    pub flags: PageFlags,
+}
+
+// Size of the range covered by one entry of the table of `level`.
+const fn entry_span(level: usize) -> u64 {
+    1 << (12 + 9 * (level - 1))
+}
+
+const fn table_index(virt: u64, level: usize) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+fn table_at(phys: u64) -> &'static mut [u64; 512] {
+    unsafe {
+        &mut *(phys_to_virt(phys) as *mut [u64; 512])
+    }
+}
 
-pub fn setup_identity_page_table() {
+fn allocate_table() -> Result<u64, OsError> {
+    let mut frame = memory_manager().allocate(1)?;
     unsafe {
-        PML4_TABLE.0[0] = &PDP_TABLE as *const _ as u64 | 0x003;
-        for (i, pd) in PAGE_DIRECTORY.0.iter_mut().enumerate() {
-            PDP_TABLE.0[i] = &pd[0] as *const _ as u64 | 0x003;
-            for j in 0..512 {
-                PAGE_DIRECTORY.0[i][j] =
-                    (i as u64 * PAGE_SIZE_1G + j as u64 * PAGE_SIZE_2M) | 0x083;
+        (*(frame.frame() as *mut [u64; 512])).fill(0);
+    }
+    Ok((frame.id() * BYTE_PER_FRAME) as u64)
+}
+
+fn free_frames(phys: u64, size: u64) -> Result<(), OsError> {
+    memory_manager().free(
+        FrameId::new(phys as usize / BYTE_PER_FRAME),
+        size as usize / BYTE_PER_FRAME,
+    )
+}
+
+fn is_present(entry: u64) -> bool {
+    entry & PageFlags::PRESENT.bits() != 0
+}
+
+fn is_leaf(entry: u64, level: usize) -> bool {
+    level == 1 || entry & HUGE_PAGE != 0
+}
+
+fn table_entry_flags(flags: PageFlags) -> u64 {
+    let mut table_flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    if flags.contains(PageFlags::USER) {
+        table_flags |= PageFlags::USER;
+    }
+    table_flags.bits()
+}
+
+enum Leaf {
+    Mapped(&'static mut u64, PageSize),
+    // Bytes from the address to the end of the unmapped entry.
+    Unmapped { skip: u64 },
+}
+
+pub struct PageTableManager {
+    pml4: u64,
+}
+
+#[allow(dead_code)]
+impl PageTableManager {
+    pub fn new() -> Result<Self, OsError> {
+        Ok(Self { pml4: allocate_table()? })
+    }
+
+    // Creates an address space with an empty user half.
+    pub fn new_address_space() -> Result<Self, OsError> {
+        let page_table = Self::new()?;
+        let current = table_at(Self::current().pml4);
+        table_at(page_table.pml4)[KERNEL_PML4_INDEX_START..]
+            .copy_from_slice(&current[KERNEL_PML4_INDEX_START..]);
+        Ok(page_table)
+    }
+
+    // Frees the user half, including the frames mapped there, and the PML4.
+    // The address space must not be active.
+    pub fn release(self) -> Result<(), OsError> {
+        let pml4 = table_at(self.pml4);
+        for entry in pml4[..KERNEL_PML4_INDEX_START].iter() {
+            release_entry(*entry, 4)?;
+        }
+        free_frames(self.pml4, PAGE_SIZE_4K)
+    }
+
+    pub fn from_pml4(pml4: u64) -> Self {
+        Self { pml4 }
+    }
+
+    pub fn current() -> Self {
+        Self { pml4: unsafe { get_cr3() } & ADDRESS_MASK }
+    }
+
+    pub fn pml4(&self) -> u64 {
+        self.pml4
+    }
+
+    pub fn activate(&self) {
+        unsafe {
+            set_cr3(self.pml4);
+        }
+    }
+
+    pub fn map(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        size: u64,
+        flags: PageFlags,
+    ) -> Result<(), OsError> {
+        if virt % PAGE_SIZE_4K != 0 || phys % PAGE_SIZE_4K != 0 ||
+            size % PAGE_SIZE_4K != 0 {
+            return make_error!(OsErrorCode::InvalidAlignment);
+        }
+        check_range(virt, size)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let page_size = self.map_page(
+                virt + offset, phys + offset, size - offset, flags)?;
+            offset += page_size.bytes();
+        }
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, virt: u64, size: u64) -> Result<(), OsError> {
+        if virt % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+            return make_error!(OsErrorCode::InvalidAlignment);
+        }
+
+        check_range(virt, size)?;
+        let mut offset = 0;
+        let mut changed = false;
+        while offset < size {
+            let addr = virt + offset;
+            let advance = match self.leaf_covering(addr, size - offset)? {
+                Leaf::Mapped(entry, page_size) => {
+                    *entry = 0;
+                    unsafe {
+                        invlpg(addr);
+                    }
+                    changed = true;
+                    page_size.bytes()
+                },
+                Leaf::Unmapped { skip } => skip,
+            };
+            offset = offset.saturating_add(advance);
+        }
+        if changed {
+            self.shootdown(virt, size);
+        }
+        Ok(())
+    }
+
+    pub fn protect(
+        &mut self,
+        virt: u64,
+        size: u64,
+        flags: PageFlags,
+    ) -> Result<(), OsError> {
+        if virt % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+            return make_error!(OsErrorCode::InvalidAlignment);
+        }
+
+        check_range(virt, size)?;
+        let mut offset = 0;
+        while offset < size {
+            let addr = virt + offset;
+            match self.leaf_covering(addr, size - offset)? {
+                Leaf::Mapped(entry, page_size) => {
+                    *entry = (*entry & !FLAGS_MASK) |
+                        (flags | PageFlags::PRESENT).bits();
+                    unsafe {
+                        invlpg(addr);
+                    }
+                    offset += page_size.bytes();
+                },
+                Leaf::Unmapped { .. } => {
+                    return make_error!(OsErrorCode::PageNotMapped);
+                },
+            }
+        }
+        self.shootdown(virt, size);
+        Ok(())
+    }
+
+    pub fn translate(&self, virt: u64) -> Option<Translation> {
+        let mut table = table_at(self.pml4);
+        for level in (1..=4).rev() {
+            let entry = table[table_index(virt, level)];
+            if !is_present(entry) {
+                return None;
+            }
+            if is_leaf(entry, level) {
+                let span = entry_span(level);
+                let base = entry & ADDRESS_MASK & !(span - 1);
+                return Some(Translation {
+                    phys: base + (virt & (span - 1)),
+                    size: PageSize::from_level(level),
+                    flags: PageFlags(entry & FLAGS_MASK),
+                });
+            }
+            table = table_at(entry & ADDRESS_MASK);
+        }
+        None
+    }
+
+    // Other CPUs may cache the kernel half, which every address space
+    // shares, and the address space active here.
+    fn shootdown(&self, virt: u64, size: u64) {
+        if virt >= USER_SPACE_END || self.pml4 == Self::current().pml4 {
+            shootdown_tlb(virt, size);
+        }
+    }
+
+    // Maps the largest page that fits at `virt` and returns its size.
+    fn map_page(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        remaining: u64,
+        flags: PageFlags,
+    ) -> Result<PageSize, OsError> {
+        use PageSize::*;
+        for &page_size in &[Size1G, Size2M, Size4K] {
+            let bytes = page_size.bytes();
+            if virt % bytes != 0 || phys % bytes != 0 || remaining < bytes {
+                continue;
+            }
+
+            let level = page_size.level();
+            let entry = self.entry_at(virt, level, flags)?;
+            if level > 1 && is_present(*entry) && *entry & HUGE_PAGE == 0 {
+                // Already split into a lower table; keep it.
+                continue;
+            }
+
+            let was_present = is_present(*entry);
+            *entry = phys | (flags | PageFlags::PRESENT).bits();
+            if level > 1 {
+                *entry |= HUGE_PAGE;
+            }
+            if was_present {
+                unsafe {
+                    invlpg(virt);
+                }
+                self.shootdown(virt, bytes);
+            }
+            return Ok(page_size);
+        }
+        unreachable!()
+    }
+
+    // Walks down to the table of `level`, creating tables and splitting
+    // huge pages on the way.
+    fn entry_at(
+        &mut self,
+        virt: u64,
+        level: usize,
+        flags: PageFlags,
+    ) -> Result<&'static mut u64, OsError> {
+        let mut table = table_at(self.pml4);
+        for current in ((level + 1)..=4).rev() {
+            let entry = &mut table[table_index(virt, current)];
+            if !is_present(*entry) {
+                *entry = allocate_table()?;
+            } else if is_leaf(*entry, current) {
+                split_huge_page(entry, current)?;
             }
+            *entry |= table_entry_flags(flags);
+            table = table_at(*entry & ADDRESS_MASK);
         }
+        Ok(&mut table[table_index(virt, level)])
+    }
+
+    // Finds the leaf entry mapping `addr`. Huge pages reaching out of
+    // `[addr, addr + remaining)` are split first.
+    fn leaf_covering(
+        &mut self,
+        addr: u64,
+        remaining: u64,
+    ) -> Result<Leaf, OsError> {
+        let mut table = table_at(self.pml4);
+        let mut level = 4;
+        loop {
+            let entry = &mut table[table_index(addr, level)];
+            let span = entry_span(level);
+            if !is_present(*entry) {
+                return Ok(Leaf::Unmapped {
+                    skip: span - (addr & (span - 1)),
+                });
+            }
+            if is_leaf(*entry, level) {
+                if addr % span == 0 && remaining >= span {
+                    return Ok(Leaf::Mapped(entry, PageSize::from_level(level)));
+                }
+                split_huge_page(entry, level)?;
+            }
+            table = table_at(*entry & ADDRESS_MASK);
+            level -= 1;
+        }
+    }
+}
+
+// The range may end at the top of the address space but not wrap around.
+fn check_range(virt: u64, size: u64) -> Result<(), OsError> {
+    if size > 0 && virt.checked_add(size - 1).is_none() {
+        return make_error!(OsErrorCode::IndexOutOfRange);
+    }
+    Ok(())
+}
+
+// Replaces a huge page entry with a table mapping the same range with
+// pages of the next smaller size.
+fn split_huge_page(entry: &mut u64, level: usize) -> Result<(), OsError> {
+    let table_phys = allocate_table()?;
+    let table = table_at(table_phys);
+
+    let child_span = entry_span(level - 1);
+    let base = *entry & ADDRESS_MASK & !(entry_span(level) - 1);
+    let flags = PageFlags(*entry & FLAGS_MASK);
+    let mut child_flags = flags.bits();
+    if level - 1 > 1 {
+        child_flags |= HUGE_PAGE;
+    }
+    for (i, child) in table.iter_mut().enumerate() {
+        *child = (base + i as u64 * child_span) | child_flags;
+    }
+
+    *entry = table_phys | table_entry_flags(flags);
+    Ok(())
+}
+
+fn release_entry(entry: u64, level: usize) -> Result<(), OsError> {
+    if !is_present(entry) {
+        return Ok(());
+    }
+    if is_leaf(entry, level) {
+        let span = entry_span(level);
+        return free_frames(entry & ADDRESS_MASK & !(span - 1), span);
+    }
 
-        set_cr3(&PML4_TABLE as *const _ as u64);
+    let table_phys = entry & ADDRESS_MASK;
+    for child in table_at(table_phys).iter() {
+        release_entry(*child, level - 1)?;
     }
+    free_frames(table_phys, PAGE_SIZE_4K)
 }
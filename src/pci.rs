@@ -506,16 +506,38 @@ fn configure_msi(
     }
 }
 
+#[allow(dead_code)]
 pub fn configure_msi_fixed_destination(
     device: &Device,
     apic_id: u32,
     trigger_mode: MsiTriggerMode,
-    derivery_mode: MsiDeliveryMode,
+    delivery_mode: MsiDeliveryMode,
     vector: u32,
     num_vector_exponent: usize,
 ) -> Result<(), OsError> {
     let msg_addr = 0xfee00000 | (apic_id << 12);
-    let mut msg_data = ((derivery_mode as u32) << 8) | vector;
+    let mut msg_data = ((delivery_mode as u32) << 8) | vector;
+    if trigger_mode == MsiTriggerMode::Level {
+        msg_data |= 0xc000;
+    }
+    configure_msi(device, msg_addr, msg_data, num_vector_exponent)
+}
+
+// Delivers to the CPUs whose logical APIC ID (flat model) is in
+// `logical_mask`. With lowest priority delivery only one of them is
+// interrupted, which spreads the load.
+pub fn configure_msi_logical_destination(
+    device: &Device,
+    logical_mask: u8,
+    trigger_mode: MsiTriggerMode,
+    delivery_mode: MsiDeliveryMode,
+    vector: u32,
+    num_vector_exponent: usize,
+) -> Result<(), OsError> {
+    // RH (redirection hint) and DM (logical destination mode).
+    let msg_addr =
+        0xfee00000 | ((logical_mask as u32) << 12) | (1 << 3) | (1 << 2);
+    let mut msg_data = ((delivery_mode as u32) << 8) | vector;
     if trigger_mode == MsiTriggerMode::Level {
         msg_data |= 0xc000;
     }
@@ -0,0 +1,597 @@
+use crate::error::*;
+use crate::interrupt::{
+    InterruptVector, ExceptionStackFrame, KernelGsGuard,
+    notify_end_of_interrupt, get_cs, load_idt, make_id_attr, set_idt_entry,
+    IDT,
+};
+use crate::apic::route_isa_irq;
+use crate::keyboard::on_key_event;
+use crate::logger::*;
+use crate::message::Message;
+use crate::smp::current_cpu;
+use crate::sync::{IrqLock, OnceCell};
+use crate::task::{preempt_if_needed, send_message, TaskId};
+use crate::timer::current_tick;
+use crate::usb::MouseEvent;
+use crate::x86_descriptor::GateDescriptorType;
+
+use core::mem::{replace, size_of_val};
+
+extern "C" {
+    fn io_out8(addr: u16, data: u8);
+    fn io_in8(addr: u16) -> u8;
+}
+
+const DATA_PORT: u16 = 0x60;
+// Status when read, command when written.
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_MOUSE: u8 = 0xa7;
+const COMMAND_ENABLE_MOUSE: u8 = 0xa8;
+const COMMAND_TEST_MOUSE: u8 = 0xa9;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_TEST_KEYBOARD: u8 = 0xab;
+const COMMAND_DISABLE_KEYBOARD: u8 = 0xad;
+const COMMAND_ENABLE_KEYBOARD: u8 = 0xae;
+const COMMAND_WRITE_MOUSE: u8 = 0xd4;
+
+const CONFIG_KEYBOARD_INTERRUPT: u8 = 1 << 0;
+const CONFIG_MOUSE_INTERRUPT: u8 = 1 << 1;
+const CONFIG_KEYBOARD_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_MOUSE_CLOCK_DISABLED: u8 = 1 << 5;
+// The controller translates set 2 to set 1.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Commands to the devices and their responses.
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_GET_ID: u8 = 0xf2;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+const DEVICE_ACK: u8 = 0xfa;
+const DEVICE_RESEND: u8 = 0xfe;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+
+// Reported after a wheel mouse has been unlocked by a sequence of sample
+// rates.
+const MOUSE_ID_WHEEL: u8 = 0x03;
+
+const KEYBOARD_IRQ: u8 = 1;
+const MOUSE_IRQ: u8 = 12;
+
+// Roughly a second of reading the status port, for resetting devices.
+const WAIT_LOOPS: usize = 1_000_000;
+
+const MOUSE_PACKET_ALWAYS_SET: u8 = 1 << 3;
+const MOUSE_PACKET_X_SIGN: u8 = 1 << 4;
+const MOUSE_PACKET_Y_SIGN: u8 = 1 << 5;
+const MOUSE_PACKET_OVERFLOW: u8 = (1 << 6) | (1 << 7);
+const MOUSE_PACKET_BUTTONS: u8 = 0b111;
+// The bytes of a packet come within a few milliseconds, so a gap of more
+// than a tick (10 ms) ends a packet cut short by a lost byte.
+const MOUSE_PACKET_TIMEOUT_TICKS: u64 = 2;
+
+// HID usage IDs of the modifier keys.
+const KEYCODE_LEFT_CONTROL: u8 = 0xe0;
+const KEYCODE_RIGHT_GUI: u8 = 0xe7;
+
+// HID usage ID, scan code set 1 and scan code set 2 of each key. In set 1 a
+// key is released with its code plus 0x80, in set 2 with 0xf0 before it.
+const KEYS: [(u8, u8, u8); 91] = [
+    (0x04, 0x1e, 0x1c), // A
+    (0x05, 0x30, 0x32), // B
+    (0x06, 0x2e, 0x21), // C
+    (0x07, 0x20, 0x23), // D
+    (0x08, 0x12, 0x24), // E
+    (0x09, 0x21, 0x2b), // F
+    (0x0a, 0x22, 0x34), // G
+    (0x0b, 0x23, 0x33), // H
+    (0x0c, 0x17, 0x43), // I
+    (0x0d, 0x24, 0x3b), // J
+    (0x0e, 0x25, 0x42), // K
+    (0x0f, 0x26, 0x4b), // L
+    (0x10, 0x32, 0x3a), // M
+    (0x11, 0x31, 0x31), // N
+    (0x12, 0x18, 0x44), // O
+    (0x13, 0x19, 0x4d), // P
+    (0x14, 0x10, 0x15), // Q
+    (0x15, 0x13, 0x2d), // R
+    (0x16, 0x1f, 0x1b), // S
+    (0x17, 0x14, 0x2c), // T
+    (0x18, 0x16, 0x3c), // U
+    (0x19, 0x2f, 0x2a), // V
+    (0x1a, 0x11, 0x1d), // W
+    (0x1b, 0x2d, 0x22), // X
+    (0x1c, 0x15, 0x35), // Y
+    (0x1d, 0x2c, 0x1a), // Z
+    (0x1e, 0x02, 0x16), // 1
+    (0x1f, 0x03, 0x1e), // 2
+    (0x20, 0x04, 0x26), // 3
+    (0x21, 0x05, 0x25), // 4
+    (0x22, 0x06, 0x2e), // 5
+    (0x23, 0x07, 0x36), // 6
+    (0x24, 0x08, 0x3d), // 7
+    (0x25, 0x09, 0x3e), // 8
+    (0x26, 0x0a, 0x46), // 9
+    (0x27, 0x0b, 0x45), // 0
+    (0x28, 0x1c, 0x5a), // Enter
+    (0x29, 0x01, 0x76), // Escape
+    (0x2a, 0x0e, 0x66), // Backspace
+    (0x2b, 0x0f, 0x0d), // Tab
+    (0x2c, 0x39, 0x29), // Space
+    (0x2d, 0x0c, 0x4e), // -
+    (0x2e, 0x0d, 0x55), // =
+    (0x2f, 0x1a, 0x54), // [
+    (0x30, 0x1b, 0x5b), // ]
+    (0x31, 0x2b, 0x5d), // \
+    (0x33, 0x27, 0x4c), // ;
+    (0x34, 0x28, 0x52), // '
+    (0x35, 0x29, 0x0e), // `
+    (0x36, 0x33, 0x41), // ,
+    (0x37, 0x34, 0x49), // .
+    (0x38, 0x35, 0x4a), // /
+    (0x39, 0x3a, 0x58), // Caps Lock
+    (0x3a, 0x3b, 0x05), // F1
+    (0x3b, 0x3c, 0x06), // F2
+    (0x3c, 0x3d, 0x04), // F3
+    (0x3d, 0x3e, 0x0c), // F4
+    (0x3e, 0x3f, 0x03), // F5
+    (0x3f, 0x40, 0x0b), // F6
+    (0x40, 0x41, 0x83), // F7
+    (0x41, 0x42, 0x0a), // F8
+    (0x42, 0x43, 0x01), // F9
+    (0x43, 0x44, 0x09), // F10
+    (0x44, 0x57, 0x78), // F11
+    (0x45, 0x58, 0x07), // F12
+    (0x47, 0x46, 0x7e), // Scroll Lock
+    (0x53, 0x45, 0x77), // Num Lock
+    (0x55, 0x37, 0x7c), // Keypad *
+    (0x56, 0x4a, 0x7b), // Keypad -
+    (0x57, 0x4e, 0x79), // Keypad +
+    (0x59, 0x4f, 0x69), // Keypad 1
+    (0x5a, 0x50, 0x72), // Keypad 2
+    (0x5b, 0x51, 0x7a), // Keypad 3
+    (0x5c, 0x4b, 0x6b), // Keypad 4
+    (0x5d, 0x4c, 0x73), // Keypad 5
+    (0x5e, 0x4d, 0x74), // Keypad 6
+    (0x5f, 0x47, 0x6c), // Keypad 7
+    (0x60, 0x48, 0x75), // Keypad 8
+    (0x61, 0x49, 0x7d), // Keypad 9
+    (0x62, 0x52, 0x70), // Keypad 0
+    (0x63, 0x53, 0x71), // Keypad .
+    (0x64, 0x56, 0x61), // Non-US \
+    (0x87, 0x73, 0x51), // Ro (JIS)
+    (0x88, 0x70, 0x13), // Katakana (JIS)
+    (0x89, 0x7d, 0x6a), // Yen (JIS)
+    (0x8a, 0x79, 0x64), // Henkan (JIS)
+    (0x8b, 0x7b, 0x67), // Muhenkan (JIS)
+    (0xe0, 0x1d, 0x14), // Left Control
+    (0xe1, 0x2a, 0x12), // Left Shift
+    (0xe2, 0x38, 0x11), // Left Alt
+    (0xe5, 0x36, 0x59), // Right Shift
+];
+
+// Prefixed with 0xe0.
+const EXTENDED_KEYS: [(u8, u8, u8); 18] = [
+    (0x46, 0x37, 0x7c), // Print Screen
+    (0x49, 0x52, 0x70), // Insert
+    (0x4a, 0x47, 0x6c), // Home
+    (0x4b, 0x49, 0x7d), // Page Up
+    (0x4c, 0x53, 0x71), // Delete
+    (0x4d, 0x4f, 0x69), // End
+    (0x4e, 0x51, 0x7a), // Page Down
+    (0x4f, 0x4d, 0x74), // Right
+    (0x50, 0x4b, 0x6b), // Left
+    (0x51, 0x50, 0x72), // Down
+    (0x52, 0x48, 0x75), // Up
+    (0x54, 0x35, 0x4a), // Keypad /
+    (0x58, 0x1c, 0x5a), // Keypad Enter
+    (0x65, 0x5d, 0x2f), // Menu
+    (0xe3, 0x5b, 0x1f), // Left GUI
+    (0xe4, 0x1d, 0x14), // Right Control
+    (0xe6, 0x38, 0x11), // Right Alt
+    (0xe7, 0x5c, 0x27), // Right GUI
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ScanCodeSet {
+    Set1,
+    Set2,
+}
+
+struct KeyboardDecoder {
+    set: ScanCodeSet,
+    extended: bool,
+    release: bool,
+    // Pause sends a sequence without a release, which is skipped.
+    skip: u8,
+    modifier: u8,
+    // By usage ID, so that the typematic repeat of the keyboard is not
+    // taken as presses; the key repeat comes from the keyboard module.
+    held: [u64; 4],
+}
+
+impl KeyboardDecoder {
+    // Returns the usage ID, the modifier after it and whether it was
+    // pressed once a key is complete.
+    fn feed(&mut self, data: u8) -> Option<(u8, u8, bool)> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match data {
+            // Errors and responses to commands.
+            0x00 | DEVICE_ACK | DEVICE_RESEND | 0xff => return None,
+            0xe0 => {
+                self.extended = true;
+                return None;
+            },
+            0xe1 => {
+                self.skip = if self.set == ScanCodeSet::Set1 { 5 } else { 7 };
+                return None;
+            },
+            0xf0 if self.set == ScanCodeSet::Set2 => {
+                self.release = true;
+                return None;
+            },
+            _ => {},
+        }
+
+        let (code, release) = match self.set {
+            ScanCodeSet::Set1 => (data & 0x7f, data & 0x80 != 0),
+            ScanCodeSet::Set2 => (data, replace(&mut self.release, false)),
+        };
+        let keys: &[(u8, u8, u8)] = if replace(&mut self.extended, false) {
+            &EXTENDED_KEYS
+        } else {
+            &KEYS
+        };
+        let set = self.set;
+        let &(keycode, _, _) = keys.iter().find(|&&(_, set1, set2)| {
+            code == if set == ScanCodeSet::Set1 { set1 } else { set2 }
+        })?;
+
+        let index = keycode as usize / 64;
+        let bit = 1 << (keycode % 64);
+        if release {
+            self.held[index] &= !bit;
+        } else if self.held[index] & bit != 0 {
+            return None;
+        } else {
+            self.held[index] |= bit;
+        }
+        if (KEYCODE_LEFT_CONTROL..=KEYCODE_RIGHT_GUI).contains(&keycode) {
+            let bit = 1 << (keycode - KEYCODE_LEFT_CONTROL);
+            if release {
+                self.modifier &= !bit;
+            } else {
+                self.modifier |= bit;
+            }
+        }
+        Some((keycode, self.modifier, !release))
+    }
+}
+
+struct MouseDecoder {
+    packet: [u8; 4],
+    received: usize,
+    // 4 with a wheel.
+    packet_size: usize,
+    last_tick: u64,
+}
+
+impl MouseDecoder {
+    fn feed(&mut self, data: u8, tick: u64) -> Option<MouseEvent> {
+        // Bytes of the packet were lost, and the rest is not to be mistaken
+        // for the start of the next one.
+        let gap = tick.wrapping_sub(replace(&mut self.last_tick, tick));
+        if self.received != 0 && gap >= MOUSE_PACKET_TIMEOUT_TICKS {
+            self.received = 0;
+        }
+        // A packet starts with the flags, which have bit 3 set. Bytes lost
+        // while the mouse keeps moving may still shift the packets, until the
+        // next gap or a byte here without the bit.
+        if self.received == 0 && data & MOUSE_PACKET_ALWAYS_SET == 0 {
+            return None;
+        }
+        self.packet[self.received] = data;
+        self.received += 1;
+        if self.received < self.packet_size {
+            return None;
+        }
+        self.received = 0;
+
+        let flags = self.packet[0];
+        if flags & MOUSE_PACKET_OVERFLOW != 0 {
+            return None;
+        }
+        // 9-bit displacements, with y upwards.
+        let mut dx = self.packet[1] as i16;
+        if flags & MOUSE_PACKET_X_SIGN != 0 {
+            dx -= 0x100;
+        }
+        let mut dy = self.packet[2] as i16;
+        if flags & MOUSE_PACKET_Y_SIGN != 0 {
+            dy -= 0x100;
+        }
+        // Positive is towards the user, unlike HID.
+        let wheel = if self.packet_size == 4 {
+            (self.packet[3] as i8).saturating_neg()
+        } else {
+            0
+        };
+        Some(MouseEvent {
+            buttons: flags & MOUSE_PACKET_BUTTONS,
+            dx: dx.clamp(-128, 127) as i8,
+            dy: (-dy).clamp(-128, 127) as i8,
+            wheel,
+            absolute: false,
+            x: 0,
+            y: 0,
+        })
+    }
+}
+
+// Bytes are read by the interrupt handlers and sent to `task` as
+// Message::Ps2Data, which passes them to on_ps2_data. Keys go to the keyboard
+// module and mouse packets to `mouse_observer`, as from USB devices.
+struct Ps2 {
+    task: TaskId,
+    keyboard: KeyboardDecoder,
+    mouse: MouseDecoder,
+    mouse_observer: extern "C" fn(MouseEvent),
+}
+
+static PS2: OnceCell<IrqLock<Ps2>> = OnceCell::new();
+
+fn wait_for(status: u8, set: bool) -> Result<(), OsError> {
+    for _ in 0..WAIT_LOOPS {
+        if (unsafe { io_in8(COMMAND_PORT) } & status != 0) == set {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    make_error!(OsErrorCode::Ps2Timeout)
+}
+
+fn write_command(command: u8) -> Result<(), OsError> {
+    wait_for(STATUS_INPUT_FULL, false)?;
+    unsafe {
+        io_out8(COMMAND_PORT, command);
+    }
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), OsError> {
+    wait_for(STATUS_INPUT_FULL, false)?;
+    unsafe {
+        io_out8(DATA_PORT, data);
+    }
+    Ok(())
+}
+
+fn read_data() -> Result<u8, OsError> {
+    wait_for(STATUS_OUTPUT_FULL, true)?;
+    Ok(unsafe { io_in8(DATA_PORT) })
+}
+
+fn flush_output() {
+    for _ in 0..16 {
+        if unsafe { io_in8(COMMAND_PORT) } & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        unsafe {
+            io_in8(DATA_PORT);
+        }
+    }
+}
+
+// A command with a response of one byte.
+fn query(command: u8) -> Result<u8, OsError> {
+    write_command(command)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), OsError> {
+    write_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+// Sends a byte to the keyboard, or to the mouse if `mouse`, and waits for
+// the acknowledgement.
+fn send_to_device(mouse: bool, data: u8) -> Result<(), OsError> {
+    for _ in 0..3 {
+        if mouse {
+            write_command(COMMAND_WRITE_MOUSE)?;
+        }
+        write_data(data)?;
+        match read_data()? {
+            DEVICE_ACK => return Ok(()),
+            DEVICE_RESEND => continue,
+            _ => break,
+        }
+    }
+    make_error!(OsErrorCode::Ps2Timeout)
+}
+
+fn reset_device(mouse: bool) -> Result<(), OsError> {
+    send_to_device(mouse, DEVICE_RESET)?;
+    if read_data()? != DEVICE_SELF_TEST_PASSED {
+        return make_error!(OsErrorCode::Ps2Timeout);
+    }
+    // A mouse sends its ID as well.
+    if mouse {
+        read_data()?;
+    }
+    Ok(())
+}
+
+// Asks for the IntelliMouse protocol, which adds the wheel to the packets.
+fn enable_mouse_wheel() -> Result<bool, OsError> {
+    for &rate in &[200, 100, 80] {
+        send_to_device(true, MOUSE_SET_SAMPLE_RATE)?;
+        send_to_device(true, rate)?;
+    }
+    send_to_device(true, DEVICE_GET_ID)?;
+    Ok(read_data()? == MOUSE_ID_WHEEL)
+}
+
+fn setup_device(mouse: bool) -> Result<bool, OsError> {
+    reset_device(mouse)?;
+    let wheel = if mouse { enable_mouse_wheel()? } else { false };
+    send_to_device(mouse, DEVICE_ENABLE_SCANNING)?;
+    Ok(wheel)
+}
+
+extern "x86-interrupt" fn interrupt_handler_ps2_keyboard(
+    stack_frame: ExceptionStackFrame,
+) {
+    let _gs = KernelGsGuard::new(&stack_frame);
+    receive(false);
+}
+
+extern "x86-interrupt" fn interrupt_handler_ps2_mouse(
+    stack_frame: ExceptionStackFrame,
+) {
+    let _gs = KernelGsGuard::new(&stack_frame);
+    receive(true);
+}
+
+fn receive(mouse: bool) {
+    // The controller sends nothing more until the byte is read.
+    let data = unsafe { io_in8(DATA_PORT) };
+    if let Some(ps2) = PS2.get() {
+        let task = ps2.lock().task;
+        // Lost if the queue is full. From the tick the mouse decoder sees
+        // the gap and drops the rest of the packet.
+        let tick = current_tick();
+        let _ = send_message(task, Message::Ps2Data { data, mouse, tick });
+    }
+    unsafe {
+        notify_end_of_interrupt();
+    }
+    preempt_if_needed();
+}
+
+fn set_handler(vector: InterruptVector, handler: u64) {
+    unsafe {
+        set_idt_entry(
+            &mut IDT[vector as usize],
+            make_id_attr(GateDescriptorType::InterruptGate, 0),
+            handler,
+            get_cs(),
+        );
+        load_idt((size_of_val(&IDT) - 1) as u16, &IDT as *const _ as u64);
+    }
+}
+
+// Sets up the i8042 controller and the keyboard and mouse behind it. Either
+// device may be missing.
+pub fn setup_ps2(
+    task: TaskId,
+    mouse_observer: extern "C" fn(MouseEvent),
+) -> Result<(), OsError> {
+    write_command(COMMAND_DISABLE_KEYBOARD)?;
+    write_command(COMMAND_DISABLE_MOUSE)?;
+    flush_output();
+
+    let config = query(COMMAND_READ_CONFIG)?
+        & !(CONFIG_KEYBOARD_INTERRUPT | CONFIG_MOUSE_INTERRUPT);
+    write_config(config)?;
+    if query(COMMAND_SELF_TEST)? != SELF_TEST_PASSED {
+        return make_error!(OsErrorCode::NoPs2Controller);
+    }
+    // The self test may reset the configuration.
+    write_config(config)?;
+
+    let has_keyboard = query(COMMAND_TEST_KEYBOARD)? == PORT_TEST_PASSED;
+    let has_mouse = query(COMMAND_TEST_MOUSE)? == PORT_TEST_PASSED;
+    if has_keyboard {
+        write_command(COMMAND_ENABLE_KEYBOARD)?;
+    }
+    if has_mouse {
+        write_command(COMMAND_ENABLE_MOUSE)?;
+    }
+    let keyboard = has_keyboard && setup_device(false).is_ok();
+    // Whether it has a wheel if there is one.
+    let mouse = if has_mouse { setup_device(true).ok() } else { None };
+    log!(Info, "PS/2: keyboard {}, mouse {}, wheel {}",
+         keyboard, mouse.is_some(), mouse == Some(true));
+
+    let set = if config & CONFIG_TRANSLATION != 0 {
+        ScanCodeSet::Set1
+    } else {
+        ScanCodeSet::Set2
+    };
+    let ps2 = Ps2 {
+        task,
+        keyboard: KeyboardDecoder {
+            set,
+            extended: false,
+            release: false,
+            skip: 0,
+            modifier: 0,
+            held: [0; 4],
+        },
+        mouse: MouseDecoder {
+            packet: [0; 4],
+            received: 0,
+            packet_size: if mouse == Some(true) { 4 } else { 3 },
+            last_tick: 0,
+        },
+        mouse_observer,
+    };
+    PS2.set(IrqLock::new(ps2))?;
+
+    let apic_id = current_cpu().apic_id();
+    let mut config = config;
+    if keyboard {
+        set_handler(
+            InterruptVector::Ps2Keyboard,
+            interrupt_handler_ps2_keyboard as usize as u64,
+        );
+        route_isa_irq(
+            KEYBOARD_IRQ, InterruptVector::Ps2Keyboard as u8, apic_id)?;
+        config |= CONFIG_KEYBOARD_INTERRUPT;
+        config &= !CONFIG_KEYBOARD_CLOCK_DISABLED;
+    }
+    if mouse.is_some() {
+        set_handler(
+            InterruptVector::Ps2Mouse,
+            interrupt_handler_ps2_mouse as usize as u64,
+        );
+        route_isa_irq(MOUSE_IRQ, InterruptVector::Ps2Mouse as u8, apic_id)?;
+        config |= CONFIG_MOUSE_INTERRUPT;
+        config &= !CONFIG_MOUSE_CLOCK_DISABLED;
+    }
+    flush_output();
+    write_config(config)
+}
+
+pub fn on_ps2_data(data: u8, mouse: bool, tick: u64) {
+    let mut ps2 = match PS2.get() {
+        Some(ps2) => ps2.lock(),
+        None => return,
+    };
+    if mouse {
+        let event = ps2.mouse.feed(data, tick);
+        let observer = ps2.mouse_observer;
+        drop(ps2);
+        if let Some(event) = event {
+            observer(event);
+        }
+    } else {
+        let key = ps2.keyboard.feed(data);
+        drop(ps2);
+        if let Some((keycode, modifier, press)) = key {
+            on_key_event(keycode, modifier, press);
+        }
+    }
+}
@@ -48,6 +48,13 @@ impl<'a, T, const N: usize> ArrayQueue<'a, T, N> {
         Ok(value)
     }
 
+    pub fn clear(&mut self) {
+        self.read_pos = 0;
+        self.write_pos = 0;
+        self.count = 0;
+    }
+
+    #[allow(dead_code)]
     pub fn count(&mut self) -> usize {
         self.count
     }
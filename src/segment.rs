@@ -1,16 +1,77 @@
-use crate::x86_descriptor::SegmentDescriptorType;
+use crate::error::*;
+use crate::kernel_stack::allocate_kernel_stack;
+use crate::smp::current_cpu;
+use crate::task::user_mode_kernel_rsp;
+use crate::x86_descriptor::{GateDescriptorType, SegmentDescriptorType};
+
+use alloc::boxed::Box;
+use core::mem::size_of;
 
 extern "C" {
     fn load_gdt(limit: u16, offset: u64);
+    fn load_tr(selector: u16);
+    fn set_ds_all(value: u16);
+    fn set_cs_ss(cs: u16, ss: u16);
+    fn jump_to_user_mode(
+        rip: u64, rsp: u64, cs: u16, ss: u16, kernel_rsp: *mut u64) -> i32;
+    fn return_from_user_mode(kernel_rsp: u64, code: i32) -> !;
 }
 
-static mut GDT: [SegmentDescriptor; 3] = {
+// syscall loads CS from STAR[47:32] and SS from the next entry, while
+// sysret loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16,
+// so the user data segment has to come right before the user code segment.
+pub const KERNEL_CS: u16 = 1 << 3;
+pub const KERNEL_SS: u16 = 2 << 3;
+pub const USER_SS: u16 = 3 << 3 | 3;
+pub const USER_CS: u16 = 4 << 3 | 3;
+pub const TSS_SELECTOR: u16 = 5 << 3;
+
+// Interrupt stack table indices (1 to 7).
+pub const IST_DOUBLE_FAULT: u16 = 1;
+pub const IST_NMI: u16 = 2;
+pub const IST_MACHINE_CHECK: u16 = 3;
+const IST_STACK_PAGES: usize = 4;
+const KERNEL_STACK_PAGES: usize = 16;
+
+const GDT_ENTRIES: usize = 7;
+
+static mut GDT: [SegmentDescriptor; GDT_ENTRIES] = {
     let empty_desc = SegmentDescriptor {
         data: 0,
     };
-    [empty_desc; 3]
+    [empty_desc; GDT_ENTRIES]
+};
+
+#[repr(C, packed)]
+struct TaskStateSegment {
+    reserved1: u32,
+    rsp: [u64; 3],
+    reserved2: u64,
+    ist: [u64; 7],
+    reserved3: u64,
+    reserved4: u16,
+    io_map_base: u16,
+}
+
+const EMPTY_TSS: TaskStateSegment = TaskStateSegment {
+    reserved1: 0,
+    rsp: [0; 3],
+    reserved2: 0,
+    ist: [0; 7],
+    reserved3: 0,
+    reserved4: 0,
+    io_map_base: size_of::<TaskStateSegment>() as u16,
 };
 
+// The bootstrap processor's TSS.
+static mut TSS: TaskStateSegment = EMPTY_TSS;
+
+// The GDT and TSS of an application processor.
+pub struct CpuSegments {
+    gdt: [SegmentDescriptor; GDT_ENTRIES],
+    tss: TaskStateSegment,
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 struct SegmentDescriptorFields {
@@ -46,7 +107,7 @@ fn set_code_segment(
     unsafe {
         desc.fields.others1 |= (desc_type as u8) & 0b00001111;
         desc.fields.others1 |= 1 << 4; // system_segment
-        desc.fields.others1 |= (desc_privilege_level & 0b00000011) >> 5;
+        desc.fields.others1 |= (desc_privilege_level & 0b00000011) << 5;
         desc.fields.others1 |= 1 << 7; // present
 
         desc.fields.others2 |= ((limit >> 16) & 0x0000000f) as u8;
@@ -73,15 +134,145 @@ fn set_data_segment(
     }
 }
 
-pub fn setup_segments() {
+// A system segment descriptor occupies two entries in long mode.
+fn set_system_segment(
+    desc: &mut [SegmentDescriptor],
+    desc_type: GateDescriptorType,
+    desc_privilege_level: u8,
+    base: u64,
+    limit: u32,
+) {
+    desc[0].data = 0;
+    desc[1].data = base >> 32;
+
+    desc[0].fields.base_low = (base & 0x0000ffff) as u16;
+    desc[0].fields.base_middle = ((base >> 16) & 0x000000ff) as u8;
+    desc[0].fields.base_high = ((base >> 24) & 0x000000ff) as u8;
+
+    desc[0].fields.limit_low = (limit & 0x0000ffff) as u16;
+
+    unsafe {
+        desc[0].fields.others1 |= (desc_type as u8) & 0b00001111;
+        desc[0].fields.others1 |= (desc_privilege_level & 0b00000011) << 5;
+        desc[0].fields.others1 |= 1 << 7; // present
+
+        desc[0].fields.others2 |= ((limit >> 16) & 0x0000000f) as u8;
+    }
+}
+
+fn set_flat_segments(gdt: &mut [SegmentDescriptor; GDT_ENTRIES]) {
     use SegmentDescriptorType::*;
+    gdt[0].data = 0;
+    set_code_segment(&mut gdt[1], ExecuteRead, 0, 0, 0x000fffff);
+    set_data_segment(&mut gdt[2], ReadWrite, 0, 0, 0x000fffff);
+    set_data_segment(&mut gdt[3], ReadWrite, 3, 0, 0x000fffff);
+    set_code_segment(&mut gdt[4], ExecuteRead, 3, 0, 0x000fffff);
+}
+
+fn set_tss_segment(
+    gdt: &mut [SegmentDescriptor; GDT_ENTRIES],
+    tss: &TaskStateSegment,
+) {
+    set_system_segment(
+        &mut gdt[(TSS_SELECTOR >> 3) as usize..],
+        GateDescriptorType::TssAvailable,
+        0,
+        tss as *const _ as u64,
+        (size_of::<TaskStateSegment>() - 1) as u32,
+    );
+}
+
+fn allocate_tss_stacks(tss: &mut TaskStateSegment) -> Result<(), OsError> {
+    for &index in &[IST_DOUBLE_FAULT, IST_NMI, IST_MACHINE_CHECK] {
+        let stack = allocate_kernel_stack(IST_STACK_PAGES)?;
+        tss.ist[index as usize - 1] = stack.top();
+    }
+    tss.rsp[0] = allocate_kernel_stack(KERNEL_STACK_PAGES)?.top();
+    Ok(())
+}
+
+pub fn setup_segments() {
     unsafe {
-        GDT[0].data = 0;
-        set_code_segment(&mut GDT[1], ExecuteRead, 0, 0, 0x000fffff);
-        set_data_segment(&mut GDT[2], ReadWrite, 0, 0, 0x000fffff);
+        set_flat_segments(&mut GDT);
         load_gdt(
             (core::mem::size_of_val(&GDT) - 1) as u16,
             &GDT as *const _ as u64
         );
     }
 }
+
+// Stack used when an interrupt or a system call comes from ring 3.
+pub fn set_rsp0(rsp: u64) {
+    unsafe {
+        TSS.rsp[0] = rsp;
+    }
+    current_cpu().set_syscall_stack(rsp);
+}
+
+pub fn set_ist(index: u16, rsp: u64) -> Result<(), OsError> {
+    if !(1..=7).contains(&index) {
+        return make_error!(OsErrorCode::IndexOutOfRange);
+    }
+    unsafe {
+        TSS.ist[index as usize - 1] = rsp;
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn allocate_ist(index: u16, num_pages: usize) -> Result<(), OsError> {
+    let stack = allocate_kernel_stack(num_pages)?;
+    set_ist(index, stack.top())
+}
+
+// Needs the frame allocator for the interrupt stacks.
+pub fn setup_tss() -> Result<(), OsError> {
+    unsafe {
+        allocate_tss_stacks(&mut TSS)?;
+        set_tss_segment(&mut GDT, &TSS);
+        load_tr(TSS_SELECTOR);
+    }
+    Ok(())
+}
+
+// Called by the bootstrap processor for each application processor, which
+// then loads the result with load_cpu_segments.
+pub fn allocate_cpu_segments() -> Result<&'static mut CpuSegments, OsError> {
+    let segments = Box::leak(Box::new(CpuSegments {
+        gdt: [SegmentDescriptor { data: 0 }; GDT_ENTRIES],
+        tss: EMPTY_TSS,
+    }));
+    allocate_tss_stacks(&mut segments.tss)?;
+    set_flat_segments(&mut segments.gdt);
+    set_tss_segment(&mut segments.gdt, &segments.tss);
+    Ok(segments)
+}
+
+pub fn load_cpu_segments(segments: &'static mut CpuSegments) {
+    unsafe {
+        load_gdt(
+            (core::mem::size_of_val(&segments.gdt) - 1) as u16,
+            &segments.gdt as *const _ as u64
+        );
+        set_ds_all(0);
+        set_cs_ss(KERNEL_CS, KERNEL_SS);
+        load_tr(TSS_SELECTOR);
+    }
+}
+
+// The pages around `entry` and `stack_top` must be mapped with USER, and
+// RSP0 in the TSS must point to a kernel stack before calling this.
+// Returns the code passed to exit_user_mode. The kernel stack of the caller
+// is saved in the current task, so that each task may run a program.
+pub fn call_user_mode(entry: u64, stack_top: u64) -> i32 {
+    unsafe {
+        jump_to_user_mode(
+            entry, stack_top, USER_CS, USER_SS, user_mode_kernel_rsp())
+    }
+}
+
+pub fn exit_user_mode(code: i32) -> ! {
+    unsafe {
+        return_from_user_mode(*user_mode_kernel_rsp(), code);
+    }
+}
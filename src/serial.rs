@@ -0,0 +1,86 @@
+use crate::error::*;
+use crate::sync::{IrqLock, OnceCell};
+
+extern "C" {
+    fn io_out8(addr: u16, data: u8);
+    fn io_in8(addr: u16) -> u8;
+}
+
+const COM1: u16 = 0x3f8;
+
+// 16550 UART registers, offsets from the base port.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_CONTROL_DLAB: u8 = 0x80;
+const LINE_CONTROL_8N1: u8 = 0x03;
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+const MODEM_CONTROL_NORMAL: u8 = 0x0f;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+// Log output goes here when there is no frame buffer to draw on.
+pub static SERIAL: OnceCell<IrqLock<SerialPort>> = OnceCell::new();
+
+pub struct SerialPort {
+    port: u16,
+}
+
+impl SerialPort {
+    // 115200 baud, 8N1, no interrupts.
+    fn new(port: u16) -> Result<Self, OsError> {
+        unsafe {
+            io_out8(port + INTERRUPT_ENABLE, 0);
+            io_out8(port + LINE_CONTROL, LINE_CONTROL_DLAB);
+            io_out8(port + DATA, 1);
+            io_out8(port + INTERRUPT_ENABLE, 0);
+            io_out8(port + LINE_CONTROL, LINE_CONTROL_8N1);
+            io_out8(port + FIFO_CONTROL, 0xc7);
+
+            // Nothing answers if there is no UART.
+            io_out8(port + MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+            io_out8(port + DATA, 0xae);
+            if io_in8(port + DATA) != 0xae {
+                return make_error!(OsErrorCode::NoSerialPort);
+            }
+            io_out8(port + MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+        }
+        Ok(Self { port })
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            while io_in8(self.port + LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY
+                == 0
+            {
+                core::hint::spin_loop();
+            }
+            io_out8(self.port + DATA, byte);
+        }
+    }
+
+    pub fn put_string<A: AsRef<str>>(&mut self, s: A) {
+        for &byte in s.as_ref().as_bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+    }
+}
+
+// For handlers which may have interrupted the holder of the lock on this
+// CPU. The output may interleave with that of other writers.
+pub fn put_string_unlocked<A: AsRef<str>>(s: A) {
+    if SERIAL.get().is_some() {
+        SerialPort { port: COM1 }.put_string(s);
+    }
+}
+
+pub fn setup_serial() -> Result<(), OsError> {
+    SERIAL.set(IrqLock::new(SerialPort::new(COM1)?))?;
+    Ok(())
+}
@@ -0,0 +1,462 @@
+use crate::acpi::local_apic_ids;
+use crate::apic::{
+    enable_lapic, lapic_id, send_init, send_ipi, send_startup, set_logical_id,
+    IpiDestination, MAX_LOGICAL_APICS,
+};
+use crate::error::*;
+use crate::interrupt::{
+    ExceptionStackFrame, InterruptVector, KernelGsGuard, get_cs, load_idt,
+    make_id_attr, notify_end_of_interrupt, set_idt_entry, IDT,
+};
+use crate::kernel_stack::allocate_kernel_stack;
+use crate::logger::*;
+use crate::memory_manager::{BitmapMemoryManager, FrameId, BYTE_PER_FRAME};
+use crate::paging::{
+    flush_tlb, phys_to_virt, PageFlags, PageTableManager, PAGE_SIZE_4K,
+};
+use crate::segment::{allocate_cpu_segments, load_cpu_segments, CpuSegments};
+use crate::sync::{disable_interrupts, restore_interrupts, OnceCell, SpinLock};
+use crate::task::{preempt_if_needed, sleep};
+use crate::timer::{current_tick, TIMER_FREQUENCY};
+use crate::x86_descriptor::GateDescriptorType;
+
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    mem::size_of_val,
+    ptr::copy_nonoverlapping,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+extern "C" {
+    fn write_msr(msr: u32, value: u64);
+
+    // Defined in asmfunc.rs.
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_params: u8;
+    static ap_trampoline_end: u8;
+}
+
+const MSR_GS_BASE: u32 = 0xc000_0101;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+// Can only be set in long mode.
+const CR4_PCIDE: u64 = 1 << 17;
+
+pub const MAX_CPUS: usize = 16;
+const AP_STACK_PAGES: usize = 16;
+const AP_STARTUP_TIMEOUT_MS: u64 = 200;
+
+// The startup IPI can only point to a page in the first megabyte.
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+
+// Selector of the 64-bit code segment in the trampoline's GDT.
+const TRAMPOLINE_CODE_SELECTOR: u16 = 1 << 3;
+
+// Read by the code in asmfunc.rs at fixed offsets.
+#[repr(C, packed)]
+struct TrampolineParams {
+    gdt_limit: u16,
+    gdt_base: u32,
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    cr0: u32,
+    cr3: u32,
+    cr4: u32,
+    stack_top: u64,
+    cpu: u64,
+    segments: u64,
+    entry: u64,
+}
+
+// Per-CPU data, reachable through the GS base in the kernel. User programs
+// have their own GS base, which is kept in IA32_KERNEL_GS_BASE while the
+// kernel runs; every entry from and return to ring 3 swaps the two.
+#[repr(C)]
+pub struct Cpu {
+    // Must be first: current_cpu reads it from gs:0.
+    self_ptr: u64,
+    // Read by syscall_entry from gs:8 and gs:16, so these must follow.
+    syscall_stack: AtomicU64,
+    // Where syscall_entry keeps the user RSP while switching stacks.
+    syscall_user_rsp: AtomicU64,
+    index: usize,
+    apic_id: u32,
+    online: AtomicBool,
+}
+
+impl Cpu {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    // The stack syscall_entry switches to, RSP0 of the running task.
+    pub fn set_syscall_stack(&self, rsp: u64) {
+        self.syscall_stack.store(rsp, Ordering::Relaxed);
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CPU: OnceCell<&'static Cpu> = OnceCell::new();
+static CPUS: [OnceCell<&'static Cpu>; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+static NUM_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+static TRAMPOLINE: OnceCell<u64> = OnceCell::new();
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum CallTarget {
+    Cpu(usize),
+    AllExcludingSelf,
+}
+
+// One cross-CPU call is in flight at a time. Each target clears its bit in
+// CALL_TARGETS after running the function.
+static CALL_LOCK: SpinLock<()> = SpinLock::new(());
+static CALL_FUNCTION: AtomicU64 = AtomicU64::new(0);
+static CALL_ARG: AtomicU64 = AtomicU64::new(0);
+static CALL_TARGETS: AtomicU64 = AtomicU64::new(0);
+
+struct TlbRange {
+    virt: u64,
+    size: u64,
+}
+
+fn new_cpu(index: usize, apic_id: u32) -> &'static Cpu {
+    let cpu = Box::leak(Box::new(Cpu {
+        self_ptr: 0,
+        syscall_stack: AtomicU64::new(0),
+        syscall_user_rsp: AtomicU64::new(0),
+        index,
+        apic_id,
+        online: AtomicBool::new(false),
+    }));
+    cpu.self_ptr = cpu as *const Cpu as u64;
+    cpu
+}
+
+// User programs start with a GS base of 0.
+fn set_current_cpu(cpu: &'static Cpu) {
+    unsafe {
+        write_msr(MSR_GS_BASE, cpu.self_ptr);
+        write_msr(MSR_KERNEL_GS_BASE, 0);
+    }
+}
+
+pub fn current_cpu() -> &'static Cpu {
+    let cpu: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly));
+        &*(cpu as *const Cpu)
+    }
+}
+
+#[allow(dead_code)]
+pub fn cpu(index: usize) -> Option<&'static Cpu> {
+    CPUS.get(index)?.get().copied()
+}
+
+pub fn num_cpus() -> usize {
+    NUM_CPUS.load(Ordering::Acquire)
+}
+
+fn online_cpu_mask() -> u64 {
+    (1 << num_cpus()) - 1
+}
+
+// For MSI with lowest priority delivery to any online CPU.
+pub fn logical_destination_all() -> u8 {
+    (online_cpu_mask() & ((1 << MAX_LOGICAL_APICS) - 1)) as u8
+}
+
+// Runs the function of the call in flight if this CPU is a target. Also
+// called while waiting for CALL_LOCK, since the holder may be waiting for
+// this CPU with interrupts disabled.
+fn run_pending_call() {
+    let bit = 1 << current_cpu().index;
+    if CALL_TARGETS.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    let function: fn(u64) = unsafe {
+        core::mem::transmute(CALL_FUNCTION.load(Ordering::Acquire) as usize)
+    };
+    function(CALL_ARG.load(Ordering::Acquire));
+    CALL_TARGETS.fetch_and(!bit, Ordering::AcqRel);
+}
+
+extern "x86-interrupt" fn interrupt_handler_call_function(
+    stack_frame: ExceptionStackFrame,
+) {
+    let _gs = KernelGsGuard::new(&stack_frame);
+    run_pending_call();
+    unsafe {
+        notify_end_of_interrupt();
+    }
+}
+
+extern "x86-interrupt" fn interrupt_handler_reschedule(
+    stack_frame: ExceptionStackFrame,
+) {
+    let _gs = KernelGsGuard::new(&stack_frame);
+    unsafe {
+        notify_end_of_interrupt();
+    }
+    preempt_if_needed();
+}
+
+// Makes the BSP, which runs the tasks, switch to a task woken on this CPU.
+pub fn reschedule_bsp() {
+    let apic_id = CPUS[0].get().unwrap().apic_id;
+    send_ipi(IpiDestination::Apic(apic_id), InterruptVector::Reschedule as u8);
+}
+
+// Runs `function(arg)` on the target CPUs and waits until all of them have
+// returned. `function` runs in an interrupt handler.
+pub fn call_function(target: CallTarget, function: fn(u64), arg: u64) {
+    let interrupts = disable_interrupts();
+    let self_bit = 1 << current_cpu().index;
+    let targets = match target {
+        CallTarget::Cpu(index) => (1 << index) & online_cpu_mask() & !self_bit,
+        CallTarget::AllExcludingSelf => online_cpu_mask() & !self_bit,
+    };
+    if targets == 0 {
+        restore_interrupts(interrupts);
+        return;
+    }
+
+    let guard = loop {
+        if let Some(guard) = CALL_LOCK.try_lock() {
+            break guard;
+        }
+        run_pending_call();
+        spin_loop();
+    };
+    CALL_FUNCTION.store(function as usize as u64, Ordering::Release);
+    CALL_ARG.store(arg, Ordering::Release);
+    CALL_TARGETS.store(targets, Ordering::Release);
+
+    let vector = InterruptVector::CallFunction as u8;
+    match target {
+        CallTarget::Cpu(index) => {
+            let apic_id = CPUS[index].get().unwrap().apic_id;
+            send_ipi(IpiDestination::Apic(apic_id), vector);
+        },
+        CallTarget::AllExcludingSelf => {
+            send_ipi(IpiDestination::AllExcludingSelf, vector);
+        },
+    }
+    while CALL_TARGETS.load(Ordering::Acquire) != 0 {
+        spin_loop();
+    }
+
+    drop(guard);
+    restore_interrupts(interrupts);
+}
+
+fn flush_tlb_range(arg: u64) {
+    let range = unsafe {
+        &*(arg as *const TlbRange)
+    };
+    flush_tlb(range.virt, range.size);
+}
+
+// Invalidates the range on the other CPUs after a page table change. The
+// caller flushes its own TLB.
+pub fn shootdown_tlb(virt: u64, size: u64) {
+    if num_cpus() <= 1 {
+        return;
+    }
+    // Stays on this stack until every target has returned.
+    let range = TlbRange { virt, size };
+    call_function(
+        CallTarget::AllExcludingSelf,
+        flush_tlb_range,
+        &range as *const TlbRange as u64,
+    );
+}
+
+// Sets up the per-CPU data of the bootstrap processor.
+pub fn init_bsp() -> Result<(), OsError> {
+    let cpu = new_cpu(0, lapic_id());
+    cpu.online.store(true, Ordering::Release);
+    CPUS[0].set(cpu)?;
+    set_current_cpu(cpu);
+    set_logical_id(0);
+    NUM_CPUS.store(1, Ordering::Release);
+
+    unsafe {
+        let cs = get_cs();
+        set_idt_entry(
+            &mut IDT[InterruptVector::CallFunction as usize],
+            make_id_attr(GateDescriptorType::InterruptGate, 0),
+            interrupt_handler_call_function as usize as u64,
+            cs,
+        );
+        set_idt_entry(
+            &mut IDT[InterruptVector::Reschedule as usize],
+            make_id_attr(GateDescriptorType::InterruptGate, 0),
+            interrupt_handler_reschedule as usize as u64,
+            cs,
+        );
+        load_idt((size_of_val(&IDT) - 1) as u16, &IDT as *const _ as u64);
+    }
+    Ok(())
+}
+
+// Must be called before anything else allocates frames, while the lowest
+// ones are still free.
+pub fn reserve_trampoline(
+    memory_manager: &mut BitmapMemoryManager,
+) -> Result<(), OsError> {
+    let frame = memory_manager.allocate(1)?;
+    let phys = (frame.id() * BYTE_PER_FRAME) as u64;
+    if phys + PAGE_SIZE_4K > TRAMPOLINE_LIMIT {
+        memory_manager.free(FrameId::new(frame.id()), 1)?;
+        return make_error!(OsErrorCode::NoEnoughMemory);
+    }
+    TRAMPOLINE.set(phys)?;
+    Ok(())
+}
+
+fn trampoline_offset(label: &u8) -> u64 {
+    unsafe {
+        label as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64
+    }
+}
+
+fn trampoline_params(trampoline: u64) -> *mut TrampolineParams {
+    let offset = unsafe {
+        trampoline_offset(&ap_trampoline_params)
+    };
+    (phys_to_virt(trampoline) + offset) as *mut TrampolineParams
+}
+
+fn install_trampoline(trampoline: u64) {
+    let cr0: u64;
+    let cr4: u64;
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0);
+        asm!("mov {}, cr4", out(reg) cr4);
+
+        let size = trampoline_offset(&ap_trampoline_end) as usize;
+        copy_nonoverlapping(
+            &ap_trampoline_start as *const u8,
+            phys_to_virt(trampoline) as *mut u8,
+            size,
+        );
+
+        let params = trampoline_params(trampoline);
+        (*params).gdt_limit = 3 * 8 - 1;
+        (*params).gdt_base =
+            (trampoline + trampoline_offset(&ap_trampoline_gdt)) as u32;
+        (*params).long_mode_offset =
+            (trampoline + trampoline_offset(&ap_trampoline_long_mode)) as u32;
+        (*params).long_mode_selector = TRAMPOLINE_CODE_SELECTOR;
+        // The same paging and FPU settings as the bootstrap processor. The
+        // kernel page table lies below 4 GiB.
+        (*params).cr0 = cr0 as u32;
+        (*params).cr3 = PageTableManager::current().pml4() as u32;
+        (*params).cr4 = (cr4 & !CR4_PCIDE) as u32;
+        (*params).entry = ap_main as usize as u64;
+    }
+}
+
+fn start_cpu(trampoline: u64, apic_id: u32) -> Result<(), OsError> {
+    let index = NUM_CPUS.load(Ordering::Acquire);
+    if index >= MAX_CPUS {
+        return make_error!(OsErrorCode::Full);
+    }
+
+    let cpu = new_cpu(index, apic_id);
+    let segments = allocate_cpu_segments()?;
+    let stack = allocate_kernel_stack(AP_STACK_PAGES)?;
+    unsafe {
+        let params = trampoline_params(trampoline);
+        (*params).stack_top = stack.top();
+        (*params).cpu = cpu as *const Cpu as u64;
+        (*params).segments = segments as *mut CpuSegments as u64;
+    }
+
+    // INIT, then up to two startup IPIs as in the MultiProcessor
+    // Specification.
+    send_init(apic_id);
+    // At least 10 ms whatever the phase of the timer is.
+    sleep(20);
+    for _ in 0..2 {
+        send_startup(apic_id, (trampoline / PAGE_SIZE_4K) as u8);
+        sleep(1);
+        if cpu.online.load(Ordering::Acquire) {
+            break;
+        }
+    }
+
+    let deadline =
+        current_tick() + AP_STARTUP_TIMEOUT_MS * TIMER_FREQUENCY / 1000;
+    while !cpu.online.load(Ordering::Acquire) {
+        if current_tick() >= deadline {
+            // Stop it before the trampoline is reused for the next one.
+            send_init(apic_id);
+            return make_error!(OsErrorCode::CpuStartupTimeout);
+        }
+        sleep(1);
+    }
+
+    CPUS[index].set(cpu)?;
+    NUM_CPUS.store(index + 1, Ordering::Release);
+    Ok(())
+}
+
+// Starts the processors listed in the MADT one at a time. Returns the number
+// of CPUs online, including the bootstrap processor.
+pub fn start_application_processors() -> Result<usize, OsError> {
+    let trampoline = match TRAMPOLINE.get() {
+        Some(&trampoline) => trampoline,
+        None => return make_error!(OsErrorCode::NoEnoughMemory),
+    };
+    install_trampoline(trampoline);
+
+    // The trampoline keeps running at its physical address after enabling
+    // paging.
+    let mut page_table = PageTableManager::current();
+    page_table.map(trampoline, trampoline, PAGE_SIZE_4K, PageFlags::empty())?;
+
+    let bsp_apic_id = lapic_id();
+    for apic_id in local_apic_ids()? {
+        if apic_id == bsp_apic_id {
+            continue;
+        }
+        if let Err(err) = start_cpu(trampoline, apic_id) {
+            log!(Error, "CPU (APIC ID {}): Error ({:?})", apic_id, err.code);
+        }
+    }
+
+    page_table.unmap(trampoline, PAGE_SIZE_4K)?;
+    Ok(num_cpus())
+}
+
+extern "C" fn ap_main(cpu: &'static Cpu, segments: &'static mut CpuSegments) -> ! {
+    load_cpu_segments(segments);
+    set_current_cpu(cpu);
+    unsafe {
+        load_idt((size_of_val(&IDT) - 1) as u16, &IDT as *const _ as u64);
+    }
+    enable_lapic(InterruptVector::Spurious as u8);
+    set_logical_id(cpu.index);
+
+    cpu.online.store(true, Ordering::Release);
+    log!(Info, "CPU {} (APIC ID {}) online", cpu.index, cpu.apic_id);
+
+    restore_interrupts(true);
+    loop {
+        unsafe {
+            asm!("hlt");
+        }
+    }
+}
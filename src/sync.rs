@@ -0,0 +1,209 @@
+use crate::error::*;
+
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    hint::spin_loop,
+    mem::{ManuallyDrop, MaybeUninit},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+// Returns whether interrupts were enabled, to be passed to
+// restore_interrupts.
+pub fn disable_interrupts() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe {
+            asm!("sti");
+        }
+    }
+}
+
+// Must not be taken by interrupt handlers; use IrqLock for data they share.
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe {
+            &*self.lock.data.get()
+        }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut *self.lock.data.get()
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+// A spinlock which keeps interrupts disabled while it is held, so that
+// interrupt handlers on the same CPU can take it too.
+pub struct IrqLock<T: ?Sized> {
+    inner: SpinLock<T>,
+}
+
+impl<T> IrqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: SpinLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqLock<T> {
+    pub fn lock(&self) -> IrqLockGuard<'_, T> {
+        let interrupts = disable_interrupts();
+        IrqLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqLockGuard<'_, T>> {
+        let interrupts = disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqLockGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts,
+            }),
+            None => {
+                restore_interrupts(interrupts);
+                None
+            },
+        }
+    }
+}
+
+pub struct IrqLockGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+    interrupts: bool,
+}
+
+impl<T: ?Sized> Deref for IrqLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // The lock is released before interrupts are enabled again.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        restore_interrupts(self.interrupts);
+    }
+}
+
+const ONCE_UNINIT: u8 = 0;
+const ONCE_INITIALIZING: u8 = 1;
+const ONCE_READY: u8 = 2;
+
+// A global which is set once during boot and only read afterwards. The
+// value is never dropped.
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn set(&self, value: T) -> Result<&T, OsError> {
+        let result = self.state.compare_exchange(
+            ONCE_UNINIT, ONCE_INITIALIZING, Ordering::Acquire, Ordering::Relaxed);
+        if result.is_err() {
+            return make_error!(OsErrorCode::AlreadyInitialized);
+        }
+
+        let value = unsafe {
+            (*self.value.get()).write(value)
+        };
+        self.state.store(ONCE_READY, Ordering::Release);
+        Ok(value)
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != ONCE_READY {
+            return None;
+        }
+        unsafe {
+            Some((*self.value.get()).assume_init_ref())
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::error::*;
+use crate::file::FileDescriptorTable;
+use crate::logger::*;
+use crate::memory_manager::{memory_manager, FrameId, BYTE_PER_FRAME};
+use crate::paging::{
+    PageTableManager, PageFlags, PAGE_SIZE_4K,
+    USER_SPACE_END, USER_HEAP_BASE, USER_HEAP_END,
+};
+use crate::queue::ArrayQueue;
+use crate::segment::{exit_user_mode, KERNEL_CS, USER_SS};
+use crate::sync::{IrqLock, OnceCell};
+use crate::task::{set_user_heap_next, user_heap_next};
+use crate::timer::{current_tick, TIMER_FREQUENCY};
+
+use core::{mem::size_of, ptr::write_bytes, str};
+
+extern "C" {
+    fn read_msr(msr: u32) -> u64;
+    fn write_msr(msr: u32, value: u64);
+    fn syscall_entry();
+}
+
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_STAR: u32 = 0xc000_0081;
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_FMASK: u32 = 0xc000_0084;
+const EFER_SCE: u64 = 1 << 0;
+
+// TF, IF, DF and AC are cleared on entry.
+const SYSCALL_FLAG_MASK: u64 = 0x0004_0700;
+
+static FD_TABLE: IrqLock<FileDescriptorTable> =
+    IrqLock::new(FileDescriptorTable::new());
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, u32)]
+#[allow(dead_code)]
+pub enum AppEvent {
+    Empty,
+    MouseMove { dx: i32, dy: i32 },
+    // Sent when a button is pressed or released, with all buttons held.
+    MouseButton { buttons: u32 },
+    // Positive is away from the user.
+    MouseWheel { delta: i32 },
+    // `keycode` is the HID usage ID and `ascii` 0 if the key has no
+    // character.
+    KeyPush { keycode: u32, ascii: u32, modifier: u32 },
+    KeyRelease { keycode: u32, modifier: u32 },
+}
+
+static mut EVENT_QUEUE_DATA: [AppEvent; 32] = [AppEvent::Empty; 32];
+static EVENT_QUEUE: OnceCell<IrqLock<ArrayQueue<'static, AppEvent, 32>>> =
+    OnceCell::new();
+
+// `error` is 0 on success, otherwise the OsErrorCode plus 1.
+#[repr(C)]
+pub struct SyscallResult {
+    value: u64,
+    error: u64,
+}
+
+type SyscallArgs = [u64; 6];
+type SyscallHandler = fn(&SyscallArgs) -> Result<u64, OsError>;
+
+const SYSCALL_TABLE: [SyscallHandler; 8] = [
+    sys_write,        // 0: write(fd, buf, len)
+    sys_exit,         // 1: exit(code)
+    sys_get_time,     // 2: get_time() -> milliseconds
+    sys_alloc_memory, // 3: alloc_memory(size) -> address
+    sys_open,         // 4: open(path, path_len) -> fd
+    sys_read,         // 5: read(fd, buf, len) -> bytes read
+    sys_close,        // 6: close(fd)
+    sys_read_event,   // 7: read_event(events, max) -> events read
+];
+
+pub fn setup_syscall() {
+    let queue = unsafe {
+        ArrayQueue::new(&mut EVENT_QUEUE_DATA)
+    };
+    EVENT_QUEUE.set(IrqLock::new(queue)).unwrap();
+
+    unsafe {
+        write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_SCE);
+        // sysret loads CS and SS relative to the user data segment.
+        write_msr(
+            MSR_STAR,
+            (KERNEL_CS as u64) << 32 | ((USER_SS - 8) as u64) << 48,
+        );
+        write_msr(MSR_LSTAR, syscall_entry as usize as u64);
+        write_msr(MSR_FMASK, SYSCALL_FLAG_MASK);
+    }
+}
+
+// Called before a new program starts running.
+pub fn reset_process_state() {
+    set_user_heap_next(USER_HEAP_BASE);
+    *FD_TABLE.lock() = FileDescriptorTable::new();
+}
+
+pub fn push_event(event: AppEvent) {
+    // Nobody may be reading the events, so they are dropped when full.
+    if let Some(queue) = EVENT_QUEUE.get() {
+        let _ = queue.lock().push(event);
+    }
+}
+
+#[no_mangle]
+extern "C" fn handle_syscall(number: u64, args: &SyscallArgs) -> SyscallResult {
+    let result = match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(args),
+        None => make_error!(OsErrorCode::InvalidSyscall),
+    };
+    match result {
+        Ok(value) => SyscallResult { value, error: 0 },
+        Err(err) => {
+            log!(Debug, "syscall {}: {:?} at {}:{}",
+                 number, err.code, err.file, err.line);
+            SyscallResult { value: 0, error: err.code as u64 + 1 }
+        },
+    }
+}
+
+// Checks that [addr, addr + len) is mapped for user mode in the current
+// address space.
+fn check_user_range(addr: u64, len: u64, write: bool) -> Result<(), OsError> {
+    let end = match addr.checked_add(len) {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return make_error!(OsErrorCode::InvalidUserAddress),
+    };
+
+    let page_table = PageTableManager::current();
+    let mut page = addr & !(PAGE_SIZE_4K - 1);
+    while page < end {
+        match page_table.translate(page) {
+            Some(translation) if translation.flags.contains(PageFlags::USER) &&
+                (!write || translation.flags.contains(PageFlags::WRITABLE)) => {},
+            _ => return make_error!(OsErrorCode::InvalidUserAddress),
+        }
+        page += PAGE_SIZE_4K;
+    }
+    Ok(())
+}
+
+fn user_slice(addr: u64, len: u64) -> Result<&'static [u8], OsError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    check_user_range(addr, len, false)?;
+    unsafe {
+        Ok(core::slice::from_raw_parts(addr as *const u8, len as usize))
+    }
+}
+
+fn user_slice_mut(addr: u64, len: u64) -> Result<&'static mut [u8], OsError> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    check_user_range(addr, len, true)?;
+    unsafe {
+        Ok(core::slice::from_raw_parts_mut(addr as *mut u8, len as usize))
+    }
+}
+
+fn sys_write(args: &SyscallArgs) -> Result<u64, OsError> {
+    let buf = user_slice(args[1], args[2])?;
+    match args[0] {
+        1 | 2 => {
+            let s = match str::from_utf8(buf) {
+                Ok(s) => s,
+                Err(err) => unsafe {
+                    str::from_utf8_unchecked(&buf[..err.valid_up_to()])
+                },
+            };
+            print(s);
+            Ok(s.len() as u64)
+        },
+        _ => make_error!(OsErrorCode::InvalidFileDescriptor),
+    }
+}
+
+fn sys_exit(args: &SyscallArgs) -> Result<u64, OsError> {
+    exit_user_mode(args[0] as i32);
+}
+
+fn sys_get_time(_args: &SyscallArgs) -> Result<u64, OsError> {
+    Ok(current_tick() * 1000 / TIMER_FREQUENCY)
+}
+
+fn sys_alloc_memory(args: &SyscallArgs) -> Result<u64, OsError> {
+    let num_pages = match args[0].checked_add(PAGE_SIZE_4K - 1) {
+        Some(size) if size / PAGE_SIZE_4K != 0 => size / PAGE_SIZE_4K,
+        _ => return make_error!(OsErrorCode::NoEnoughMemory),
+    };
+    let size = num_pages * PAGE_SIZE_4K;
+    // Only this task maps in its program's heap. The range is reserved before
+    // mapping it, and given back if mapping fails.
+    let addr = user_heap_next();
+    if size > USER_HEAP_END - addr {
+        return make_error!(OsErrorCode::NoEnoughMemory);
+    }
+    set_user_heap_next(addr + size);
+
+    // The frames are taken one at a time, so the heap needs no contiguous run
+    // of free memory and the memory manager is not held while zeroing.
+    let mut page_table = PageTableManager::current();
+    let mut offset = 0;
+    while offset < size {
+        if let Err(e) = map_heap_page(&mut page_table, addr + offset) {
+            release_heap_pages(&mut page_table, addr, offset);
+            set_user_heap_next(addr);
+            return Err(e);
+        }
+        offset += PAGE_SIZE_4K;
+    }
+    Ok(addr)
+}
+
+fn map_heap_page(
+    page_table: &mut PageTableManager,
+    virt: u64,
+) -> Result<(), OsError> {
+    let mut frame = memory_manager().allocate(1)?;
+    unsafe {
+        write_bytes(frame.frame(), 0, BYTE_PER_FRAME);
+    }
+    let result = page_table.map(
+        virt,
+        (frame.id() * BYTE_PER_FRAME) as u64,
+        PAGE_SIZE_4K,
+        PageFlags::WRITABLE | PageFlags::USER | PageFlags::NO_EXECUTE,
+    );
+    if result.is_err() {
+        memory_manager().free(frame, 1)?;
+    }
+    result
+}
+
+// Unmaps the pages mapped by map_heap_page and frees their frames.
+fn release_heap_pages(page_table: &mut PageTableManager, virt: u64, size: u64) {
+    let mut offset = 0;
+    while offset < size {
+        if let Some(translation) = page_table.translate(virt + offset) {
+            let frame = FrameId::new(translation.phys as usize / BYTE_PER_FRAME);
+            let _ = memory_manager().free(frame, 1);
+        }
+        offset += PAGE_SIZE_4K;
+    }
+    let _ = page_table.unmap(virt, size);
+}
+
+fn sys_open(args: &SyscallArgs) -> Result<u64, OsError> {
+    let path = match str::from_utf8(user_slice(args[0], args[1])?) {
+        Ok(path) => path,
+        Err(_) => return make_error!(OsErrorCode::FileNotFound),
+    };
+    Ok(FD_TABLE.lock().open(path)? as u64)
+}
+
+fn sys_read(args: &SyscallArgs) -> Result<u64, OsError> {
+    let buf = user_slice_mut(args[1], args[2])?;
+    Ok(FD_TABLE.lock().read(args[0] as usize, buf)? as u64)
+}
+
+fn sys_close(args: &SyscallArgs) -> Result<u64, OsError> {
+    FD_TABLE.lock().close(args[0] as usize)?;
+    Ok(0)
+}
+
+fn sys_read_event(args: &SyscallArgs) -> Result<u64, OsError> {
+    let max = args[1];
+    let len = match max.checked_mul(size_of::<AppEvent>() as u64) {
+        Some(len) => len,
+        None => return make_error!(OsErrorCode::InvalidUserAddress),
+    };
+    let buf = user_slice_mut(args[0], len)?.as_mut_ptr() as *mut AppEvent;
+
+    let mut queue = EVENT_QUEUE.get().unwrap().lock();
+    let mut count = 0;
+    while count < max {
+        match queue.pop() {
+            Ok(event) => unsafe {
+                buf.add(count as usize).write_unaligned(*event);
+            },
+            Err(_) => break,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,591 @@
+use crate::error::*;
+use crate::kernel_stack::{allocate_kernel_stack, free_kernel_stack, KernelStack};
+use crate::logger::*;
+use crate::message::Message;
+use crate::paging::{PageTableManager, USER_HEAP_BASE};
+use crate::queue::ArrayQueue;
+use crate::segment::set_rsp0;
+use crate::smp::{current_cpu, reschedule_bsp};
+use crate::sync::{
+    disable_interrupts, restore_interrupts, IrqLock, IrqLockGuard, OnceCell,
+};
+use crate::timer::{current_tick, TIMER_FREQUENCY};
+
+use alloc::boxed::Box;
+use core::{arch::asm, mem::size_of};
+
+extern "C" {
+    fn switch_context(next_rsp: u64, current_rsp: *mut u64);
+    fn task_trampoline();
+}
+
+// Waiters are kept as a bit per task.
+const MAX_TASKS: usize = 64;
+const TASK_STACK_PAGES: usize = 16;
+const SYSCALL_STACK_PAGES: usize = 4;
+const TIME_SLICE_TICKS: u64 = 2;
+const MESSAGE_QUEUE_SIZE: usize = 32;
+
+const MAIN_TASK: TaskId = 0;
+const IDLE_TASK: TaskId = 1;
+
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+pub type TaskId = usize;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskState {
+    Ready,
+    Running,
+    Sleeping,
+    Blocked,
+    Exited,
+}
+
+// A ready task of a higher priority preempts the running one. Tasks of the
+// same priority share the CPU in turn.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[allow(dead_code)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+const NUM_PRIORITIES: usize = 3;
+
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum WaitEvent {
+    KeyboardInput,
+    DiskCompletion,
+}
+
+const NUM_WAIT_EVENTS: usize = 2;
+
+#[derive(Clone, Copy)]
+struct Task {
+    state: TaskState,
+    priority: Priority,
+    // A wake_up which came while the task was not blocked.
+    wakeup_pending: bool,
+    rsp: u64,
+    pml4: u64,
+    // Not set for the main task, which runs on KERNEL_MAIN_STACK.
+    kernel_stack: Option<KernelStack>,
+    // RSP0 while this task runs, used on entry from ring 3.
+    syscall_stack: KernelStack,
+    entry: fn(u64),
+    arg: u64,
+    wake_tick: u64,
+    // Messages lost because the queue was full.
+    dropped_messages: u64,
+    // Kernel stack of the caller of call_user_mode, restored on exit.
+    user_mode_kernel_rsp: u64,
+    // Where sys_alloc_memory maps next in the program of this task.
+    user_heap_next: u64,
+}
+
+// What switch_context pops when it switches to a new task.
+#[repr(C, align(16))]
+struct InitialFrame {
+    fxsave_area: [u8; 512],
+    rflags: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    rip: u64,
+}
+
+struct Scheduler {
+    tasks: [Option<Task>; MAX_TASKS],
+    current: TaskId,
+    slice_remaining: u64,
+    need_reschedule: bool,
+    run_queues: [ArrayQueue<'static, TaskId, MAX_TASKS>; NUM_PRIORITIES],
+    waiters: [u64; NUM_WAIT_EVENTS],
+    // The queue of a task is the one with the same index.
+    message_queues:
+        [ArrayQueue<'static, Message, MESSAGE_QUEUE_SIZE>; MAX_TASKS],
+}
+
+// Interrupt handlers send messages and wake tasks, so the lock keeps them
+// out on this CPU as well.
+static SCHEDULER: OnceCell<IrqLock<Scheduler>> = OnceCell::new();
+
+fn scheduler() -> IrqLockGuard<'static, Scheduler> {
+    SCHEDULER.get().unwrap().lock()
+}
+
+impl Scheduler {
+    fn task_mut(&mut self, id: TaskId) -> &mut Task {
+        self.tasks[id].as_mut().unwrap()
+    }
+
+    fn current_task_mut(&mut self) -> &mut Task {
+        self.task_mut(self.current)
+    }
+
+    fn make_ready(&mut self, id: TaskId) {
+        for waiters in self.waiters.iter_mut() {
+            *waiters &= !(1 << id);
+        }
+        let task = self.task_mut(id);
+        task.state = TaskState::Ready;
+        let priority = task.priority;
+        // Every task fits in a run queue.
+        self.run_queues[priority as usize].push(id).unwrap();
+
+        let current = self.current;
+        if current == IDLE_TASK || priority < self.task_mut(current).priority {
+            self.need_reschedule = true;
+        }
+    }
+
+    // A running task goes back to the run queue, a task in any other state
+    // stays out until it is woken. Returns the task to switch to, if it is
+    // another one.
+    fn pick_next(&mut self) -> Option<TaskId> {
+        let current = self.current;
+        let current_task = self.task_mut(current);
+        if current_task.state == TaskState::Running && current != IDLE_TASK {
+            current_task.state = TaskState::Ready;
+            let priority = current_task.priority;
+            self.run_queues[priority as usize].push(current).unwrap();
+        }
+
+        let next = self.run_queues
+            .iter_mut()
+            .find_map(|queue| queue.pop().ok().copied())
+            .unwrap_or(IDLE_TASK);
+        self.slice_remaining = TIME_SLICE_TICKS;
+        self.need_reschedule = false;
+        self.task_mut(next).state = TaskState::Running;
+        if next == current {
+            None
+        } else {
+            Some(next)
+        }
+    }
+}
+
+fn idle(_arg: u64) {
+    loop {
+        unsafe {
+            asm!("sti", "hlt");
+        }
+    }
+}
+
+// The caller of this function becomes the main task.
+pub fn init_task_manager() -> Result<(), OsError> {
+    // Each queue keeps its storage for good.
+    let [high, normal, low] =
+        Box::leak(Box::new([[0; MAX_TASKS]; NUM_PRIORITIES]));
+    let mut message_data = Box::leak(Box::new(
+        [[Message::Empty; MESSAGE_QUEUE_SIZE]; MAX_TASKS],
+    )).iter_mut();
+
+    // The main task runs the GUI event loop.
+    let mut tasks = [None; MAX_TASKS];
+    tasks[MAIN_TASK] = Some(Task {
+        state: TaskState::Running,
+        priority: Priority::High,
+        wakeup_pending: false,
+        rsp: 0,
+        pml4: PageTableManager::current().pml4(),
+        kernel_stack: None,
+        syscall_stack: allocate_kernel_stack(SYSCALL_STACK_PAGES)?,
+        entry: idle,
+        arg: 0,
+        wake_tick: 0,
+        dropped_messages: 0,
+        user_mode_kernel_rsp: 0,
+        user_heap_next: USER_HEAP_BASE,
+    });
+    // The idle task never enters the run queue. It runs when nothing else
+    // is ready.
+    tasks[IDLE_TASK] = Some(new_task(idle, 0, Priority::Low)?);
+    set_rsp0(tasks[MAIN_TASK].unwrap().syscall_stack.top());
+
+    SCHEDULER.set(IrqLock::new(Scheduler {
+        tasks,
+        current: MAIN_TASK,
+        slice_remaining: TIME_SLICE_TICKS,
+        need_reschedule: false,
+        run_queues: [
+            ArrayQueue::new(high),
+            ArrayQueue::new(normal),
+            ArrayQueue::new(low),
+        ],
+        waiters: [0; NUM_WAIT_EVENTS],
+        message_queues: core::array::from_fn(|_| {
+            ArrayQueue::new(message_data.next().unwrap())
+        }),
+    }))?;
+    Ok(())
+}
+
+fn new_task(
+    entry: fn(u64),
+    arg: u64,
+    priority: Priority,
+) -> Result<Task, OsError> {
+    let kernel_stack = allocate_kernel_stack(TASK_STACK_PAGES)?;
+    let syscall_stack = match allocate_kernel_stack(SYSCALL_STACK_PAGES) {
+        Ok(stack) => stack,
+        Err(err) => {
+            free_kernel_stack(kernel_stack)?;
+            return Err(err);
+        },
+    };
+
+    let frame_addr =
+        (kernel_stack.top() - size_of::<InitialFrame>() as u64) & !0xf;
+    let frame = unsafe {
+        &mut *(frame_addr as *mut InitialFrame)
+    };
+    frame.fxsave_area = [0; 512];
+    // Default x87 control word and MXCSR.
+    frame.fxsave_area[0..2].copy_from_slice(&0x037fu16.to_le_bytes());
+    frame.fxsave_area[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+    // Interrupts are enabled in task_start.
+    frame.rflags = RFLAGS_RESERVED;
+    frame.r15 = 0;
+    frame.r14 = 0;
+    frame.r13 = 0;
+    frame.r12 = 0;
+    frame.rbx = 0;
+    frame.rbp = 0;
+    frame.rip = task_trampoline as usize as u64;
+
+    Ok(Task {
+        state: TaskState::Ready,
+        priority,
+        wakeup_pending: false,
+        rsp: frame_addr,
+        pml4: PageTableManager::current().pml4(),
+        kernel_stack: Some(kernel_stack),
+        syscall_stack,
+        entry,
+        arg,
+        wake_tick: 0,
+        dropped_messages: 0,
+        user_mode_kernel_rsp: 0,
+        user_heap_next: USER_HEAP_BASE,
+    })
+}
+
+#[allow(dead_code)]
+pub fn spawn(entry: fn(u64), arg: u64) -> Result<TaskId, OsError> {
+    spawn_with_priority(entry, arg, Priority::Normal)
+}
+
+pub fn spawn_with_priority(
+    entry: fn(u64),
+    arg: u64,
+    priority: Priority,
+) -> Result<TaskId, OsError> {
+    let task = new_task(entry, arg, priority)?;
+
+    let interrupts = disable_interrupts();
+    let mut scheduler = scheduler();
+    let slot = scheduler.tasks.iter().position(|task| task.is_none());
+    if let Some(id) = slot {
+        scheduler.tasks[id] = Some(task);
+        scheduler.message_queues[id].clear();
+        scheduler.make_ready(id);
+    }
+    drop(scheduler);
+    restore_interrupts(interrupts);
+    if interrupts {
+        preempt_if_needed();
+    }
+
+    match slot {
+        Some(id) => Ok(id),
+        None => {
+            free_kernel_stack(task.kernel_stack.unwrap())?;
+            free_kernel_stack(task.syscall_stack)?;
+            make_error!(OsErrorCode::Full)
+        },
+    }
+}
+
+// The main task until the task manager is initialized.
+pub fn current_task() -> TaskId {
+    match SCHEDULER.get() {
+        Some(scheduler) => scheduler.lock().current,
+        None => MAIN_TASK,
+    }
+}
+
+// Switches the current task to the address space of `page_table`, which
+// switch_to restores whenever the task runs again.
+pub fn activate_address_space(page_table: &PageTableManager) {
+    let mut scheduler = scheduler();
+    scheduler.current_task_mut().pml4 = page_table.pml4();
+    page_table.activate();
+}
+
+// Only the task itself uses it, and the slot stays in place as long as the
+// task exists, so that it can be written outside of the lock.
+pub fn user_mode_kernel_rsp() -> *mut u64 {
+    &mut scheduler().current_task_mut().user_mode_kernel_rsp as *mut u64
+}
+
+pub fn user_heap_next() -> u64 {
+    scheduler().current_task_mut().user_heap_next
+}
+
+pub fn set_user_heap_next(next: u64) {
+    scheduler().current_task_mut().user_heap_next = next;
+}
+
+#[allow(dead_code)]
+pub fn yield_now() {
+    let interrupts = disable_interrupts();
+    reschedule(scheduler());
+    restore_interrupts(interrupts);
+}
+
+#[allow(dead_code)]
+pub fn sleep(milliseconds: u64) {
+    let ticks = (milliseconds * TIMER_FREQUENCY + 999) / 1000;
+    let interrupts = disable_interrupts();
+    let mut scheduler = scheduler();
+    let task = scheduler.current_task_mut();
+    task.state = TaskState::Sleeping;
+    task.wake_tick = current_tick() + ticks.max(1);
+    reschedule(scheduler);
+    restore_interrupts(interrupts);
+}
+
+// Blocks the current task until wake_up is called for it.
+pub fn block() {
+    let interrupts = disable_interrupts();
+    let mut scheduler = scheduler();
+    let task = scheduler.current_task_mut();
+    if task.wakeup_pending {
+        task.wakeup_pending = false;
+    } else {
+        task.state = TaskState::Blocked;
+        reschedule(scheduler);
+    }
+    restore_interrupts(interrupts);
+}
+
+// Can be called from interrupt handlers, which then call
+// preempt_if_needed after EOI.
+pub fn wake_up(id: TaskId) {
+    let interrupts = disable_interrupts();
+    let mut scheduler = scheduler();
+    match scheduler.tasks[id].as_mut() {
+        Some(task) if task.state == TaskState::Blocked => {
+            scheduler.make_ready(id);
+        },
+        Some(task) if task.state != TaskState::Exited => {
+            task.wakeup_pending = true;
+        },
+        _ => {},
+    }
+    drop(scheduler);
+    restore_interrupts(interrupts);
+    if interrupts {
+        preempt_if_needed();
+    }
+}
+
+// Blocks the current task until wake_up_all is called for `event`.
+#[allow(dead_code)]
+pub fn wait(event: WaitEvent) {
+    let interrupts = disable_interrupts();
+    let mut scheduler = scheduler();
+    let current = scheduler.current;
+    scheduler.waiters[event as usize] |= 1 << current;
+    scheduler.task_mut(current).state = TaskState::Blocked;
+    reschedule(scheduler);
+    restore_interrupts(interrupts);
+}
+
+#[allow(dead_code)]
+pub fn wake_up_all(event: WaitEvent) {
+    let interrupts = disable_interrupts();
+    let mut scheduler = scheduler();
+    let waiters = core::mem::replace(&mut scheduler.waiters[event as usize], 0);
+    for id in 0..MAX_TASKS {
+        if waiters & (1 << id) != 0 &&
+            scheduler.task_mut(id).state == TaskState::Blocked {
+            scheduler.make_ready(id);
+        }
+    }
+    drop(scheduler);
+    restore_interrupts(interrupts);
+    if interrupts {
+        preempt_if_needed();
+    }
+}
+
+// Does not block, so it can be used in interrupt handlers. When the queue
+// is full the message is dropped and counted.
+pub fn send_message(id: TaskId, message: Message) -> Result<(), OsError> {
+    let mut scheduler = scheduler();
+    let Scheduler { tasks, message_queues, .. } = &mut *scheduler;
+    let result = match tasks[id].as_mut() {
+        Some(task) if task.state != TaskState::Exited => {
+            let result = message_queues[id].push(message);
+            if result.is_err() {
+                task.dropped_messages += 1;
+            }
+            result
+        },
+        _ => make_error!(OsErrorCode::NoWaiter),
+    };
+    drop(scheduler);
+
+    if result.is_ok() {
+        wake_up(id);
+    }
+    result
+}
+
+// Blocks until a message arrives.
+pub fn receive_message() -> Message {
+    loop {
+        match try_receive_message() {
+            Some(message) => return message,
+            None => block(),
+        }
+    }
+}
+
+pub fn try_receive_message() -> Option<Message> {
+    let mut scheduler = scheduler();
+    let current = scheduler.current;
+    scheduler.message_queues[current].pop().ok().copied()
+}
+
+pub fn take_dropped_messages() -> u64 {
+    let mut scheduler = scheduler();
+    core::mem::replace(&mut scheduler.current_task_mut().dropped_messages, 0)
+}
+
+// Switches to a task of a higher priority woken since the last switch.
+// Tasks only run on the BSP; when a device interrupt is taken by an AP the
+// BSP is told to switch.
+pub fn preempt_if_needed() {
+    if current_cpu().index() != 0 {
+        if scheduler().need_reschedule {
+            reschedule_bsp();
+        }
+        return;
+    }
+    let interrupts = disable_interrupts();
+    let scheduler = scheduler();
+    if scheduler.need_reschedule {
+        reschedule(scheduler);
+    }
+    restore_interrupts(interrupts);
+}
+
+pub fn exit() -> ! {
+    disable_interrupts();
+    let mut scheduler = scheduler();
+    scheduler.current_task_mut().state = TaskState::Exited;
+    reschedule(scheduler);
+    unreachable!();
+}
+
+// Called by the timer interrupt handler after EOI.
+pub fn on_timer_tick() {
+    let mut scheduler = match SCHEDULER.get() {
+        Some(scheduler) => scheduler.lock(),
+        None => return,
+    };
+
+    let now = current_tick();
+    for id in 0..MAX_TASKS {
+        let sleeping = match &scheduler.tasks[id] {
+            Some(task) => {
+                task.state == TaskState::Sleeping && task.wake_tick <= now
+            },
+            None => false,
+        };
+        if sleeping {
+            scheduler.make_ready(id);
+        }
+    }
+
+    scheduler.slice_remaining = scheduler.slice_remaining.saturating_sub(1);
+    if scheduler.slice_remaining == 0 || scheduler.need_reschedule {
+        reschedule(scheduler);
+    }
+}
+
+// Interrupts must be disabled before taking the lock, so that they stay
+// disabled until switch_context.
+fn reschedule(mut scheduler: IrqLockGuard<'static, Scheduler>) {
+    let current = scheduler.current;
+    let next = match scheduler.pick_next() {
+        Some(next) => next,
+        None => return,
+    };
+
+    let next_task = scheduler.task_mut(next);
+    set_rsp0(next_task.syscall_stack.top());
+    if PageTableManager::current().pml4() != next_task.pml4 {
+        PageTableManager::from_pml4(next_task.pml4).activate();
+    }
+    let next_rsp = next_task.rsp;
+    // The tasks do not move, and only this CPU writes the stack pointer of
+    // the task running on it.
+    let current_rsp = &mut scheduler.task_mut(current).rsp as *mut u64;
+    scheduler.current = next;
+    drop(scheduler);
+
+    unsafe {
+        switch_context(next_rsp, current_rsp);
+    }
+    reap_exited_tasks();
+}
+
+// An exited task cannot free the stack it runs on, so the next task does.
+fn reap_exited_tasks() {
+    loop {
+        let mut scheduler = scheduler();
+        let current = scheduler.current;
+        let exited = (0..MAX_TASKS).find(|&id| {
+            id != current && matches!(
+                scheduler.tasks[id],
+                Some(task) if task.state == TaskState::Exited
+            )
+        });
+        let (id, task) = match exited {
+            Some(id) => (id, scheduler.tasks[id].take().unwrap()),
+            None => return,
+        };
+        drop(scheduler);
+
+        let mut result = free_kernel_stack(task.syscall_stack);
+        if let Some(stack) = task.kernel_stack {
+            result = result.and(free_kernel_stack(stack));
+        }
+        if let Err(err) = result {
+            log!(Error, "Failed to free the stacks of task {}: {:?}",
+                 id, err.code);
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn task_start() -> ! {
+    reap_exited_tasks();
+    restore_interrupts(true);
+
+    let task = *scheduler().current_task_mut();
+    (task.entry)(task.arg);
+    exit();
+}
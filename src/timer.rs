@@ -0,0 +1,191 @@
+use crate::interrupt::{
+    InterruptVector, ExceptionStackFrame, KernelGsGuard,
+    notify_end_of_interrupt, get_cs, load_idt, make_id_attr, set_idt_entry,
+    IDT,
+};
+use crate::apic::{
+    lapic_read, lapic_write, LAPIC_CURRENT_COUNT, LAPIC_DIVIDE_CONFIG,
+    LAPIC_INITIAL_COUNT, LAPIC_LVT_TIMER,
+};
+use crate::error::*;
+use crate::message::Message;
+use crate::logger::*;
+use crate::sync::{disable_interrupts, restore_interrupts, IrqLock};
+use crate::task::{TaskId, on_timer_tick, send_message};
+use crate::x86_descriptor::GateDescriptorType;
+
+use core::{
+    hint::spin_loop,
+    mem::size_of_val,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+extern "C" {
+    fn io_out8(addr: u16, data: u8);
+    fn io_in8(addr: u16) -> u8;
+}
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_1: u32 = 0b1011;
+
+pub const TIMER_FREQUENCY: u64 = 100;
+// Used if the timer cannot be measured, which gives roughly 60 Hz on QEMU.
+const FALLBACK_INITIAL_COUNT: u32 = 0x0100_0000;
+
+// The local APIC timer runs at a model-specific frequency, so it is measured
+// against channel 2 of the PIT, whose input clock is fixed.
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_MODE_COMMAND: u16 = 0x43;
+// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count).
+const PIT_CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+// The gate and the output of channel 2 are wired to the NMI status and
+// control port.
+const PIT_CHANNEL2_PORT: u16 = 0x61;
+const PIT_CHANNEL2_GATE: u8 = 1 << 0;
+const PIT_SPEAKER_ENABLE: u8 = 1 << 1;
+const PIT_CHANNEL2_OUT: u8 = 1 << 5;
+// Fits in the 16-bit counter of the PIT.
+const CALIBRATION_MS: u64 = 50;
+
+const MAX_TIMERS: usize = 32;
+
+static TICK: AtomicU64 = AtomicU64::new(0);
+
+// Sends Message::TimerTimeout to `task` when the tick reaches `deadline`.
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: u64,
+    id: u64,
+    task: TaskId,
+}
+
+static TIMERS: IrqLock<[Option<Timer>; MAX_TIMERS]> =
+    IrqLock::new([None; MAX_TIMERS]);
+
+extern "x86-interrupt" fn interrupt_handler_lapic_timer(
+    stack_frame: ExceptionStackFrame,
+) {
+    let _gs = KernelGsGuard::new(&stack_frame);
+    let tick = TICK.fetch_add(1, Ordering::Relaxed) + 1;
+    fire_timers(tick);
+    unsafe {
+        notify_end_of_interrupt();
+    }
+    // May switch to another task, so this comes after EOI.
+    on_timer_tick();
+}
+
+// The local APIC registers must be mapped before calling this.
+pub fn setup_timer() {
+    unsafe {
+        let cs = get_cs();
+        set_idt_entry(
+            &mut IDT[InterruptVector::LapicTimer as usize],
+            make_id_attr(GateDescriptorType::InterruptGate, 0),
+            interrupt_handler_lapic_timer as usize as u64,
+            cs,
+        );
+        load_idt((size_of_val(&IDT) - 1) as u16, &IDT as *const _ as u64);
+    }
+
+    let initial_count = match measure_lapic_timer() {
+        Some(counts) => {
+            let count = counts * 1000 / TIMER_FREQUENCY / CALIBRATION_MS;
+            count.clamp(1, u32::MAX as u64) as u32
+        },
+        None => {
+            log!(Error, "LAPIC timer calibration failed");
+            FALLBACK_INITIAL_COUNT
+        },
+    };
+    unsafe {
+        lapic_write(LAPIC_DIVIDE_CONFIG, DIVIDE_BY_1);
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic_write(LAPIC_INITIAL_COUNT, initial_count);
+        lapic_write(LAPIC_LVT_TIMER,
+                    LVT_PERIODIC | InterruptVector::LapicTimer as u32);
+    }
+}
+
+// Returns how many times the local APIC timer counts down at divide by 1 in
+// CALIBRATION_MS, or None if the PIT does not seem to run.
+fn measure_lapic_timer() -> Option<u64> {
+    let pit_count = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+    let interrupts = disable_interrupts();
+    let counts = unsafe {
+        let port = io_in8(PIT_CHANNEL2_PORT);
+        // The PIT does not count while the gate is low.
+        io_out8(
+            PIT_CHANNEL2_PORT,
+            port & !(PIT_CHANNEL2_GATE | PIT_SPEAKER_ENABLE),
+        );
+        io_out8(PIT_MODE_COMMAND, PIT_CHANNEL2_ONE_SHOT);
+        io_out8(PIT_CHANNEL2_DATA, pit_count as u8);
+        io_out8(PIT_CHANNEL2_DATA, (pit_count >> 8) as u8);
+
+        lapic_write(LAPIC_DIVIDE_CONFIG, DIVIDE_BY_1);
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic_write(LAPIC_INITIAL_COUNT, u32::MAX);
+        io_out8(
+            PIT_CHANNEL2_PORT,
+            (port & !PIT_SPEAKER_ENABLE) | PIT_CHANNEL2_GATE,
+        );
+
+        // The output goes high at the terminal count. The local APIC timer
+        // running out first means the output never changes.
+        let mut current = lapic_read(LAPIC_CURRENT_COUNT);
+        while io_in8(PIT_CHANNEL2_PORT) & PIT_CHANNEL2_OUT == 0 &&
+            current > 0 {
+            spin_loop();
+            current = lapic_read(LAPIC_CURRENT_COUNT);
+        }
+        lapic_write(LAPIC_INITIAL_COUNT, 0);
+        io_out8(PIT_CHANNEL2_PORT, port);
+
+        if current > 0 {
+            Some((u32::MAX - current) as u64)
+        } else {
+            None
+        }
+    };
+    restore_interrupts(interrupts);
+    counts
+}
+
+pub fn current_tick() -> u64 {
+    TICK.load(Ordering::Relaxed)
+}
+
+pub fn add_timer(milliseconds: u64, id: u64, task: TaskId) -> Result<(), OsError> {
+    let ticks = (milliseconds * TIMER_FREQUENCY + 999) / 1000;
+    let timer = Timer {
+        deadline: current_tick() + ticks.max(1),
+        id,
+        task,
+    };
+
+    let mut timers = TIMERS.lock();
+    match timers.iter_mut().find(|timer| timer.is_none()) {
+        Some(entry) => {
+            *entry = Some(timer);
+            Ok(())
+        },
+        None => make_error!(OsErrorCode::Full),
+    }
+}
+
+fn fire_timers(tick: u64) {
+    let mut timers = TIMERS.lock();
+    for entry in timers.iter_mut() {
+        if let Some(timer) = *entry {
+            if timer.deadline <= tick {
+                *entry = None;
+                // A full queue counts the message as dropped.
+                let _ = send_message(
+                    timer.task, Message::TimerTimeout { id: timer.id });
+            }
+        }
+    }
+}
@@ -1,6 +1,33 @@
 type FFIPointer = u64;
 type FFIErrorCode = u32;
 
+// x and y of absolute pointers, like QEMU's usb-tablet, are from 0 to this.
+pub const MOUSE_ABSOLUTE_MAX: u16 = 0x7fff;
+
+// Must match usb::MouseEvent in the driver. `buttons` has the left button at
+// bit 0, the right at bit 1 and the middle at bit 2.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct MouseEvent {
+    pub buttons: u8,
+    pub dx: i8,
+    pub dy: i8,
+    pub wheel: i8,
+    // If set, x and y are used instead of dx and dy.
+    pub absolute: bool,
+    pub x: u16,
+    pub y: u16,
+}
+
+// Must match usb::KeyEvent in the driver. `keycode` is the HID usage ID.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct KeyEvent {
+    pub modifier: u8,
+    pub keycode: u8,
+    pub press: bool,
+}
+
 #[link(name="driver")]
 extern "C" {
     fn XHCI_Controller_New(mmio_base: u64) -> FFIPointer;
@@ -21,7 +48,10 @@ extern "C" {
     ) -> FFIErrorCode;
     fn XHCI_ProcessEvent(controller: FFIPointer) -> FFIErrorCode;
     fn XHCI_Mouse_SetDefaultObserver(
-        observer: extern "C" fn(i8, i8),
+        observer: extern "C" fn(MouseEvent),
+    );
+    fn XHCI_Keyboard_SetDefaultObserver(
+        observer: extern "C" fn(KeyEvent),
     );
 }
 
@@ -109,9 +139,17 @@ pub fn process_event(
 }
 
 pub fn set_default_mouse_observer(
-    observer: extern "C" fn(i8, i8),
+    observer: extern "C" fn(MouseEvent),
 ) {
     unsafe {
         XHCI_Mouse_SetDefaultObserver(observer);
     }
 }
+
+pub fn set_default_keyboard_observer(
+    observer: extern "C" fn(KeyEvent),
+) {
+    unsafe {
+        XHCI_Keyboard_SetDefaultObserver(observer);
+    }
+}
@@ -0,0 +1,465 @@
+use crate::canvas::{Canvas, Point};
+use crate::error::*;
+use crate::font::{write_string, FONT_HEIGHT, FONT_WIDTH};
+use crate::graphics::{
+    BlitMode, Image, PixelColor, PixelWriter, Rectangle, Vector2D, BLACK,
+    clip_copy,
+};
+use crate::layer::{Layer, LayerId, SharedLayerManager};
+use crate::message::{Message, WindowEvent};
+use crate::sync::{IrqLock, OnceCell};
+use crate::task::{current_task, send_message, TaskId};
+
+use alloc::{string::String, vec::Vec};
+
+const BORDER_WIDTH: usize = 2;
+const TITLE_BAR_HEIGHT: usize = 22;
+const CLOSE_BUTTON_SIZE: usize = 16;
+
+const BORDER_COLOR: PixelColor = PixelColor { r: 198, g: 198, b: 198 };
+const ACTIVE_TITLE_COLOR: PixelColor = PixelColor { r: 0, g: 68, b: 136 };
+const INACTIVE_TITLE_COLOR: PixelColor = PixelColor { r: 132, g: 132, b: 132 };
+const TITLE_TEXT_COLOR: PixelColor = PixelColor { r: 255, g: 255, b: 255 };
+const CLIENT_COLOR: PixelColor = PixelColor { r: 255, g: 255, b: 255 };
+
+pub const MOUSE_BUTTON_LEFT: u8 = 1 << 0;
+
+pub type WindowId = usize;
+
+pub static WINDOW_MANAGER: OnceCell<IrqLock<WindowManager>> = OnceCell::new();
+
+struct Window {
+    layer: LayerId,
+    // Gets the WindowEvent messages.
+    owner: TaskId,
+    title: String,
+    client_size: Vector2D,
+}
+
+impl Window {
+    fn size(&self) -> Vector2D {
+        Vector2D {
+            x: self.client_size.x + 2 * BORDER_WIDTH,
+            y: self.client_size.y + TITLE_BAR_HEIGHT + 2 * BORDER_WIDTH,
+        }
+    }
+
+    // In window coordinates.
+    fn title_bar(&self) -> Rectangle {
+        Rectangle {
+            pos: Vector2D { x: BORDER_WIDTH, y: BORDER_WIDTH },
+            size: Vector2D { x: self.client_size.x, y: TITLE_BAR_HEIGHT },
+        }
+    }
+
+    fn close_button(&self) -> Rectangle {
+        let title_bar = self.title_bar();
+        let margin = (TITLE_BAR_HEIGHT - CLOSE_BUTTON_SIZE) / 2;
+        Rectangle {
+            pos: Vector2D {
+                x: (title_bar.pos.x + title_bar.size.x)
+                    .saturating_sub(CLOSE_BUTTON_SIZE + margin),
+                y: title_bar.pos.y + margin,
+            },
+            size: Vector2D { x: CLOSE_BUTTON_SIZE, y: CLOSE_BUTTON_SIZE },
+        }
+    }
+
+    fn client_origin(&self) -> Vector2D {
+        Vector2D { x: BORDER_WIDTH, y: BORDER_WIDTH + TITLE_BAR_HEIGHT }
+    }
+
+    fn draw_frame(&self, layer: &mut Layer) {
+        let size = self.size();
+        let mut canvas = Canvas::new(layer);
+        canvas.fill_rect(
+            &Rectangle { pos: Vector2D { x: 0, y: 0 }, size },
+            &BORDER_COLOR,
+        );
+        canvas.fill_rect(
+            &Rectangle { pos: self.client_origin(), size: self.client_size },
+            &CLIENT_COLOR,
+        );
+        self.draw_title_bar(layer, false);
+    }
+
+    fn draw_title_bar(&self, layer: &mut Layer, active: bool) {
+        let title_bar = self.title_bar();
+        let close_button = self.close_button();
+        let title_color =
+            if active { ACTIVE_TITLE_COLOR } else { INACTIVE_TITLE_COLOR };
+
+        let mut canvas = Canvas::new(layer);
+        canvas.set_clip(&title_bar);
+        canvas.fill_rect(&title_bar, &title_color);
+        canvas.fill_rounded_rect(&close_button, 3, &BORDER_COLOR);
+        let x0 = (close_button.pos.x + 4) as isize;
+        let y0 = (close_button.pos.y + 4) as isize;
+        let x1 = (close_button.pos.x + close_button.size.x - 5) as isize;
+        let y1 = (close_button.pos.y + close_button.size.y - 5) as isize;
+        canvas.draw_line(
+            Point { x: x0, y: y0 },
+            Point { x: x1, y: y1 },
+            &BLACK,
+        );
+        canvas.draw_line(
+            Point { x: x0, y: y1 },
+            Point { x: x1, y: y0 },
+            &BLACK,
+        );
+
+        // Cut so that the title does not run into the close button.
+        let max_chars =
+            close_button.pos.x.saturating_sub(title_bar.pos.x + 8) / FONT_WIDTH;
+        let end = self
+            .title
+            .char_indices()
+            .nth(max_chars)
+            .map_or(self.title.len(), |(i, _)| i);
+        write_string(
+            layer,
+            title_bar.pos.x + 6,
+            title_bar.pos.y + (TITLE_BAR_HEIGHT - FONT_HEIGHT) / 2,
+            &self.title[..end],
+            &TITLE_TEXT_COLOR,
+        );
+    }
+}
+
+// The client area of a window as a pixel writer; (0, 0) is its top left.
+pub struct ClientArea<'a> {
+    layer: &'a mut Layer,
+    origin: Vector2D,
+    size: Vector2D,
+}
+
+impl<'a> PixelWriter for ClientArea<'a> {
+    fn write(&mut self, x: usize, y: usize, color: &PixelColor) {
+        if x < self.size.x && y < self.size.y {
+            self.layer.write(self.origin.x + x, self.origin.y + y, color);
+        }
+    }
+
+    fn read(&self, x: usize, y: usize) -> PixelColor {
+        if x >= self.size.x || y >= self.size.y {
+            return BLACK;
+        }
+        self.layer.read(self.origin.x + x, self.origin.y + y)
+    }
+
+    fn size(&self) -> Vector2D {
+        self.size
+    }
+
+    fn fill_span(
+        &mut self,
+        x: usize,
+        y: usize,
+        len: usize,
+        color: &PixelColor,
+    ) {
+        if x >= self.size.x || y >= self.size.y {
+            return;
+        }
+        let len = len.min(self.size.x - x);
+        self.layer.fill_span(self.origin.x + x, self.origin.y + y, len, color);
+    }
+
+    fn copy_rect(&mut self, src: &Rectangle, dst: &Vector2D) {
+        if let Some((src, dst)) = clip_copy(&self.size, src, dst) {
+            let src = Rectangle {
+                pos: Vector2D {
+                    x: self.origin.x + src.pos.x,
+                    y: self.origin.y + src.pos.y,
+                },
+                size: src.size,
+            };
+            let dst =
+                Vector2D { x: self.origin.x + dst.x, y: self.origin.y + dst.y };
+            self.layer.copy_rect(&src, &dst);
+        }
+    }
+
+    fn blit_image(
+        &mut self,
+        x: usize,
+        y: usize,
+        image: &Image,
+        mode: BlitMode,
+    ) {
+        // Row by row, cut at the right edge.
+        if x >= self.size.x {
+            return;
+        }
+        let width = image.width.min(self.size.x - x);
+        for dy in 0..image.height.min(self.size.y.saturating_sub(y)) {
+            let start = image.width * dy;
+            let row = Image {
+                width,
+                height: 1,
+                pixels: &image.pixels[start..(start + width)],
+            };
+            self.layer.blit_image(
+                self.origin.x + x,
+                self.origin.y + y + dy,
+                &row,
+                mode,
+            );
+        }
+    }
+}
+
+// Windows are layers with a frame drawn by the manager. Clicking a window
+// activates and raises it, the title bar drags it, and the close button sends
+// WindowEvent::Close to the owner, which calls close_window.
+pub struct WindowManager {
+    layers: &'static SharedLayerManager,
+    windows: Vec<Option<Window>>,
+    active: Option<WindowId>,
+    // Moved with the mouse while the left button is held.
+    dragging: Option<WindowId>,
+    mouse_position: Vector2D,
+    mouse_buttons: u8,
+}
+
+impl WindowManager {
+    pub fn new(layers: &'static SharedLayerManager) -> Self {
+        Self {
+            layers,
+            windows: Vec::new(),
+            active: None,
+            dragging: None,
+            mouse_position: Vector2D { x: 0, y: 0 },
+            mouse_buttons: 0,
+        }
+    }
+
+    pub fn create_window(
+        &mut self,
+        owner: TaskId,
+        title: &str,
+        client_width: usize,
+        client_height: usize,
+        position: Vector2D,
+    ) -> WindowId {
+        let mut window = Window {
+            layer: 0,
+            owner,
+            title: String::from(title),
+            client_size: Vector2D { x: client_width, y: client_height },
+        };
+        let size = window.size();
+        let mut layers = self.layers.lock();
+        window.layer = layers.new_layer(size.x, size.y);
+        window.draw_frame(layers.layer_mut(window.layer).unwrap());
+        layers.move_to(window.layer, position);
+        layers.show(window.layer);
+        drop(layers);
+
+        let id = match self.windows.iter().position(|w| w.is_none()) {
+            Some(id) => {
+                self.windows[id] = Some(window);
+                id
+            },
+            None => {
+                self.windows.push(Some(window));
+                self.windows.len() - 1
+            },
+        };
+        self.activate(Some(id));
+        id
+    }
+
+    // Only `owner` may close or draw to its windows.
+    fn check_owner(&self, owner: TaskId, id: WindowId) -> Result<(), OsError> {
+        match self.windows.get(id).and_then(|w| w.as_ref()) {
+            Some(window) if window.owner == owner => Ok(()),
+            _ => make_error!(OsErrorCode::InvalidWindowId),
+        }
+    }
+
+    pub fn close_window(
+        &mut self,
+        owner: TaskId,
+        id: WindowId,
+    ) -> Result<(), OsError> {
+        self.check_owner(owner, id)?;
+        let window = self.windows[id].take().unwrap();
+        if self.dragging == Some(id) {
+            self.dragging = None;
+        }
+        if self.active == Some(id) {
+            self.active = None;
+        }
+        let mut layers = self.layers.lock();
+        layers.remove_layer(window.layer);
+        layers.compose();
+        Ok(())
+    }
+
+    // Calls `draw` with the client area of the window and shows the result.
+    pub fn draw<F: FnOnce(&mut ClientArea)>(
+        &mut self,
+        owner: TaskId,
+        id: WindowId,
+        draw: F,
+    ) -> Result<(), OsError> {
+        self.check_owner(owner, id)?;
+        let window = self.window(id);
+        let mut layers = self.layers.lock();
+        let mut client = ClientArea {
+            layer: layers.layer_mut(window.layer).unwrap(),
+            origin: window.client_origin(),
+            size: window.client_size,
+        };
+        draw(&mut client);
+        layers.compose();
+        Ok(())
+    }
+
+    // Gets the key events.
+    pub fn active_owner(&self) -> Option<TaskId> {
+        self.active.map(|id| self.window(id).owner)
+    }
+
+    fn window(&self, id: WindowId) -> &Window {
+        self.windows[id].as_ref().unwrap()
+    }
+
+    fn notify(&self, id: WindowId, event: WindowEvent) {
+        // Lost if the owner has exited or its queue is full.
+        let message = Message::Window { id: id as u32, event };
+        let _ = send_message(self.window(id).owner, message);
+    }
+
+    fn window_at(&self, position: &Vector2D) -> Option<WindowId> {
+        let layers = self.layers.lock();
+        let layer = layers.layer_at(position, |layer| {
+            self.windows.iter().flatten().any(|w| w.layer == layer)
+        })?;
+        self.windows
+            .iter()
+            .position(|w| w.as_ref().is_some_and(|w| w.layer == layer))
+    }
+
+    fn activate(&mut self, id: Option<WindowId>) {
+        if self.active == id {
+            if let Some(id) = id {
+                let mut layers = self.layers.lock();
+                layers.raise(self.window(id).layer);
+                layers.compose();
+            }
+            return;
+        }
+
+        let mut layers = self.layers.lock();
+        if let Some(previous) = self.active {
+            let window = self.window(previous);
+            window
+                .draw_title_bar(layers.layer_mut(window.layer).unwrap(), false);
+            self.notify(previous, WindowEvent::Deactivate);
+        }
+        if let Some(id) = id {
+            let window = self.window(id);
+            window
+                .draw_title_bar(layers.layer_mut(window.layer).unwrap(), true);
+            layers.raise(window.layer);
+            self.notify(id, WindowEvent::Activate);
+        }
+        layers.compose();
+        self.active = id;
+    }
+
+    // Called with the mouse cursor position and button state on every mouse
+    // event.
+    pub fn on_mouse(&mut self, position: Vector2D, buttons: u8) {
+        let pressed = buttons & !self.mouse_buttons;
+        let released = self.mouse_buttons & !buttons;
+        let previous = self.mouse_position;
+        self.mouse_position = position;
+        self.mouse_buttons = buttons;
+
+        if pressed & MOUSE_BUTTON_LEFT != 0 {
+            let id = self.window_at(&position);
+            self.activate(id);
+            if let Some(id) = id {
+                self.on_left_press(id, &position);
+            }
+        } else if released & MOUSE_BUTTON_LEFT != 0 {
+            self.dragging = None;
+        } else if let Some(id) = self.dragging {
+            let layer = self.window(id).layer;
+            let mut layers = self.layers.lock();
+            if let Some(mut window_position) = layers.position(layer) {
+                // Stops at the left and top edges.
+                window_position.x =
+                    (window_position.x + position.x).saturating_sub(previous.x);
+                window_position.y =
+                    (window_position.y + position.y).saturating_sub(previous.y);
+                layers.move_to(layer, window_position);
+                layers.compose();
+            }
+        }
+    }
+
+    fn on_left_press(&mut self, id: WindowId, position: &Vector2D) {
+        let window = self.window(id);
+        let origin = match self.layers.lock().position(window.layer) {
+            Some(origin) => origin,
+            None => return,
+        };
+        let point = Rectangle {
+            pos: Vector2D {
+                x: position.x - origin.x,
+                y: position.y - origin.y,
+            },
+            size: Vector2D { x: 1, y: 1 },
+        };
+        if window.close_button().intersection(&point).is_some() {
+            self.notify(id, WindowEvent::Close);
+        } else if window.title_bar().intersection(&point).is_some() {
+            self.dragging = Some(id);
+        }
+    }
+}
+
+pub fn setup_window_manager(layers: &'static SharedLayerManager) {
+    WINDOW_MANAGER.set(IrqLock::new(WindowManager::new(layers))).unwrap();
+}
+
+// For tasks. The window belongs to the calling task, which gets its
+// WindowEvent messages and is the only one allowed to close or draw to it.
+pub fn create_window(
+    title: &str,
+    client_width: usize,
+    client_height: usize,
+    position: Vector2D,
+) -> Result<WindowId, OsError> {
+    match WINDOW_MANAGER.get() {
+        Some(manager) => Ok(manager.lock().create_window(
+            current_task(),
+            title,
+            client_width,
+            client_height,
+            position,
+        )),
+        None => make_error!(OsErrorCode::NoFrameBuffer),
+    }
+}
+
+pub fn close_window(id: WindowId) -> Result<(), OsError> {
+    match WINDOW_MANAGER.get() {
+        Some(manager) => manager.lock().close_window(current_task(), id),
+        None => make_error!(OsErrorCode::NoFrameBuffer),
+    }
+}
+
+pub fn draw_window<F: FnOnce(&mut ClientArea)>(
+    id: WindowId,
+    draw: F,
+) -> Result<(), OsError> {
+    match WINDOW_MANAGER.get() {
+        Some(manager) => manager.lock().draw(current_task(), id, draw),
+        None => make_error!(OsErrorCode::NoFrameBuffer),
+    }
+}
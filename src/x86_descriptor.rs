@@ -1,3 +1,4 @@
+#[derive(Clone, Copy)]
 #[repr(u16)]
 #[allow(dead_code)]
 pub enum GateDescriptorType {